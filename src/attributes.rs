@@ -0,0 +1,211 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! The set of STUN attributes understood by the client: those from RFC 5389, plus the
+//! RFC 5780 attributes used for NAT behavior discovery and the RFC 5766 attributes used for
+//! TURN allocations.
+
+use bytecodec::{ByteCount, Decode, Encode, Eos, Result, SizedEncode, TryTaggedDecode};
+use serde::Serialize;
+use std::net::SocketAddr;
+use stun_codec::define_attribute_enums;
+use stun_codec::net::{SocketAddrDecoder, SocketAddrEncoder};
+use stun_codec::rfc5389::attributes::{
+    AlternateServer, ErrorCode, Fingerprint, MappedAddress, MessageIntegrity, Nonce, Realm,
+    Software, UnknownAttributes, Username, XorMappedAddress, XorMappedAddress2,
+};
+use stun_codec::rfc5766::attributes::{Lifetime, RequestedTransport, XorRelayAddress};
+use stun_codec::rfc5780::attributes::{ChangeRequest, OtherAddress, ResponseOrigin};
+use stun_codec::{AttributeType, Message};
+
+define_attribute_enums!(
+    Attribute,
+    AttributeDecoder,
+    AttributeEncoder,
+    [
+        MappedAddress,
+        Username,
+        MessageIntegrity,
+        ErrorCode,
+        UnknownAttributes,
+        Realm,
+        Nonce,
+        XorMappedAddress,
+        XorMappedAddress2,
+        Software,
+        AlternateServer,
+        Fingerprint,
+        ChangeRequest,
+        ResponseOrigin,
+        OtherAddress,
+        ChangedAddress,
+        RequestedTransport,
+        XorRelayAddress,
+        Lifetime
+    ]
+);
+
+/// An attribute of a decoded response, for inspecting everything that a server sent
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ResponseAttribute {
+    /// The attribute's name from its RFC, or `None` if the client doesn't know the type
+    #[serde(rename = "type")]
+    pub name: Option<&'static str>,
+    /// The attribute type's codepoint
+    pub code: u16,
+    /// The decoded value, or the raw value in hex if the type isn't known
+    pub value: String,
+}
+
+/// Lists the attributes of `message`, with the known ones first since the decoder doesn't
+/// keep the order between known and unknown attributes
+pub(crate) fn describe(message: &Message<Attribute>) -> Vec<ResponseAttribute> {
+    let known = message.attributes().map(|attribute| {
+        let (name, value) = match attribute {
+            Attribute::MappedAddress(a) => ("MAPPED-ADDRESS", a.address().to_string()),
+            Attribute::Username(a) => ("USERNAME", a.name().to_string()),
+            Attribute::MessageIntegrity(a) => ("MESSAGE-INTEGRITY", hex(&a.hmac_sha1())),
+            Attribute::ErrorCode(a) => {
+                ("ERROR-CODE", format!("{} {}", a.code(), a.reason_phrase()))
+            }
+            Attribute::UnknownAttributes(a) => (
+                "UNKNOWN-ATTRIBUTES",
+                a.unknowns()
+                    .iter()
+                    .map(|t| format!("{:#06x}", t.as_u16()))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+            Attribute::Realm(a) => ("REALM", a.text().to_string()),
+            Attribute::Nonce(a) => ("NONCE", a.value().to_string()),
+            Attribute::XorMappedAddress(a) => ("XOR-MAPPED-ADDRESS", a.address().to_string()),
+            // The pre-standard codepoint used by some older servers
+            Attribute::XorMappedAddress2(a) => ("XOR-MAPPED-ADDRESS", a.address().to_string()),
+            Attribute::Software(a) => ("SOFTWARE", a.description().to_string()),
+            Attribute::AlternateServer(a) => ("ALTERNATE-SERVER", a.address().to_string()),
+            Attribute::Fingerprint(a) => ("FINGERPRINT", format!("{:#010x}", a.crc32())),
+            Attribute::ChangeRequest(a) => {
+                ("CHANGE-REQUEST", format!("ip={} port={}", a.ip(), a.port()))
+            }
+            Attribute::ResponseOrigin(a) => ("RESPONSE-ORIGIN", a.address().to_string()),
+            Attribute::OtherAddress(a) => ("OTHER-ADDRESS", a.address().to_string()),
+            Attribute::ChangedAddress(a) => ("CHANGED-ADDRESS", a.address().to_string()),
+            Attribute::RequestedTransport(a) => ("REQUESTED-TRANSPORT", a.protocol().to_string()),
+            Attribute::XorRelayAddress(a) => ("XOR-RELAYED-ADDRESS", a.address().to_string()),
+            Attribute::Lifetime(a) => ("LIFETIME", a.lifetime().as_secs().to_string()),
+        };
+        ResponseAttribute {
+            name: Some(name),
+            code: stun_codec::Attribute::get_type(attribute).as_u16(),
+            value,
+        }
+    });
+    let unknown = message.unknown_attributes().map(|raw| ResponseAttribute {
+        name: None,
+        code: stun_codec::Attribute::get_type(raw).as_u16(),
+        value: hex(raw.value()),
+    });
+    known.chain(unknown).collect()
+}
+
+/// Formats `bytes` as lowercase hex without separators
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// `CHANGED-ADDRESS` attribute from RFC 3489, the predecessor of RFC 5780's OTHER-ADDRESS.
+/// Older servers still send it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ChangedAddress(SocketAddr);
+
+impl ChangedAddress {
+    pub const CODEPOINT: u16 = 0x0005;
+
+    pub fn address(&self) -> SocketAddr {
+        self.0
+    }
+}
+
+impl stun_codec::Attribute for ChangedAddress {
+    type Decoder = ChangedAddressDecoder;
+    type Encoder = ChangedAddressEncoder;
+
+    fn get_type(&self) -> AttributeType {
+        AttributeType::new(Self::CODEPOINT)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ChangedAddressDecoder(SocketAddrDecoder);
+
+impl Decode for ChangedAddressDecoder {
+    type Item = ChangedAddress;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> Result<usize> {
+        self.0.decode(buf, eos)
+    }
+
+    fn finish_decoding(&mut self) -> Result<Self::Item> {
+        self.0.finish_decoding().map(ChangedAddress)
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.0.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.is_idle()
+    }
+}
+
+impl TryTaggedDecode for ChangedAddressDecoder {
+    type Tag = AttributeType;
+
+    fn try_start_decoding(&mut self, attr_type: Self::Tag) -> Result<bool> {
+        Ok(attr_type.as_u16() == ChangedAddress::CODEPOINT)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ChangedAddressEncoder(SocketAddrEncoder);
+
+impl Encode for ChangedAddressEncoder {
+    type Item = ChangedAddress;
+
+    fn encode(&mut self, buf: &mut [u8], eos: Eos) -> Result<usize> {
+        self.0.encode(buf, eos)
+    }
+
+    fn start_encoding(&mut self, item: Self::Item) -> Result<()> {
+        self.0.start_encoding(item.0)
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.0.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.is_idle()
+    }
+}
+
+impl SizedEncode for ChangedAddressEncoder {
+    fn exact_requiring_bytes(&self) -> u64 {
+        self.0.exact_requiring_bytes()
+    }
+}
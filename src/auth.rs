@@ -0,0 +1,243 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::attributes::Attribute;
+use crate::error::{Context, Result};
+use crate::LookupError;
+use stun_codec::rfc5389::attributes::{ErrorCode, MessageIntegrity, Nonce, Realm, Username};
+use stun_codec::rfc5389::errors;
+use stun_codec::Message;
+
+/// Number of requests to send before giving up on authentication: One without credentials,
+/// one in response to the challenge, and one more in case the nonce went stale
+pub(crate) const MAX_REQUESTS: usize = 3;
+
+/// Long-term credentials (RFC 5389 section 10.2) for servers which require authentication
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+    /// If provided, the realm the server is expected to report in its challenge
+    pub realm: Option<String>,
+}
+
+/// The realm and nonce from a server's 401 (Unauthorized) or 438 (Stale Nonce) response,
+/// to be included in the next request
+#[derive(Clone, Debug)]
+pub(crate) struct Challenge {
+    realm: Realm,
+    nonce: Nonce,
+}
+
+/// Adds USERNAME, NONCE, REALM, and MESSAGE-INTEGRITY attributes to `message`, answering
+/// `challenge`. MESSAGE-INTEGRITY covers everything before it, so only FINGERPRINT may follow.
+pub(crate) fn sign(
+    message: &mut Message<Attribute>,
+    credentials: &Credentials,
+    challenge: &Challenge,
+) -> Result<()> {
+    let username = Username::new(credentials.username.clone()).with_category(|| {
+        LookupError::InvalidOptions("Invalid username, must be under 513 bytes".to_string())
+    })?;
+    message.add_attribute(Attribute::Username(username.clone()));
+    message.add_attribute(Attribute::Nonce(challenge.nonce.clone()));
+    message.add_attribute(Attribute::Realm(challenge.realm.clone()));
+    let integrity = MessageIntegrity::new_long_term_credential(
+        message,
+        &username,
+        &challenge.realm,
+        &credentials.password,
+    )
+    .context("Failed to compute MESSAGE-INTEGRITY")?;
+    message.add_attribute(Attribute::MessageIntegrity(integrity));
+    Ok(())
+}
+
+/// Checks whether the error response `decoded` is an authentication challenge. Returns the
+/// challenge to answer in the next request, or `None` if this is some other kind of error.
+/// `previous` is the challenge answered by the request that got this response, if any.
+pub(crate) fn challenge(
+    decoded: &Message<Attribute>,
+    credentials: Option<&Credentials>,
+    previous: Option<&Challenge>,
+) -> Result<Option<Challenge>> {
+    let code = match decoded.get_attribute::<ErrorCode>() {
+        Some(error) => error.code(),
+        None => return Ok(None),
+    };
+    if code != errors::Unauthorized::CODEPOINT && code != errors::StaleNonce::CODEPOINT {
+        return Ok(None);
+    }
+    let credentials = match credentials {
+        Some(credentials) => credentials,
+        None => {
+            return Err(LookupError::Auth(
+                "Server requires authentication, but no credentials were provided".to_string(),
+            ))
+        }
+    };
+    if code == errors::Unauthorized::CODEPOINT && previous.is_some() {
+        return Err(LookupError::Auth(format!(
+            "Server rejected credentials for username {:?}",
+            credentials.username
+        )));
+    }
+
+    let realm = decoded.get_attribute::<Realm>().ok_or_else(|| {
+        LookupError::Auth("Missing REALM in authentication challenge".to_string())
+    })?;
+    let nonce = decoded.get_attribute::<Nonce>().ok_or_else(|| {
+        LookupError::Auth("Missing NONCE in authentication challenge".to_string())
+    })?;
+    if let Some(expected) = &credentials.realm {
+        if realm.text() != expected {
+            return Err(LookupError::Auth(format!(
+                "Server realm {:?} doesn't match expected realm {:?}",
+                realm.text(),
+                expected
+            )));
+        }
+    }
+    Ok(Some(Challenge {
+        realm: realm.clone(),
+        nonce: nonce.clone(),
+    }))
+}
+
+/// Checks that the response `decoded` has a MESSAGE-INTEGRITY attribute matching the
+/// credentials which were used to answer `challenge`
+pub(crate) fn verify(
+    decoded: &Message<Attribute>,
+    credentials: &Credentials,
+    challenge: &Challenge,
+) -> Result<()> {
+    let integrity = decoded.get_attribute::<MessageIntegrity>().ok_or_else(|| {
+        LookupError::Auth("Missing MESSAGE-INTEGRITY in authenticated response".to_string())
+    })?;
+    let username = Username::new(credentials.username.clone()).with_category(|| {
+        LookupError::InvalidOptions("Invalid username, must be under 513 bytes".to_string())
+    })?;
+    if integrity
+        .check_long_term_credential(&username, &challenge.realm, &credentials.password)
+        .is_err()
+    {
+        return Err(LookupError::Auth(
+            "MESSAGE-INTEGRITY in response doesn't match the credentials".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{self, MockServer};
+    use crate::{lookup, LookupOptions};
+    use bytecodec::{DecodeExt, EncodeExt};
+    use std::time::Duration;
+    use stun_codec::rfc5389::methods;
+    use stun_codec::{MessageClass, MessageDecoder, MessageEncoder, TransactionId};
+    use tokio::net::UdpSocket;
+
+    /// Sample request with long-term authentication, from RFC 5769 section 2.4
+    const SAMPLE_REQUEST: [u8; 116] = [
+        0x00, 0x01, 0x00, 0x60, 0x21, 0x12, 0xa4, 0x42, 0x78, 0xad, 0x34, 0x33, 0xc6, 0xad, 0x72,
+        0xc0, 0x29, 0xda, 0x41, 0x2e, 0x00, 0x06, 0x00, 0x12, 0xe3, 0x83, 0x9e, 0xe3, 0x83, 0x88,
+        0xe3, 0x83, 0xaa, 0xe3, 0x83, 0x83, 0xe3, 0x82, 0xaf, 0xe3, 0x82, 0xb9, 0x00, 0x00, 0x00,
+        0x15, 0x00, 0x1c, 0x66, 0x2f, 0x2f, 0x34, 0x39, 0x39, 0x6b, 0x39, 0x35, 0x34, 0x64, 0x36,
+        0x4f, 0x4c, 0x33, 0x34, 0x6f, 0x4c, 0x39, 0x46, 0x53, 0x54, 0x76, 0x79, 0x36, 0x34, 0x73,
+        0x41, 0x00, 0x14, 0x00, 0x0b, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e, 0x6f, 0x72,
+        0x67, 0x00, 0x00, 0x08, 0x00, 0x14, 0xf6, 0x70, 0x24, 0x65, 0x6d, 0xd6, 0x4a, 0x3e, 0x02,
+        0xb8, 0xe0, 0x71, 0x2e, 0x85, 0xc9, 0xa2, 0x8c, 0xa8, 0x96, 0x66,
+    ];
+
+    /// Credentials of the sample request, with the password already processed by SASLprep
+    fn sample_credentials() -> Credentials {
+        Credentials {
+            username: "\u{30DE}\u{30C8}\u{30EA}\u{30C3}\u{30AF}\u{30B9}".to_string(),
+            password: "TheMatrIX".to_string(),
+            realm: None,
+        }
+    }
+
+    fn sample_challenge() -> Challenge {
+        Challenge {
+            realm: Realm::new("example.org".to_string()).unwrap(),
+            nonce: Nonce::new("f//499k954d6OL34oL9FSTvy64sA".to_string()).unwrap(),
+        }
+    }
+
+    #[test]
+    fn sign_matches_rfc5769_sample() {
+        let transaction_id = TransactionId::new([
+            0x78, 0xad, 0x34, 0x33, 0xc6, 0xad, 0x72, 0xc0, 0x29, 0xda, 0x41, 0x2e,
+        ]);
+        let mut message = Message::new(MessageClass::Request, methods::BINDING, transaction_id);
+        sign(&mut message, &sample_credentials(), &sample_challenge()).unwrap();
+        let encoded = MessageEncoder::new().encode_into_bytes(message).unwrap();
+        assert_eq!(encoded, SAMPLE_REQUEST);
+    }
+
+    #[test]
+    fn verify_checks_rfc5769_sample() {
+        let decoded = MessageDecoder::<Attribute>::new()
+            .decode_from_bytes(&SAMPLE_REQUEST)
+            .unwrap()
+            .unwrap();
+        verify(&decoded, &sample_credentials(), &sample_challenge()).unwrap();
+
+        let wrong = Credentials {
+            password: "The Password".to_string(),
+            ..sample_credentials()
+        };
+        let err = verify(&decoded, &wrong, &sample_challenge()).unwrap_err();
+        assert!(matches!(err, LookupError::Auth(_)));
+    }
+
+    #[tokio::test]
+    async fn endless_challenges_give_up() {
+        // A stale nonce may be retried, unlike a second 401, so this never stops by itself
+        let server = MockServer::spawn_with(|request, _peer| {
+            Some(mock::challenge(
+                request,
+                errors::StaleNonce.into(),
+                "example.org",
+                mock::NONCE,
+            ))
+        })
+        .await;
+        let conn = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let opts = LookupOptions {
+            retries: 1,
+            base_timeout: Duration::from_millis(500),
+            credentials: Some(Credentials {
+                username: "user".to_string(),
+                password: "secret".to_string(),
+                realm: None,
+            }),
+            ..LookupOptions::default()
+        };
+        let err = lookup(&conn, &server.addr, &opts).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Too many authentication challenges from {}", server.addr)
+        );
+        assert!(matches!(err, LookupError::Auth(_)));
+        assert_eq!(server.request_count(), MAX_REQUESTS);
+    }
+}
@@ -0,0 +1,57 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Replaces the content of `path` with `content` by writing to a temporary file in the same
+/// directory and then renaming it over `path`. Readers see either the old or the new content,
+/// and the old content is left alone if anything fails.
+pub fn write(path: &Path, content: &[u8]) -> Result<()> {
+    let tmp_path = tmp_path(path);
+    let result = write_tmp(&tmp_path, content).and_then(|()| {
+        fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to rename {:?} to {:?}", tmp_path, path))
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+fn write_tmp(tmp_path: &Path, content: &[u8]) -> Result<()> {
+    let mut file =
+        fs::File::create(tmp_path).with_context(|| format!("Failed to create {:?}", tmp_path))?;
+    file.write_all(content)
+        .with_context(|| format!("Failed to write {:?}", tmp_path))?;
+    file.sync_all()
+        .with_context(|| format!("Failed to sync {:?}", tmp_path))
+}
+
+/// Returns a hidden temporary path alongside `path`, so that the rename stays within one
+/// filesystem
+fn tmp_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{}.{}.tmp", name, process::id()))
+}
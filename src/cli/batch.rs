@@ -0,0 +1,170 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::cli::output::Format;
+use crate::cli::servers;
+use anyhow::{bail, Context, Result};
+use futures::stream::{self, StreamExt};
+use iplookup::{lookup_endpoint, parse_endpoint, Family, LookupOptions, Transport};
+use log::warn;
+use serde::Serialize;
+use std::net::Ipv6Addr;
+use std::path::Path;
+
+/// A failed query in `--batch` mode, as printed by `--json`
+#[derive(Debug, Serialize)]
+struct FailureReport<'a> {
+    server: &'a str,
+    error: String,
+}
+
+/// Queries each server listed in `path` with up to `concurrency` queries at a time, printing
+/// a line per server in the order they're listed, followed by a summary. Malformed lines are
+/// reported and skipped. Fails if none of the servers answered.
+pub async fn run(
+    path: &Path,
+    concurrency: usize,
+    family: Option<Family>,
+    opts: &LookupOptions,
+    format: &Format,
+) -> Result<()> {
+    let content =
+        servers::read(path).with_context(|| format!("Failed to read batch file {:?}", path))?;
+    let mut endpoints = Vec::new();
+    let mut malformed = 0;
+    for (line, endpoint) in servers::entries(&content) {
+        match check(endpoint) {
+            Ok(()) => endpoints.push(endpoint),
+            Err(reason) => {
+                warn!("{:?} line {}: {:?} {}", path, line, endpoint, reason);
+                malformed += 1;
+            }
+        }
+    }
+
+    let mut answered = 0;
+    let mut results = stream::iter(&endpoints)
+        .map(|endpoint| async move { (*endpoint, lookup_endpoint(endpoint, family, opts).await) })
+        .buffered(concurrency);
+    while let Some((endpoint, result)) = results.next().await {
+        match result {
+            Ok(binding) => {
+                answered += 1;
+                let line = format.render(endpoint, &binding, None)?;
+                match format {
+                    Format::Json => println!("{}", line),
+                    _ => println!("{}: {}", endpoint, line),
+                }
+            }
+            Err(e) => match format {
+                Format::Json => println!(
+                    "{}",
+                    serde_json::to_string(&FailureReport {
+                        server: endpoint,
+                        error: format!("{:#}", e),
+                    })?
+                ),
+                _ => println!("{}: failed: {:#}", endpoint, e),
+            },
+        }
+    }
+
+    let summary = format!(
+        "--- {} servers, {} answered, {} failed, {} malformed lines ---",
+        endpoints.len(),
+        answered,
+        endpoints.len() - answered,
+        malformed
+    );
+    // Keep stdout parseable as one JSON object per line
+    match format {
+        Format::Json => eprintln!("{}", summary),
+        _ => println!("{}", summary),
+    }
+    if answered == 0 {
+        bail!("None of the servers in {:?} answered", path);
+    }
+    Ok(())
+}
+
+/// Checks that `endpoint` looks like `host[:port]` or a `stun:`/`stuns:` URI, returning why
+/// it doesn't otherwise
+fn check(endpoint: &str) -> Result<(), &'static str> {
+    let (_transport, host) = parse_endpoint(endpoint, Transport::Udp);
+    if host.contains(char::is_whitespace) {
+        return Err("contains whitespace");
+    }
+    let port = if let Some(rest) = host.strip_prefix('[') {
+        match rest.split_once(']') {
+            Some((ip, port)) if ip.parse::<Ipv6Addr>().is_ok() => match port {
+                "" => None,
+                port => Some(
+                    port.strip_prefix(':')
+                        .ok_or("has junk after the IPv6 address")?,
+                ),
+            },
+            _ => return Err("has an invalid bracketed IPv6 address"),
+        }
+    } else if host.parse::<Ipv6Addr>().is_ok() {
+        None
+    } else {
+        match host.split_once(':') {
+            Some((name, port)) if !name.is_empty() => Some(port),
+            Some(_) => return Err("is missing a host"),
+            None if host.is_empty() => return Err("is missing a host"),
+            None => None,
+        }
+    };
+    match port {
+        Some(port) if port.parse::<u16>().is_err() => Err("has an invalid port"),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_endpoints() {
+        for endpoint in [
+            "stun.example.com",
+            "stun.example.com:3478",
+            "stun:stun.example.com",
+            "stuns:stun.example.com:5349",
+            "192.0.2.1:3478",
+            "[2001:db8::1]:3478",
+            "[2001:db8::1]",
+            "2001:db8::1",
+        ] {
+            assert_eq!(check(endpoint), Ok(()), "{}", endpoint);
+        }
+        assert_eq!(check("stun.example.com:port"), Err("has an invalid port"));
+        assert_eq!(check("stun.example.com:99999"), Err("has an invalid port"));
+        assert_eq!(check("stun example.com"), Err("contains whitespace"));
+        assert_eq!(check(":3478"), Err("is missing a host"));
+        assert_eq!(
+            check("[2001:db8::1]3478"),
+            Err("has junk after the IPv6 address")
+        );
+        assert_eq!(
+            check("[stun]:3478"),
+            Err("has an invalid bracketed IPv6 address")
+        );
+    }
+}
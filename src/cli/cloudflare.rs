@@ -0,0 +1,158 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use std::net::IpAddr;
+
+/// Base URL of the Cloudflare v4 API
+const API_URL: &str = "https://api.cloudflare.com/client/v4";
+
+/// Keeps a Cloudflare DNS record pointed at the public IP, as configured by `--cloudflare-*`
+pub struct Cloudflare {
+    client: Client,
+    /// ID of the zone which holds the record
+    zone: String,
+    /// Full name of the record, e.g. `home.example.com`
+    record: String,
+    /// API token with permission to edit the zone's DNS records
+    token: String,
+}
+
+/// Envelope around every Cloudflare API response
+#[derive(Debug, Deserialize)]
+struct ApiResponse<T> {
+    success: bool,
+    #[serde(default)]
+    errors: Vec<ApiError>,
+    result: Option<T>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiError {
+    code: u32,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DnsRecord {
+    id: String,
+    content: String,
+}
+
+impl Cloudflare {
+    pub fn new(zone: String, record: String, token: String) -> Result<Cloudflare> {
+        let client = Client::builder()
+            .build()
+            .context("Failed to configure HTTP client")?;
+        Ok(Cloudflare {
+            client,
+            zone,
+            record,
+            token,
+        })
+    }
+
+    /// Points the A or AAAA record (matching the family of `ip`) at `ip`, unless it already
+    /// does. The record must already exist.
+    pub async fn update(&self, ip: IpAddr) -> Result<()> {
+        let record_type = if ip.is_ipv4() { "A" } else { "AAAA" };
+        let records: Vec<DnsRecord> = self
+            .call(
+                self.client
+                    .get(format!("{}/zones/{}/dns_records", API_URL, self.zone))
+                    .query(&[("name", self.record.as_str()), ("type", record_type)]),
+            )
+            .await?;
+        let record = match records.as_slice() {
+            [record] => record,
+            [] => bail!(
+                "No Cloudflare {} record named {} in zone {}",
+                record_type,
+                self.record,
+                self.zone
+            ),
+            _ => bail!(
+                "Multiple Cloudflare {} records named {}, not sure which to update",
+                record_type,
+                self.record
+            ),
+        };
+        if record.content == ip.to_string() {
+            debug!("Cloudflare record {} already points to {}", self.record, ip);
+            return Ok(());
+        }
+
+        let _: DnsRecord = self
+            .call(
+                self.client
+                    .patch(format!(
+                        "{}/zones/{}/dns_records/{}",
+                        API_URL, self.zone, record.id
+                    ))
+                    .json(&json!({ "content": ip.to_string() })),
+            )
+            .await?;
+        info!(
+            "Updated Cloudflare {} record {} from {} to {}",
+            record_type, self.record, record.content, ip
+        );
+        Ok(())
+    }
+
+    /// Sends an authenticated API request, returning its result
+    async fn call<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T> {
+        let response = request
+            .bearer_auth(&self.token)
+            .send()
+            .await
+            .context("Cloudflare API request failed")?;
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .context("Failed to read Cloudflare API response")?;
+        debug!("Cloudflare API response ({}): {}", status, body);
+        if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            bail!(
+                "Cloudflare rejected the API token ({}), check that it's valid and can edit \
+                 DNS records in zone {}: {}",
+                status,
+                self.zone,
+                body
+            );
+        }
+        let response: ApiResponse<T> = serde_json::from_str(&body)
+            .with_context(|| format!("Malformed Cloudflare API response ({}): {}", status, body))?;
+        match response.result {
+            Some(result) if response.success => Ok(result),
+            _ => {
+                let errors: Vec<String> = response
+                    .errors
+                    .iter()
+                    .map(|e| format!("{} (code {})", e.message, e.code))
+                    .collect();
+                bail!("Cloudflare API error ({}): {}", status, errors.join(", "))
+            }
+        }
+    }
+}
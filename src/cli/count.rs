@@ -0,0 +1,126 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::cli::query::Query;
+use anyhow::{anyhow, Result};
+use log::warn;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::time;
+
+/// Time between queries in `--count` mode
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Queries the server(s) `count` times like `ping`, printing each result and then a summary
+/// of the round-trip times and losses. Fails if none of the queries got a response.
+pub async fn run(query: &mut Query, count: u32) -> Result<()> {
+    let mut rtts = Vec::new();
+    let mut first_ip: Option<IpAddr> = None;
+    let mut last_err = None;
+    for seq in 1..=count {
+        if seq > 1 {
+            time::sleep(INTERVAL).await;
+        }
+        match query.run().await {
+            Ok((server, binding)) => {
+                let rtt_ms = binding.rtt.as_secs_f64() * 1000.0;
+                println!(
+                    "{}: seq={} addr={} time={:.1}ms",
+                    server, seq, binding.addr, rtt_ms
+                );
+                match first_ip {
+                    Some(ip) if ip != binding.addr.ip() => {
+                        warn!("Public IP changed from {} to {}", ip, binding.addr.ip())
+                    }
+                    Some(_) => {}
+                    None => first_ip = Some(binding.addr.ip()),
+                }
+                rtts.push(rtt_ms);
+            }
+            Err(e) => {
+                println!("seq={} failed: {:#}", seq, e);
+                last_err = Some(e);
+            }
+        }
+    }
+
+    println!("--- {} ---", query.endpoints.join(", "));
+    println!(
+        "{} queries, {} responses, {:.0}% loss",
+        count,
+        rtts.len(),
+        100.0 * f64::from(count - rtts.len() as u32) / f64::from(count)
+    );
+    match Stats::of(&rtts) {
+        Some(stats) => {
+            println!(
+                "rtt min/avg/max/stddev = {:.3}/{:.3}/{:.3}/{:.3} ms",
+                stats.min, stats.avg, stats.max, stats.stddev
+            );
+            Ok(())
+        }
+        None => Err(last_err.unwrap_or_else(|| anyhow!("No queries were sent"))),
+    }
+}
+
+/// Summary of a set of round-trip times
+#[derive(Debug, PartialEq)]
+struct Stats {
+    min: f64,
+    avg: f64,
+    max: f64,
+    /// Population standard deviation, like ping's mdev
+    stddev: f64,
+}
+
+impl Stats {
+    /// Returns the summary of `values`, or `None` if there aren't any
+    fn of(values: &[f64]) -> Option<Stats> {
+        if values.is_empty() {
+            return None;
+        }
+        let n = values.len() as f64;
+        let avg = values.iter().sum::<f64>() / n;
+        let variance = values.iter().map(|v| (v - avg).powi(2)).sum::<f64>() / n;
+        Some(Stats {
+            min: values.iter().cloned().fold(f64::INFINITY, f64::min),
+            avg,
+            max: values.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+            stddev: variance.sqrt(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_stats() {
+        assert_eq!(Stats::of(&[]), None);
+        assert_eq!(
+            Stats::of(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]),
+            Some(Stats {
+                min: 2.0,
+                avg: 5.0,
+                max: 9.0,
+                stddev: 2.0,
+            })
+        );
+    }
+}
@@ -0,0 +1,104 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use reqwest::Client;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// DuckDNS's update endpoint
+const UPDATE_URL: &str = "https://www.duckdns.org/update";
+
+/// Points a DuckDNS subdomain at the public IP, as configured by `--duckdns`
+pub struct DuckDns {
+    client: Client,
+    /// Subdomain to update, e.g. `home` for `home.duckdns.org`
+    domain: String,
+    /// Account token shown on the DuckDNS dashboard
+    token: String,
+}
+
+impl DuckDns {
+    pub fn new(domain: String, token: String) -> Result<DuckDns> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .context("Failed to configure HTTP client")?;
+        // DuckDNS only wants the subdomain, but accept the full name too
+        let domain = domain.trim_end_matches(".duckdns.org").to_string();
+        Ok(DuckDns {
+            client,
+            domain,
+            token,
+        })
+    }
+
+    /// Points the A or AAAA record (matching the family of `ip`) of the domain at `ip`
+    pub async fn update(&self, ip: IpAddr) -> Result<()> {
+        let ip_param = if ip.is_ipv4() { "ip" } else { "ipv6" };
+        let response = self
+            .client
+            .get(UPDATE_URL)
+            .query(&[
+                ("domains", self.domain.as_str()),
+                ("token", self.token.as_str()),
+                (ip_param, ip.to_string().as_str()),
+            ])
+            .send()
+            .await
+            .context("DuckDNS update request failed")?;
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .context("Failed to read DuckDNS response")?;
+        debug!("DuckDNS response ({}): {}", status, body);
+        if !status.is_success() {
+            bail!("DuckDNS update failed ({}): {}", status, body.trim());
+        }
+        check_response(&body).with_context(|| format!("Failed to update {}", self.domain))?;
+        info!("Updated DuckDNS domain {} to {}", self.domain, ip);
+        Ok(())
+    }
+}
+
+/// Interprets the body of a DuckDNS update response, which is just `OK` or `KO`
+fn check_response(body: &str) -> Result<()> {
+    match body.lines().next().map(str::trim) {
+        Some("OK") => Ok(()),
+        Some("KO") => bail!(
+            "DuckDNS rejected the update (KO), check that the domain belongs to the account \
+             of the token"
+        ),
+        _ => bail!("Unexpected DuckDNS response: {}", body.trim()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_response() {
+        assert!(check_response("OK").is_ok());
+        assert!(check_response("OK\n1.2.3.4\n\nUPDATED").is_ok());
+        assert!(check_response("KO").is_err());
+        assert!(check_response("<html>").is_err());
+    }
+}
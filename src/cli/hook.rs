@@ -0,0 +1,52 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use log::{debug, warn};
+use std::net::IpAddr;
+use tokio::process::Command;
+
+/// Runs `cmd` through `sh` in the background, passing `ip` as `$1` and as `$IPLOOKUP_IP`.
+/// The previous IP, if any, is passed as `$IPLOOKUP_PREVIOUS_IP`. This returns immediately
+/// so that a slow hook doesn't delay the next check. The hook's exit status is logged in
+/// at debug level, and failures to run it are logged as warnings.
+pub fn spawn(cmd: &str, ip: IpAddr, previous: Option<IpAddr>) {
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg(cmd)
+        .arg("iplookup")
+        .arg(ip.to_string())
+        .env("IPLOOKUP_IP", ip.to_string());
+    if let Some(previous) = previous {
+        command.env("IPLOOKUP_PREVIOUS_IP", previous.to_string());
+    }
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            warn!("Failed to run --on-change command '{}': {}", cmd, e);
+            return;
+        }
+    };
+    let cmd = cmd.to_string();
+    tokio::spawn(async move {
+        match child.wait().await {
+            Ok(status) => debug!("--on-change command '{}' exited with {}", cmd, status),
+            Err(e) => warn!("Failed to wait for --on-change command '{}': {}", cmd, e),
+        }
+    });
+}
@@ -0,0 +1,162 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::cli::atomic;
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use std::fs;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+/// Keeps a hostname in a hosts file pointed at the public IP, as configured by
+/// `--update-hosts`
+pub struct HostsFile {
+    path: PathBuf,
+    /// Hostname to point at the public IP, e.g. `home.lan`
+    name: String,
+}
+
+impl HostsFile {
+    pub fn new(path: PathBuf, name: String) -> Result<HostsFile> {
+        if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c == '#') {
+            bail!("Invalid hostname '{}' for --update-hosts", name);
+        }
+        Ok(HostsFile { path, name })
+    }
+
+    /// Points the hostname at `ip`, leaving every other line of the file as it was. The file
+    /// is replaced atomically, and left untouched if any line can't be parsed.
+    pub fn update(&self, ip: IpAddr) -> Result<()> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", self.path)),
+        };
+        let updated = rewrite(&content, &self.name, ip)
+            .with_context(|| format!("Not updating {:?}", self.path))?;
+        match updated {
+            Some(updated) => {
+                atomic::write(&self.path, updated.as_bytes())?;
+                info!("Pointed {} at {} in {:?}", self.name, ip, self.path);
+            }
+            None => debug!("{} already points at {} in {:?}", self.name, ip, self.path),
+        }
+        Ok(())
+    }
+}
+
+/// Returns `content` with `name` pointed at `ip`, or `None` if it already was. Only entries of
+/// the same family as `ip` are touched, so that a name can have both an IPv4 and an IPv6 entry.
+/// The first entry for `name` has its address replaced in place, any others are removed, and a
+/// new entry is appended if there was none. Fails rather than guess if a line doesn't start
+/// with an address, or if `name` shares an entry with other names.
+fn rewrite(content: &str, name: &str, ip: IpAddr) -> Result<Option<String>> {
+    let mut lines = Vec::new();
+    let mut found = false;
+    let mut changed = false;
+    for (number, line) in content.lines().enumerate() {
+        let entry = line.split('#').next().unwrap_or("");
+        let mut fields = entry.split_whitespace();
+        let addr = match fields.next() {
+            Some(addr) => addr,
+            // Blank or comment
+            None => {
+                lines.push(line.to_string());
+                continue;
+            }
+        };
+        // Link-local entries can have a zone, e.g. fe80::1%lo0
+        let unzoned = addr.split('%').next().unwrap_or(addr);
+        let entry_ip: IpAddr = unzoned.parse().with_context(|| {
+            format!(
+                "Line {} doesn't start with an IP address: {}",
+                number + 1,
+                line
+            )
+        })?;
+        let names: Vec<&str> = fields.collect();
+        if entry_ip.is_ipv4() != ip.is_ipv4()
+            || !names.iter().any(|entry| entry.eq_ignore_ascii_case(name))
+        {
+            lines.push(line.to_string());
+            continue;
+        }
+        if names.len() > 1 {
+            bail!(
+                "Line {} maps {} along with other names, move it to a line of its own: {}",
+                number + 1,
+                name,
+                line
+            );
+        }
+        if found {
+            changed = true;
+            continue;
+        }
+        found = true;
+        if entry_ip == ip {
+            lines.push(line.to_string());
+        } else {
+            // Keep any indentation, spacing and comment around the address
+            let start = line.find(addr).unwrap_or(0);
+            lines.push(format!(
+                "{}{}{}",
+                &line[..start],
+                ip,
+                &line[start + addr.len()..]
+            ));
+            changed = true;
+        }
+    }
+    if !found {
+        lines.push(format!("{}\t{}", ip, name));
+        changed = true;
+    }
+    if !changed {
+        return Ok(None);
+    }
+    let mut updated = lines.join("\n");
+    updated.push('\n');
+    Ok(Some(updated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrites_only_matching_entry() {
+        let hosts = "# Static entries\n127.0.0.1\tlocalhost\n::1 localhost\n\n  192.0.2.1  home.lan # public\n";
+        let ip: IpAddr = "198.51.100.7".parse().unwrap();
+        assert_eq!(
+            rewrite(hosts, "home.lan", ip).unwrap().unwrap(),
+            "# Static entries\n127.0.0.1\tlocalhost\n::1 localhost\n\n  198.51.100.7  home.lan # public\n"
+        );
+        assert_eq!(
+            rewrite(hosts, "home.lan", "192.0.2.1".parse().unwrap()).unwrap(),
+            None
+        );
+        assert_eq!(
+            rewrite(hosts, "other.lan", ip).unwrap().unwrap(),
+            format!("{}198.51.100.7\tother.lan\n", hosts)
+        );
+        assert!(rewrite("127.0.0.1 localhost home.lan\n", "home.lan", ip).is_err());
+        assert!(rewrite("garbage line\n", "home.lan", ip).is_err());
+    }
+}
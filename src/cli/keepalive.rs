@@ -0,0 +1,89 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::cli::query::Query;
+use crate::cli::watch;
+use anyhow::Result;
+use log::{info, warn};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::{self, MissedTickBehavior};
+
+/// Queries a single UDP server every `interval` from the same local socket, so that the NAT
+/// keeps its mapping for that socket open, and prints the mapped address each time. Any change
+/// in the mapped address means the NAT dropped the old mapping and created a new one, which is
+/// warned about. Returns once SIGINT or SIGTERM is received.
+pub async fn run(query: &mut Query, interval: Duration) -> Result<()> {
+    let shutdown = watch::shutdown_signal()?;
+    tokio::pin!(shutdown);
+    let mut ticker = time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut first: Option<SocketAddr> = None;
+    let mut last: Option<SocketAddr> = None;
+    let mut changes = 0;
+    loop {
+        let result = tokio::select! {
+            result = async {
+                ticker.tick().await;
+                query.run().await
+            } => result,
+            signal = &mut shutdown => {
+                info!("Received {}, exiting", signal);
+                break;
+            }
+        };
+        let (server, binding) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                warn!("Keepalive failed, retrying in {:?}: {:#}", interval, e);
+                continue;
+            }
+        };
+        let local = query
+            .local_addr()
+            .map_or_else(|| "?".to_string(), |addr| addr.to_string());
+        println!(
+            "{}: local={} mapped={} time={:.1}ms",
+            server,
+            local,
+            binding.addr,
+            binding.rtt.as_secs_f64() * 1000.0
+        );
+        match last {
+            Some(previous) if previous != binding.addr => {
+                changes += 1;
+                warn!(
+                    "NAT mapping for local {} changed from {} to {}: the NAT dropped the \
+                     previous binding despite keepalives every {:?}",
+                    local, previous, binding.addr, interval
+                );
+            }
+            Some(_) => {}
+            None => first = Some(binding.addr),
+        }
+        last = Some(binding.addr);
+    }
+
+    if let Some(first) = first {
+        match changes {
+            0 => println!("Mapped address {} was stable", first),
+            n => println!("Mapped address changed {} time(s) since {}", n, first),
+        }
+    }
+    Ok(())
+}
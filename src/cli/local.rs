@@ -0,0 +1,215 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{Context, Result};
+use iplookup::Binding;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+/// An address assigned to one of the host's network interfaces
+pub struct LocalAddr {
+    pub interface: String,
+    pub ip: IpAddr,
+}
+
+/// Returns the addresses assigned to the host's network interfaces, including loopback
+pub fn interface_addrs() -> Result<Vec<LocalAddr>> {
+    let interfaces = if_addrs::get_if_addrs().context("Failed to list network interfaces")?;
+    Ok(interfaces
+        .into_iter()
+        .map(|interface| LocalAddr {
+            ip: interface.ip(),
+            interface: interface.name,
+        })
+        .collect())
+}
+
+/// Lists the non-loopback addresses in `interfaces` one per line, labelling link-local ones
+pub fn render_local(interfaces: &[LocalAddr]) -> String {
+    let mut lines = vec!["Local addresses:".to_string()];
+    for addr in interfaces.iter().filter(|addr| !addr.ip.is_loopback()) {
+        if is_link_local(&addr.ip) {
+            lines.push(format!("  {}: {} (link-local)", addr.interface, addr.ip));
+        } else {
+            lines.push(format!("  {}: {}", addr.interface, addr.ip));
+        }
+    }
+    if lines.len() == 1 {
+        lines.push("  (none)".to_string());
+    }
+    lines.join("\n")
+}
+
+/// Describes whether the host is behind a NAT, based on whether the public IP in `binding` is
+/// assigned to one of `interfaces`. `local` is the address of the socket which received the
+/// response, if known.
+pub fn describe_nat(
+    binding: &Binding,
+    local: Option<SocketAddr>,
+    interfaces: &[LocalAddr],
+) -> String {
+    let public = binding.addr;
+    let ip = public.ip();
+    let assigned = interfaces
+        .iter()
+        .find(|addr| addr.ip == ip)
+        .map(|addr| addr.interface.as_str())
+        .or_else(|| {
+            local
+                .filter(|local| local.ip() == ip)
+                .map(|_| "the local socket")
+        });
+    if let Some(interface) = assigned {
+        // A firewall may still rewrite the port without changing the IP
+        return match local {
+            Some(local)
+                if local.port() != 0 && public.port() != 0 && local.port() != public.port() =>
+            {
+                format!(
+                    "No NAT: public IP {} is assigned to {}, but local port {} was translated to {}",
+                    ip,
+                    interface,
+                    local.port(),
+                    public.port()
+                )
+            }
+            _ => format!("No NAT: public IP {} is assigned to {}", ip, interface),
+        };
+    }
+
+    let candidates: Vec<String> = interfaces
+        .iter()
+        .filter(|addr| addr.ip.is_ipv4() == ip.is_ipv4() && is_routable(&addr.ip))
+        .map(|addr| addr.ip.to_string())
+        .collect();
+    match ip {
+        // IPv6 hosts usually have public addresses, so a mismatch points to prefix translation
+        IpAddr::V6(_) if !candidates.is_empty() => format!(
+            "Behind NAT66 or NPTv6: public IP {} isn't assigned to any local interface, \
+             though the host has global IPv6 addresses {}",
+            ip,
+            candidates.join(", ")
+        ),
+        _ if candidates.is_empty() => format!(
+            "Behind NAT: public IP {} isn't assigned to any local interface",
+            ip
+        ),
+        _ => format!(
+            "Behind NAT: public IP {} isn't assigned to any local interface (local addresses: {})",
+            ip,
+            candidates.join(", ")
+        ),
+    }
+}
+
+/// Returns whether `ip` is only valid on its local link: 169.254.0.0/16 or fe80::/10
+fn is_link_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_unicast_link_local(),
+    }
+}
+
+/// Returns whether `ip` could be the source of a packet on the internet, or on a LAN behind a
+/// NAT. Link-local addresses are excluded, along with IPv6 addresses outside of 2000::/3.
+fn is_routable(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => !ip.is_link_local() && !ip.is_loopback(),
+        IpAddr::V6(ip) => is_global_ipv6(ip),
+    }
+}
+
+/// Returns whether `ip` is a global unicast address in 2000::/3, rather than e.g. link-local
+/// (fe80::/10) or unique local (fc00::/7)
+pub fn is_global_ipv6(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xe000 == 0x2000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn binding(addr: &str) -> Binding {
+        Binding {
+            addr: addr.parse().unwrap(),
+            other_address: None,
+            rtt: Duration::from_millis(10),
+            response_origin: None,
+            attributes: Vec::new(),
+        }
+    }
+
+    fn local(interface: &str, ip: &str) -> LocalAddr {
+        LocalAddr {
+            interface: interface.to_string(),
+            ip: ip.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn renders_local_addresses() {
+        let interfaces = vec![
+            local("lo", "127.0.0.1"),
+            local("eth0", "192.168.1.10"),
+            local("eth0", "fe80::1"),
+            local("eth0", "2001:db8::10"),
+        ];
+        assert_eq!(
+            render_local(&interfaces),
+            "Local addresses:\n  eth0: 192.168.1.10\n  eth0: fe80::1 (link-local)\n  \
+             eth0: 2001:db8::10"
+        );
+        assert_eq!(render_local(&interfaces[..1]), "Local addresses:\n  (none)");
+    }
+
+    #[test]
+    fn describes_nat_presence() {
+        let interfaces = vec![
+            local("eth0", "192.168.1.10"),
+            local("eth0", "fe80::1"),
+            local("eth0", "2001:db8::10"),
+        ];
+        assert_eq!(
+            describe_nat(&binding("203.0.113.5:4000"), None, &interfaces),
+            "Behind NAT: public IP 203.0.113.5 isn't assigned to any local interface \
+             (local addresses: 192.168.1.10)"
+        );
+        assert_eq!(
+            describe_nat(&binding("[2001:db8::10]:4000"), None, &interfaces),
+            "No NAT: public IP 2001:db8::10 is assigned to eth0"
+        );
+        assert_eq!(
+            describe_nat(
+                &binding("[2001:db8::10]:4000"),
+                Some("[::]:5000".parse().unwrap()),
+                &interfaces
+            ),
+            "No NAT: public IP 2001:db8::10 is assigned to eth0, but local port 5000 was \
+             translated to 4000"
+        );
+        assert_eq!(
+            describe_nat(&binding("[2001:db8:1::20]:4000"), None, &interfaces),
+            "Behind NAT66 or NPTv6: public IP 2001:db8:1::20 isn't assigned to any local \
+             interface, though the host has global IPv6 addresses 2001:db8::10"
+        );
+        assert_eq!(
+            describe_nat(&binding("[2001:db8:1::20]:4000"), None, &interfaces[..2]),
+            "Behind NAT: public IP 2001:db8:1::20 isn't assigned to any local interface"
+        );
+    }
+}
@@ -0,0 +1,128 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::cli::atomic;
+use anyhow::Result;
+use iplookup::Binding;
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Writes the outcome of each lookup to PATH in the Prometheus text format, as used by
+/// `--metrics-file` for the node_exporter textfile collector
+pub struct Metrics {
+    path: PathBuf,
+    /// When the last successful lookup completed, in seconds since the UNIX epoch
+    last_success: Option<u64>,
+}
+
+impl Metrics {
+    pub fn new(path: PathBuf) -> Metrics {
+        Metrics {
+            path,
+            last_success: None,
+        }
+    }
+
+    /// Records a lookup which returned `binding`, or which failed if `None`, and atomically
+    /// rewrites the file
+    pub fn record(&mut self, binding: Option<&Binding>) -> Result<()> {
+        if binding.is_some() {
+            self.last_success = Some(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+            );
+        }
+        atomic::write(&self.path, render(binding, self.last_success).as_bytes())
+    }
+}
+
+/// Returns the exposition text for a lookup which returned `binding`, or which failed if
+/// `None`. The RTT and last success are omitted when they aren't known.
+fn render(binding: Option<&Binding>, last_success: Option<u64>) -> String {
+    let mut out = String::new();
+    metric(
+        &mut out,
+        "iplookup_success",
+        "Whether the last lookup succeeded",
+        if binding.is_some() { 1.0 } else { 0.0 },
+    );
+    if let Some(binding) = binding {
+        metric(
+            &mut out,
+            "iplookup_rtt_milliseconds",
+            "Round-trip time of the last successful lookup",
+            binding.rtt.as_secs_f64() * 1000.0,
+        );
+    }
+    if let Some(timestamp) = last_success {
+        metric(
+            &mut out,
+            "iplookup_last_success_timestamp_seconds",
+            "When the last successful lookup completed, in seconds since the UNIX epoch",
+            timestamp as f64,
+        );
+    }
+    out
+}
+
+/// Appends a gauge `name` with its HELP and TYPE lines to `out`
+fn metric(out: &mut String, name: &str, help: &str, value: f64) {
+    // Writing to a String can't fail
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn renders_success_and_failure() {
+        let binding = Binding {
+            addr: "192.0.2.1:4000".parse().unwrap(),
+            other_address: None,
+            rtt: Duration::from_micros(23500),
+            response_origin: None,
+            attributes: Vec::new(),
+        };
+        assert_eq!(
+            render(Some(&binding), Some(1700000000)),
+            "# HELP iplookup_success Whether the last lookup succeeded\n\
+             # TYPE iplookup_success gauge\n\
+             iplookup_success 1\n\
+             # HELP iplookup_rtt_milliseconds Round-trip time of the last successful lookup\n\
+             # TYPE iplookup_rtt_milliseconds gauge\n\
+             iplookup_rtt_milliseconds 23.5\n\
+             # HELP iplookup_last_success_timestamp_seconds When the last successful lookup \
+             completed, in seconds since the UNIX epoch\n\
+             # TYPE iplookup_last_success_timestamp_seconds gauge\n\
+             iplookup_last_success_timestamp_seconds 1700000000\n"
+        );
+        assert_eq!(
+            render(None, None),
+            "# HELP iplookup_success Whether the last lookup succeeded\n\
+             # TYPE iplookup_success gauge\n\
+             iplookup_success 0\n"
+        );
+    }
+}
@@ -0,0 +1,45 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Pieces of the `iplookup` binary which aren't part of the library API
+
+pub mod atomic;
+pub mod batch;
+pub mod cloudflare;
+pub mod count;
+pub mod duckdns;
+pub mod hook;
+pub mod hosts;
+pub mod keepalive;
+pub mod local;
+pub mod metrics;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod noip;
+#[cfg(feature = "notify")]
+pub mod notify;
+pub mod nsupdate;
+pub mod output;
+pub mod query;
+pub mod route53;
+pub mod servers;
+pub mod state;
+pub mod syslog;
+pub mod systemd;
+pub mod watch;
+pub mod webhook;
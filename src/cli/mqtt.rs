@@ -0,0 +1,150 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use reqwest::Url;
+use rumqttc::tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use rumqttc::{AsyncClient, Event, MqttOptions, Outgoing, Packet, QoS, Transport};
+use std::net::IpAddr;
+use std::process;
+use std::time::Duration;
+use tokio::time;
+
+/// How long to wait for the broker to accept a message
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Publishes the public IP to an MQTT broker as a retained message, as configured by `--mqtt`
+pub struct Mqtt {
+    options: MqttOptions,
+    /// The broker URL, for messages
+    url: String,
+    topic: String,
+    qos: QoS,
+}
+
+impl Mqtt {
+    /// Configures publishing to `topic` on the broker at `url`, which is `mqtt://HOST[:PORT]`
+    /// or `mqtts://HOST[:PORT]` for TLS. The port defaults to 1883, or 8883 with TLS.
+    pub fn new(
+        url: &str,
+        topic: String,
+        credentials: Option<(String, String)>,
+        qos: QoS,
+    ) -> Result<Mqtt> {
+        let parsed = Url::parse(url).with_context(|| format!("Invalid MQTT broker URL {}", url))?;
+        let tls = match parsed.scheme() {
+            "mqtt" | "tcp" => false,
+            "mqtts" | "ssl" => true,
+            scheme => bail!(
+                "Unsupported MQTT broker URL scheme '{}', expected mqtt:// or mqtts://",
+                scheme
+            ),
+        };
+        let host = parsed
+            .host_str()
+            .with_context(|| format!("MQTT broker URL {} has no host", url))?
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let port = parsed.port().unwrap_or(if tls { 8883 } else { 1883 });
+        let mut options = MqttOptions::new(format!("iplookup-{}", process::id()), host, port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let Some((username, password)) = credentials {
+            options.set_credentials(username, password);
+        }
+        if tls {
+            options.set_transport(Transport::tls_with_config(root_config()?.into()));
+        }
+        Ok(Mqtt {
+            options,
+            url: url.to_string(),
+            topic,
+            qos,
+        })
+    }
+
+    /// Connects to the broker, publishes `ip` as the retained value of the topic, and
+    /// disconnects again once the broker has acknowledged it at the configured QoS
+    pub async fn publish(&self, ip: IpAddr) -> Result<()> {
+        let (client, mut eventloop) = AsyncClient::new(self.options.clone(), 10);
+        client
+            .publish(&self.topic, self.qos, true, ip.to_string())
+            .await
+            .context("Failed to queue MQTT message")?;
+        let delivered = async {
+            loop {
+                let event = eventloop.poll().await?;
+                debug!("MQTT event: {:?}", event);
+                match (event, self.qos) {
+                    (Event::Outgoing(Outgoing::Publish(_)), QoS::AtMostOnce)
+                    | (Event::Incoming(Packet::PubAck(_)), QoS::AtLeastOnce)
+                    | (Event::Incoming(Packet::PubComp(_)), QoS::ExactlyOnce) => {
+                        return Ok::<_, rumqttc::ConnectionError>(())
+                    }
+                    _ => {}
+                }
+            }
+        };
+        time::timeout(TIMEOUT, delivered)
+            .await
+            .with_context(|| {
+                format!(
+                    "Timed out after {}s publishing to MQTT broker {}",
+                    TIMEOUT.as_secs(),
+                    self.url
+                )
+            })?
+            .with_context(|| format!("Failed to publish to MQTT broker {}", self.url))?;
+        info!(
+            "Published {} to MQTT topic {} on {}",
+            ip, self.topic, self.url
+        );
+
+        // Let the broker know we're leaving, but the message has already been delivered
+        if client.disconnect().await.is_ok() {
+            let _ = time::timeout(Duration::from_secs(1), eventloop.poll()).await;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a `--mqtt-qos` value
+pub fn parse_qos(qos: &str) -> Result<QoS, String> {
+    match qos {
+        "0" => Ok(QoS::AtMostOnce),
+        "1" => Ok(QoS::AtLeastOnce),
+        "2" => Ok(QoS::ExactlyOnce),
+        _ => Err(format!("Invalid MQTT QoS '{}', expected 0, 1 or 2", qos)),
+    }
+}
+
+/// Returns a TLS configuration which trusts the system's root certificates
+fn root_config() -> Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    let native = rustls_native_certs::load_native_certs();
+    let (added, _ignored) = roots.add_parsable_certificates(native.certs);
+    if added == 0 {
+        bail!(
+            "Couldn't load any trusted root certificates for connecting to the MQTT broker: {:?}",
+            native.errors
+        );
+    }
+    Ok(ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
@@ -0,0 +1,191 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use reqwest::Client;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// No-IP's update endpoint, which follows the dyndns2 protocol
+const UPDATE_URL: &str = "https://dynupdate.no-ip.com/nic/update";
+
+/// How long No-IP asks clients to wait after a `911` response
+const RETRY_AFTER_911: Duration = Duration::from_secs(30 * 60);
+
+/// Points a No-IP hostname at the public IP, as configured by `--noip`
+pub struct NoIp {
+    client: Client,
+    /// Hostname to update, e.g. `home.ddns.net`
+    hostname: String,
+    username: String,
+    password: String,
+    /// Set once No-IP asks us to stop sending updates
+    blocked: Mutex<Option<Blocked>>,
+}
+
+struct Blocked {
+    /// What No-IP said
+    reason: String,
+    /// When updates may be sent again, or `None` if they mustn't be sent again at all
+    until: Option<Instant>,
+}
+
+/// No-IP's verdict on an update, from the first word of its response
+#[derive(Debug, PartialEq, Eq)]
+enum Reply {
+    /// The hostname now points at the IP
+    Good,
+    /// The hostname already pointed at the IP
+    NoChange,
+    /// Something is wrong with the account or hostname, and retrying won't help until the user
+    /// fixes it
+    Fatal(String),
+    /// No-IP is having trouble and asks clients to try again later
+    Retry(String),
+    /// Anything else, e.g. an error page from a proxy
+    Unknown(String),
+}
+
+impl NoIp {
+    pub fn new(hostname: String, username: String, password: String) -> Result<NoIp> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(30))
+            // No-IP blocks clients which don't identify themselves
+            .user_agent(format!(
+                "iplookup/{} {}",
+                env!("CARGO_PKG_VERSION"),
+                env!("CARGO_PKG_REPOSITORY")
+            ))
+            .build()
+            .context("Failed to configure HTTP client")?;
+        Ok(NoIp {
+            client,
+            hostname,
+            username,
+            password,
+            blocked: Mutex::new(None),
+        })
+    }
+
+    /// Points the hostname at `ip`. After a response telling us to stop, later calls fail
+    /// without contacting No-IP, as its update policy requires.
+    pub async fn update(&self, ip: IpAddr) -> Result<()> {
+        if let Some(blocked) = self.blocked.lock().unwrap().as_ref() {
+            match blocked.until {
+                Some(until) if until <= Instant::now() => {}
+                Some(until) => bail!(
+                    "Not updating No-IP for another {}s after: {}",
+                    until.saturating_duration_since(Instant::now()).as_secs(),
+                    blocked.reason
+                ),
+                None => bail!("Not updating No-IP after: {}", blocked.reason),
+            }
+        }
+
+        let response = self
+            .client
+            .get(UPDATE_URL)
+            .basic_auth(&self.username, Some(&self.password))
+            .query(&[
+                ("hostname", self.hostname.as_str()),
+                ("myip", ip.to_string().as_str()),
+            ])
+            .send()
+            .await
+            .context("No-IP update request failed")?;
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .context("Failed to read No-IP response")?;
+        debug!("No-IP response ({}): {}", status, body);
+
+        let block = |reason: &str, until| {
+            *self.blocked.lock().unwrap() = Some(Blocked {
+                reason: reason.to_string(),
+                until,
+            });
+        };
+        match parse_response(&body, &self.hostname) {
+            Reply::Good => {
+                *self.blocked.lock().unwrap() = None;
+                info!("Updated No-IP hostname {} to {}", self.hostname, ip);
+                Ok(())
+            }
+            Reply::NoChange => {
+                *self.blocked.lock().unwrap() = None;
+                info!("No-IP hostname {} already pointed to {}", self.hostname, ip);
+                Ok(())
+            }
+            Reply::Fatal(reason) => {
+                block(&reason, None);
+                bail!("{}", reason)
+            }
+            Reply::Retry(reason) => {
+                warn!(
+                    "Waiting {} minutes before updating No-IP again",
+                    RETRY_AFTER_911.as_secs() / 60
+                );
+                block(&reason, Some(Instant::now() + RETRY_AFTER_911));
+                bail!("{}", reason)
+            }
+            Reply::Unknown(reason) => bail!("{} ({})", reason, status),
+        }
+    }
+}
+
+/// Interprets the body of a No-IP update response for `hostname`
+fn parse_response(body: &str, hostname: &str) -> Reply {
+    let code = body.split_whitespace().next().unwrap_or("");
+    match code {
+        "good" => Reply::Good,
+        "nochg" => Reply::NoChange,
+        "nohost" => Reply::Fatal(format!(
+            "No-IP account has no hostname {} (nohost)",
+            hostname
+        )),
+        "badauth" => Reply::Fatal("No-IP rejected the username or password (badauth)".to_string()),
+        "badagent" => Reply::Fatal("No-IP blocked this client (badagent)".to_string()),
+        "!donator" => {
+            Reply::Fatal("No-IP only allows this update for paid accounts (!donator)".to_string())
+        }
+        "abuse" => Reply::Fatal(format!(
+            "No-IP blocked updates of {} for abuse (abuse)",
+            hostname
+        )),
+        "911" => Reply::Retry("No-IP is having problems (911)".to_string()),
+        _ => Reply::Unknown(format!("Unexpected No-IP response: {}", body.trim())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_responses() {
+        let host = "home.ddns.net";
+        assert_eq!(parse_response("good 192.0.2.1", host), Reply::Good);
+        assert_eq!(parse_response("nochg 192.0.2.1\r\n", host), Reply::NoChange);
+        assert!(matches!(parse_response("badauth", host), Reply::Fatal(_)));
+        assert!(matches!(parse_response("abuse", host), Reply::Fatal(_)));
+        assert!(matches!(parse_response("911", host), Reply::Retry(_)));
+    }
+}
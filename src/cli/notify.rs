@@ -0,0 +1,40 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use log::{debug, warn};
+use notify_rust::Notification;
+use std::net::IpAddr;
+use tokio::task;
+
+/// Pops up a desktop notification that the public IP changed from `previous` to `ip`. This
+/// returns immediately, and failures such as there being no notification daemon are only
+/// logged, so that watching carries on regardless.
+pub fn spawn(ip: IpAddr, previous: IpAddr) {
+    let body = format!("{} → {}", previous, ip);
+    task::spawn_blocking(move || {
+        let result = Notification::new()
+            .appname("iplookup")
+            .summary("Public IP changed")
+            .body(&body)
+            .show();
+        match result {
+            Ok(_) => debug!("Showed desktop notification: {}", body),
+            Err(e) => warn!("Failed to show desktop notification: {}", e),
+        }
+    });
+}
@@ -0,0 +1,329 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hickory_proto::op::{Message, MessageType, OpCode, Query, ResponseCode, UpdateMessage};
+use hickory_proto::rr::dnssec::rdata::tsig::TsigAlgorithm;
+use hickory_proto::rr::dnssec::tsig::TSigner;
+use hickory_proto::rr::rdata::{A, AAAA};
+use hickory_proto::rr::{DNSClass, Name, RData, Record, RecordType};
+use log::{debug, info};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::{self, UdpSocket};
+use tokio::time;
+
+/// Maximum difference in seconds between our clock and the server's for a signature to be
+/// accepted, the value recommended by RFC 8945
+const FUDGE: u16 = 300;
+
+/// Keeps a record on an authoritative DNS server pointed at the public IP, using TSIG-signed
+/// RFC 2136 updates, as configured by `--nsupdate-*`
+pub struct Nsupdate {
+    /// Authoritative server to send updates to
+    server: String,
+    /// Zone which holds the record, e.g. `example.com.`
+    zone: Name,
+    /// Full name of the record, e.g. `home.example.com.`
+    record: Name,
+    /// TTL to give the record, in seconds
+    ttl: u32,
+    signer: TSigner,
+    /// How long to wait for each response from the server
+    timeout: Duration,
+}
+
+impl Nsupdate {
+    /// Configures updates of `record` in `zone` on `server` (an IP or host name with an
+    /// optional port, defaulting to 53), signed with `signer`
+    pub fn new(
+        server: String,
+        zone: &str,
+        record: &str,
+        signer: TSigner,
+        ttl: u32,
+        timeout: Duration,
+    ) -> Result<Nsupdate> {
+        let zone = fqdn(zone).with_context(|| format!("Invalid DNS zone {}", zone))?;
+        let record = fqdn(record).with_context(|| format!("Invalid record name {}", record))?;
+        if !zone.zone_of(&record) {
+            bail!("Record {} isn't in zone {}", record, zone);
+        }
+        Ok(Nsupdate {
+            server,
+            zone,
+            record,
+            ttl,
+            signer,
+            timeout,
+        })
+    }
+
+    /// Points the A or AAAA record (matching the family of `ip`) at `ip`, unless the server
+    /// already has exactly that value, so that the zone's serial isn't bumped needlessly
+    pub async fn update(&self, ip: IpAddr) -> Result<()> {
+        let server = self.resolve().await?;
+        let record_type = record_type(ip);
+        let current = self.current(server, record_type).await?;
+        if current == [ip] {
+            debug!(
+                "DNS record {} on {} already points to {}",
+                self.record, server, ip
+            );
+            return Ok(());
+        }
+
+        let mut message = self.update_message(ip);
+        let mut verifier = message
+            .finalize(&self.signer, unix_time())
+            .context("Failed to sign DNS UPDATE")?
+            .context("Signing DNS UPDATE didn't produce a verifier")?;
+        let response = self.exchange(server, &message).await?;
+        let code = Message::from_vec(&response)
+            .context("Malformed DNS UPDATE response")?
+            .response_code();
+        if code != ResponseCode::NoError {
+            bail!(
+                "DNS server {} rejected the update of {} with {}",
+                server,
+                self.record,
+                code
+            );
+        }
+        verifier(&response)
+            .with_context(|| format!("DNS UPDATE response from {} isn't validly signed", server))?;
+        info!(
+            "Updated {} record {} on {} from {} to {} ({})",
+            record_type,
+            self.record,
+            server,
+            render(&current),
+            ip,
+            code
+        );
+        Ok(())
+    }
+
+    /// Returns the message deleting any existing records of the family of `ip` at the record's
+    /// name and then adding one for `ip`, before it's signed
+    fn update_message(&self, ip: IpAddr) -> Message {
+        let rdata = match ip {
+            IpAddr::V4(ip) => RData::A(A(ip)),
+            IpAddr::V6(ip) => RData::AAAA(AAAA(ip)),
+        };
+        let mut message = Message::new();
+        message
+            .set_id(rand::random())
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Update);
+        message.add_zone(Query::query(self.zone.clone(), RecordType::SOA));
+        // A class ANY record with no data deletes the whole RRset (RFC 2136 section 2.5.2)
+        let mut delete = Record::with(self.record.clone(), record_type(ip), 0);
+        delete.set_dns_class(DNSClass::ANY);
+        message.add_update(delete);
+        message.add_update(Record::from_rdata(self.record.clone(), self.ttl, rdata));
+        message
+    }
+
+    /// Asks the server for the current values of the record
+    async fn current(&self, server: SocketAddr, record_type: RecordType) -> Result<Vec<IpAddr>> {
+        let mut message = Message::new();
+        message
+            .set_id(rand::random())
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(false)
+            .add_query(Query::query(self.record.clone(), record_type));
+        let response = Message::from_vec(&self.exchange(server, &message).await?)
+            .context("Malformed DNS response")?;
+        match response.response_code() {
+            ResponseCode::NoError | ResponseCode::NXDomain => {}
+            code => bail!(
+                "DNS server {} failed to look up {} with {}",
+                server,
+                self.record,
+                code
+            ),
+        }
+        Ok(response
+            .answers()
+            .iter()
+            .filter(|answer| answer.record_type() == record_type)
+            .filter_map(|answer| answer.data().and_then(RData::ip_addr))
+            .collect())
+    }
+
+    /// Sends `message` to the server over UDP and returns the response with the same ID
+    async fn exchange(&self, server: SocketAddr, message: &Message) -> Result<Vec<u8>> {
+        let request = message.to_vec().context("Failed to encode DNS message")?;
+        let local = if server.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let conn = UdpSocket::bind(local)
+            .await
+            .context("Failed to bind socket for DNS update")?;
+        conn.connect(server)
+            .await
+            .with_context(|| format!("Failed to connect to DNS server {}", server))?;
+        conn.send(&request)
+            .await
+            .with_context(|| format!("Failed to send DNS message to {}", server))?;
+        let mut buf = vec![0u8; 4096];
+        let receive = async {
+            loop {
+                let size = conn.recv(&mut buf).await?;
+                let response = &buf[..size];
+                if response.len() >= 2
+                    && u16::from_be_bytes([response[0], response[1]]) == message.id()
+                {
+                    return Ok::<_, std::io::Error>(response.to_vec());
+                }
+                debug!("Ignoring DNS response from {} with the wrong ID", server);
+            }
+        };
+        time::timeout(self.timeout, receive)
+            .await
+            .with_context(|| {
+                format!(
+                    "Timed out after {}ms waiting for DNS server {}",
+                    self.timeout.as_millis(),
+                    server
+                )
+            })?
+            .with_context(|| format!("Failed to receive DNS response from {}", server))
+    }
+
+    /// Resolves the configured server, which may be a name, each time it's used
+    async fn resolve(&self) -> Result<SocketAddr> {
+        if let Ok(ip) = self.server.parse::<IpAddr>() {
+            return Ok(SocketAddr::new(ip, 53));
+        }
+        let endpoint = if self.server.contains(':') {
+            self.server.clone()
+        } else {
+            format!("{}:53", self.server)
+        };
+        net::lookup_host(endpoint)
+            .await
+            .with_context(|| format!("Failed to resolve DNS server {}", self.server))?
+            .next()
+            .with_context(|| format!("DNS server {} has no addresses", self.server))
+    }
+}
+
+/// Returns a signer for the TSIG key `name`, with its base64 `secret` as found in BIND's key
+/// files
+pub fn tsig_key(name: &str, secret: &str, algorithm: TsigAlgorithm) -> Result<TSigner> {
+    let key = STANDARD
+        .decode(secret.trim())
+        .context("TSIG key secret isn't valid base64")?;
+    let name = fqdn(name).with_context(|| format!("Invalid TSIG key name {}", name))?;
+    TSigner::new(key, algorithm, name, FUDGE).context("Failed to configure TSIG key")
+}
+
+/// Parses a TSIG algorithm name such as "hmac-sha256"
+pub fn parse_algorithm(name: &str) -> Result<TsigAlgorithm, String> {
+    match name.to_ascii_lowercase().as_str() {
+        "hmac-sha256" => Ok(TsigAlgorithm::HmacSha256),
+        "hmac-sha384" => Ok(TsigAlgorithm::HmacSha384),
+        "hmac-sha512" => Ok(TsigAlgorithm::HmacSha512),
+        _ => Err(format!(
+            "Unsupported TSIG algorithm '{}', expected hmac-sha256, hmac-sha384 or hmac-sha512",
+            name
+        )),
+    }
+}
+
+/// Parses `name` as a fully qualified domain name, whether or not it ends with a dot
+fn fqdn(name: &str) -> Result<Name> {
+    let mut name = Name::from_ascii(name)?;
+    name.set_fqdn(true);
+    Ok(name)
+}
+
+fn record_type(ip: IpAddr) -> RecordType {
+    if ip.is_ipv4() {
+        RecordType::A
+    } else {
+        RecordType::AAAA
+    }
+}
+
+/// Describes the previous values of the record for logging
+fn render(ips: &[IpAddr]) -> String {
+    match ips {
+        [] => "nothing".to_string(),
+        ips => ips
+            .iter()
+            .map(IpAddr::to_string)
+            .collect::<Vec<_>>()
+            .join(", "),
+    }
+}
+
+fn unix_time() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as u32)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn update_replaces_record_and_is_signed() {
+        let nsupdate = Nsupdate::new(
+            "127.0.0.1".to_string(),
+            "example.com",
+            "home.example.com.",
+            tsig_key(
+                "iplookup-key",
+                "c2VjcmV0IGtleSBmb3IgdGVzdGluZw==",
+                TsigAlgorithm::HmacSha256,
+            )
+            .unwrap(),
+            60,
+            Duration::from_secs(1),
+        )
+        .unwrap();
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let mut message = nsupdate.update_message(ip);
+        assert_eq!(message.op_code(), OpCode::Update);
+        assert_eq!(message.zones()[0].name(), &fqdn("example.com").unwrap());
+        let updates = message.updates();
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0].dns_class(), DNSClass::ANY);
+        assert_eq!(updates[0].record_type(), RecordType::A);
+        assert_eq!(updates[1].data().and_then(RData::ip_addr), Some(ip));
+        assert_eq!(updates[1].ttl(), 60);
+
+        message.finalize(&nsupdate.signer, unix_time()).unwrap();
+        let signed = message.to_vec().unwrap();
+        nsupdate
+            .signer
+            .verify_message_byte(None, &signed, true)
+            .unwrap();
+    }
+}
@@ -0,0 +1,745 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::cli::atomic;
+use anyhow::Result;
+use chrono::{Local, SecondsFormat, Utc};
+use iplookup::{
+    Allocation, Binding, Family, Filtering, FilteringBehavior, FilteringTest, Hairpin, Mapping,
+    MappingBehavior, MappingTest, NatBehavior, SymmetricCheck,
+};
+use serde::Serialize;
+use serde_json::Value;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+/// How a result is printed to stdout
+#[derive(Clone, Debug)]
+pub enum Format {
+    /// Only the public IP
+    Ip,
+    /// The public IP and port, as ip:port
+    IpPort,
+    /// A JSON `Report`
+    Json,
+    /// The same `Report` as YAML
+    Yaml,
+    /// A `--format` template
+    Template(Template),
+    /// Shell variable assignments, for `--env-output`
+    Env(EnvOutput),
+}
+
+impl Format {
+    /// Whether results are printed as serialized reports, which include details that are
+    /// otherwise printed to stderr
+    pub fn is_structured(&self) -> bool {
+        matches!(self, Format::Json | Format::Yaml)
+    }
+
+    /// Serializes `report` as YAML for `--yaml`, or otherwise as JSON
+    fn serialize<T: Serialize>(&self, report: &T) -> Result<String> {
+        Ok(match self {
+            Format::Yaml => render_yaml(&serde_json::to_value(report)?).join("\n"),
+            _ => serde_json::to_string(report)?,
+        })
+    }
+
+    /// Returns the line to print for a result from `server`. A `timestamp` from `--timestamp`
+    /// is included in JSON, and otherwise printed to stderr so that stdout only has the result.
+    pub fn render(
+        &self,
+        server: &str,
+        binding: &Binding,
+        timestamp: Option<&str>,
+    ) -> Result<String> {
+        if let (Some(timestamp), false) = (timestamp, self.is_structured()) {
+            eprintln!("Time: {}", timestamp);
+        }
+        Ok(match self {
+            Format::Ip => binding.addr.ip().to_string(),
+            Format::IpPort => binding.addr.to_string(),
+            Format::Json | Format::Yaml => {
+                let report = Report {
+                    timestamp: timestamp.map(str::to_string),
+                    ..Report::new(server, binding)
+                };
+                self.serialize(&report)?
+            }
+            Format::Template(template) => template.render(server, binding),
+            Format::Env(env) => env.render(binding.addr, "IP"),
+        })
+    }
+
+    /// Returns the lines to print for a `--both` result, from the servers which answered over
+    /// IPv4 and IPv6 if any. Templates are printed as-is since they can include `{family}`,
+    /// while other lines are prefixed with their family.
+    pub fn render_both(
+        &self,
+        v4: Option<&(String, Binding)>,
+        v6: Option<&(String, Binding)>,
+    ) -> Result<String> {
+        if self.is_structured() {
+            let report = BothReport {
+                v4: v4.map(|(server, binding)| Report::new(server, binding)),
+                v6: v6.map(|(server, binding)| Report::new(server, binding)),
+            };
+            return self.serialize(&report);
+        }
+        let mut lines = Vec::new();
+        for (label, result) in [("ipv4", v4), ("ipv6", v6)] {
+            if let Some((server, binding)) = result {
+                let line = self.render(server, binding, None)?;
+                lines.push(match self {
+                    Format::Template(_) => line,
+                    Format::Env(env) => env.render(binding.addr, &label.to_ascii_uppercase()),
+                    _ => format!("{}: {}", label, line),
+                });
+            }
+        }
+        Ok(lines.join("\n"))
+    }
+
+    /// Returns the line to print for a `--nat-type` result from `server`
+    pub fn render_nat(&self, server: &str, behavior: &NatBehavior) -> Result<String> {
+        Ok(match self {
+            Format::Ip | Format::IpPort | Format::Template(_) | Format::Env(_) => {
+                behavior.to_string()
+            }
+            Format::Json | Format::Yaml => self.serialize(&NatReport::new(server, behavior))?,
+        })
+    }
+
+    /// Returns the lines to print for a `--mapping-behavior` result from `server`
+    pub fn render_mapping(&self, server: &str, behavior: &MappingBehavior) -> Result<String> {
+        Ok(match self {
+            Format::Ip | Format::IpPort | Format::Template(_) | Format::Env(_) => {
+                behavior.to_string()
+            }
+            Format::Json | Format::Yaml => self.serialize(&MappingReport::new(server, behavior))?,
+        })
+    }
+
+    /// Returns the lines to print for a `--filtering-behavior` result from `server`
+    pub fn render_filtering(&self, server: &str, behavior: &FilteringBehavior) -> Result<String> {
+        Ok(match self {
+            Format::Ip | Format::IpPort | Format::Template(_) | Format::Env(_) => {
+                behavior.to_string()
+            }
+            Format::Json | Format::Yaml => {
+                self.serialize(&FilteringReport::new(server, behavior))?
+            }
+        })
+    }
+
+    /// Returns the lines to print for a `--symmetric-check` result
+    pub fn render_symmetric(&self, check: &SymmetricCheck) -> Result<String> {
+        Ok(match self {
+            Format::Ip | Format::IpPort | Format::Template(_) | Format::Env(_) => check.to_string(),
+            Format::Json | Format::Yaml => self.serialize(check)?,
+        })
+    }
+
+    /// Returns the line to print for a `--hairpin-test` result
+    pub fn render_hairpin(&self, hairpin: &Hairpin) -> Result<String> {
+        Ok(match self {
+            Format::Ip | Format::IpPort | Format::Template(_) | Format::Env(_) => {
+                hairpin.to_string()
+            }
+            Format::Json | Format::Yaml => self.serialize(hairpin)?,
+        })
+    }
+
+    /// Returns the lines to print for the addresses which `server` resolved to with
+    /// `--resolve-only`, prefixed by the server if `labeled`
+    pub fn render_resolved(
+        &self,
+        server: &str,
+        addrs: &[SocketAddr],
+        labeled: bool,
+    ) -> Result<String> {
+        Ok(match self {
+            Format::Json | Format::Yaml => self.serialize(&ResolvedReport {
+                server: server.to_string(),
+                addrs: addrs.to_vec(),
+            })?,
+            _ => addrs
+                .iter()
+                .map(|addr| {
+                    if labeled {
+                        format!("{}: {}", server, addr)
+                    } else {
+                        addr.to_string()
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        })
+    }
+
+    /// Returns the line to print for a `--turn` result from `server`
+    pub fn render_turn(&self, server: &str, allocation: &Allocation) -> Result<String> {
+        Ok(match self {
+            Format::Ip => allocation.relayed.ip().to_string(),
+            Format::IpPort | Format::Template(_) => allocation.relayed.to_string(),
+            Format::Env(env) => env.render(allocation.relayed, "IP"),
+            Format::Json | Format::Yaml => self.serialize(&TurnReport::new(server, allocation))?,
+        })
+    }
+}
+
+/// Returns the `--diff` line for a change from `previous` (if any IP was recorded) to `ip` at
+/// `time`, e.g. `192.0.2.1 -> 198.51.100.7 (changed at 2024-01-01T00:00:00Z)`
+pub fn render_diff(previous: Option<IpAddr>, ip: IpAddr, time: &str) -> String {
+    let previous = previous.map_or_else(|| "(none)".to_string(), |previous| previous.to_string());
+    format!("{} -> {} (changed at {})", previous, ip, time)
+}
+
+/// Which time zone `--timestamp` is in
+#[derive(Clone, Copy, Debug)]
+pub enum Clock {
+    Utc,
+    /// The system's local time zone, with its UTC offset
+    Local,
+}
+
+impl Clock {
+    /// Returns the current time in ISO 8601 format, to the second
+    pub fn now(self) -> String {
+        match self {
+            Clock::Utc => Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            Clock::Local => Local::now().to_rfc3339_opts(SecondsFormat::Secs, false),
+        }
+    }
+}
+
+/// A file which holds the latest result, as written by `--output-file`
+#[derive(Clone, Debug)]
+pub struct OutputFile {
+    pub path: PathBuf,
+    /// Whether to end the file with a newline
+    pub newline: bool,
+}
+
+impl OutputFile {
+    /// Atomically replaces the file's content with `line`, leaving it untouched on failure
+    pub fn write(&self, line: &str) -> Result<()> {
+        let mut content = line.to_string();
+        if self.newline {
+            content.push('\n');
+        }
+        atomic::write(&self.path, content.as_bytes())
+    }
+}
+
+/// A piece of a `--format` template
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Literal(String),
+    /// `{ip}`: The public IP
+    Ip,
+    /// `{port}`: The public port
+    Port,
+    /// `{server}`: The server endpoint as it was provided by the user
+    Server,
+    /// `{rtt}`: Round-trip time in milliseconds
+    Rtt,
+    /// `{family}`: ipv4 or ipv6
+    Family,
+}
+
+/// A `--format` template such as `{ip}:{port} via {server}`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template(Vec<Token>);
+
+impl Template {
+    /// Parses `template`, failing on unknown or unterminated tokens. Literal braces are
+    /// written as `{{` and `}}`.
+    pub fn parse(template: &str) -> Result<Template, String> {
+        let mut tokens = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("Unterminated token '{{{}'", name)),
+                        }
+                    }
+                    let token = match name.as_str() {
+                        "ip" => Token::Ip,
+                        "port" => Token::Port,
+                        "server" => Token::Server,
+                        "rtt" => Token::Rtt,
+                        "family" => Token::Family,
+                        _ => {
+                            return Err(format!(
+                                "Unknown token '{{{}}}', expected one of {{ip}}, {{port}}, \
+                                 {{server}}, {{rtt}} or {{family}}",
+                                name
+                            ))
+                        }
+                    };
+                    if !literal.is_empty() {
+                        tokens.push(Token::Literal(std::mem::take(&mut literal)));
+                    }
+                    tokens.push(token);
+                }
+                '}' => return Err("Unmatched '}', use '}}' for a literal brace".to_string()),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            tokens.push(Token::Literal(literal));
+        }
+        Ok(Template(tokens))
+    }
+
+    /// Returns the template with the tokens replaced by the result from `server`
+    fn render(&self, server: &str, binding: &Binding) -> String {
+        self.0
+            .iter()
+            .map(|token| match token {
+                Token::Literal(text) => text.clone(),
+                Token::Ip => binding.addr.ip().to_string(),
+                Token::Port => binding.addr.port().to_string(),
+                Token::Server => server.to_string(),
+                Token::Rtt => format!("{:.1}", binding.rtt.as_secs_f64() * 1000.0),
+                Token::Family => match Family::of(&binding.addr) {
+                    Family::V4 => "ipv4".to_string(),
+                    Family::V6 => "ipv6".to_string(),
+                },
+            })
+            .collect()
+    }
+}
+
+/// Shell variable assignments to `eval`, as printed by `--env-output`
+#[derive(Clone, Debug)]
+pub struct EnvOutput {
+    /// Prepended to each variable name, e.g. `IPLOOKUP_`
+    pub prefix: String,
+    /// Whether to include the port as well as the IP
+    pub port: bool,
+}
+
+impl EnvOutput {
+    /// Returns the assignment of the IP of `addr` to the variable `name` after the prefix, and
+    /// of its port to `PORT` or `<name>_PORT` if the port is wanted
+    fn render(&self, addr: SocketAddr, name: &str) -> String {
+        let mut line = format!(
+            "{}{}={}",
+            self.prefix,
+            name,
+            shell_quote(&addr.ip().to_string())
+        );
+        if self.port {
+            let port_name = match name {
+                "IP" => "PORT".to_string(),
+                name => format!("{}_PORT", name),
+            };
+            line.push_str(&format!("\n{}{}={}", self.prefix, port_name, addr.port()));
+        }
+        line
+    }
+
+    /// Parses a `--env-prefix` value, which must keep the variable names valid in any shell
+    pub fn parse_prefix(prefix: &str) -> Result<String, String> {
+        let valid = prefix
+            .chars()
+            .enumerate()
+            .all(|(i, c)| c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit()));
+        if valid {
+            Ok(prefix.to_string())
+        } else {
+            Err(format!(
+                "Invalid variable prefix '{}', expected letters, digits and underscores, not \
+                 starting with a digit",
+                prefix
+            ))
+        }
+    }
+}
+
+/// Returns the lines of `value` as a YAML block. Covers everything which can appear in the
+/// reports below, without anchors, multi-line strings or the other parts of YAML they don't need.
+fn render_yaml(value: &Value) -> Vec<String> {
+    match value {
+        Value::Object(map) if !map.is_empty() => map
+            .iter()
+            .flat_map(|(key, value)| nest(format!("{}:", yaml_scalar(key)), value, "  "))
+            .collect(),
+        Value::Array(items) if !items.is_empty() => items
+            .iter()
+            .flat_map(|item| nest("-".to_string(), item, "  "))
+            .collect(),
+        Value::Object(_) => vec!["{}".to_string()],
+        Value::Array(_) => vec!["[]".to_string()],
+        Value::String(s) => vec![yaml_scalar(s)],
+        scalar => vec![scalar.to_string()],
+    }
+}
+
+/// Returns the lines for `value` under a `key:` or `-` prefix: on the same line if it's a scalar
+/// or a list item's first line, or else indented by `indent` on the following lines
+fn nest(prefix: String, value: &Value, indent: &str) -> Vec<String> {
+    let lines = render_yaml(value);
+    let block = matches!(value, Value::Object(m) if !m.is_empty())
+        || matches!(value, Value::Array(a) if !a.is_empty());
+    if !block {
+        return vec![format!("{} {}", prefix, lines[0])];
+    }
+    if prefix == "-" && value.is_object() {
+        // Like "- ip: 192.0.2.1", with the rest of the object lined up under the first key
+        let mut lines = lines.into_iter();
+        let first = format!("- {}", lines.next().unwrap_or_default());
+        return std::iter::once(first)
+            .chain(lines.map(|line| format!("{}{}", indent, line)))
+            .collect();
+    }
+    std::iter::once(prefix)
+        .chain(lines.into_iter().map(|line| format!("{}{}", indent, line)))
+        .collect()
+}
+
+/// Returns `value` as a plain YAML scalar, or double-quoted if it contains any characters which
+/// could be special or would otherwise read as another type, like `true` or `10`
+fn yaml_scalar(value: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "._-:/+".contains(c);
+    let reserved = ["true", "false", "null", "yes", "no", "on", "off", "~"];
+    if value.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && value.chars().all(plain)
+        && !value.ends_with(':')
+        && !reserved.contains(&value.to_ascii_lowercase().as_str())
+        && value.parse::<f64>().is_err()
+    {
+        value.to_string()
+    } else {
+        // JSON strings are also valid YAML double-quoted scalars
+        Value::String(value.to_string()).to_string()
+    }
+}
+
+/// Returns `value` single-quoted for a POSIX shell, unless it's made only of characters which
+/// are never special
+fn shell_quote(value: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || ".:_-".contains(c);
+    if !value.is_empty() && value.chars().all(plain) {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
+
+/// The result of a lookup, as printed by `--json`
+#[derive(Debug, Serialize)]
+pub struct Report {
+    /// Public IP reported by the server
+    pub ip: IpAddr,
+    /// Public (reflexive) port reported by the server
+    pub port: u16,
+    pub family: Family,
+    /// The server endpoint as it was provided by the user
+    pub server: String,
+    /// The server's alternate address, if it advertised one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub other_address: Option<SocketAddr>,
+    /// The address which the server says it sent the response from, if it reported one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_origin: Option<SocketAddr>,
+    /// Round-trip time of the request which got a response, in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rtt_ms: Option<f64>,
+    /// When the lookup completed, if `--timestamp` was given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+}
+
+impl Report {
+    pub fn new(server: &str, binding: &Binding) -> Report {
+        Report {
+            ip: binding.addr.ip(),
+            port: binding.addr.port(),
+            family: Family::of(&binding.addr),
+            server: server.to_string(),
+            other_address: binding.other_address,
+            response_origin: binding.response_origin,
+            rtt_ms: Some(binding.rtt.as_secs_f64() * 1000.0),
+            timestamp: None,
+        }
+    }
+}
+
+/// The result of `--both`, as printed by `--json`
+#[derive(Debug, Serialize)]
+pub struct BothReport {
+    pub v4: Option<Report>,
+    pub v6: Option<Report>,
+}
+
+/// The result of `--nat-type`, as printed by `--json`
+#[derive(Debug, Serialize)]
+pub struct NatReport {
+    #[serde(flatten)]
+    pub report: Report,
+    pub mapping: Mapping,
+    pub filtering: Filtering,
+}
+
+impl NatReport {
+    pub fn new(server: &str, behavior: &NatBehavior) -> NatReport {
+        NatReport {
+            report: Report {
+                ip: behavior.public.ip(),
+                port: behavior.public.port(),
+                family: Family::of(&behavior.public),
+                server: server.to_string(),
+                other_address: Some(behavior.other_address),
+                response_origin: None,
+                rtt_ms: None,
+                timestamp: None,
+            },
+            mapping: behavior.mapping,
+            filtering: behavior.filtering,
+        }
+    }
+}
+
+/// The result of `--mapping-behavior`, as printed by `--json`
+#[derive(Debug, Serialize)]
+pub struct MappingReport {
+    #[serde(flatten)]
+    pub report: Report,
+    pub mapping: Mapping,
+    pub tests: Vec<MappingTest>,
+}
+
+impl MappingReport {
+    pub fn new(server: &str, behavior: &MappingBehavior) -> MappingReport {
+        MappingReport {
+            report: Report {
+                ip: behavior.public.ip(),
+                port: behavior.public.port(),
+                family: Family::of(&behavior.public),
+                server: server.to_string(),
+                other_address: Some(behavior.other_address),
+                response_origin: None,
+                rtt_ms: None,
+                timestamp: None,
+            },
+            mapping: behavior.mapping,
+            tests: behavior.tests.clone(),
+        }
+    }
+}
+
+/// The result of `--filtering-behavior`, as printed by `--json`
+#[derive(Debug, Serialize)]
+pub struct FilteringReport {
+    #[serde(flatten)]
+    pub report: Report,
+    pub filtering: Filtering,
+    pub tests: Vec<FilteringTest>,
+}
+
+impl FilteringReport {
+    pub fn new(server: &str, behavior: &FilteringBehavior) -> FilteringReport {
+        FilteringReport {
+            report: Report {
+                ip: behavior.public.ip(),
+                port: behavior.public.port(),
+                family: Family::of(&behavior.public),
+                server: server.to_string(),
+                other_address: Some(behavior.other_address),
+                response_origin: None,
+                rtt_ms: None,
+                timestamp: None,
+            },
+            filtering: behavior.filtering,
+            tests: behavior.tests.clone(),
+        }
+    }
+}
+
+/// The result of `--resolve-only`, as printed by `--json`
+#[derive(Debug, Serialize)]
+pub struct ResolvedReport {
+    /// The server endpoint as it was provided by the user
+    pub server: String,
+    /// The addresses it resolved to, in the order they would be tried
+    pub addrs: Vec<SocketAddr>,
+}
+
+/// The result of `--turn`, as printed by `--json`
+#[derive(Debug, Serialize)]
+pub struct TurnReport {
+    /// Relayed address allocated by the server
+    pub relayed_address: SocketAddr,
+    /// Public address reported by the server, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mapped_address: Option<SocketAddr>,
+    /// How long the allocation would have lasted, in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lifetime_secs: Option<u64>,
+    /// The server endpoint as it was provided by the user
+    pub server: String,
+}
+
+impl TurnReport {
+    pub fn new(server: &str, allocation: &Allocation) -> TurnReport {
+        TurnReport {
+            relayed_address: allocation.relayed,
+            mapped_address: allocation.mapped,
+            lifetime_secs: allocation.lifetime.map(|lifetime| lifetime.as_secs()),
+            server: server.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn renders_template() {
+        let binding = Binding {
+            addr: "192.0.2.1:4000".parse().unwrap(),
+            other_address: None,
+            rtt: Duration::from_micros(23460),
+            response_origin: None,
+            attributes: Vec::new(),
+        };
+        let template =
+            Template::parse("{ip}:{port} via {server} ({family}, {rtt}ms) {{x}}").unwrap();
+        assert_eq!(
+            template.render("stun.example.com", &binding),
+            "192.0.2.1:4000 via stun.example.com (ipv4, 23.5ms) {x}"
+        );
+    }
+
+    #[test]
+    fn renders_both_families() {
+        let binding = Binding {
+            addr: "192.0.2.1:4000".parse().unwrap(),
+            other_address: None,
+            rtt: Duration::ZERO,
+            response_origin: None,
+            attributes: Vec::new(),
+        };
+        let v4 = ("stun.example.com".to_string(), binding);
+        assert_eq!(
+            Format::IpPort.render_both(Some(&v4), None).unwrap(),
+            "ipv4: 192.0.2.1:4000"
+        );
+        let json = Format::Json.render_both(Some(&v4), None).unwrap();
+        assert!(json.starts_with(r#"{"v4":{"ip":"192.0.2.1","#), "{}", json);
+        assert!(json.ends_with(r#","v6":null}"#), "{}", json);
+        let yaml = Format::Yaml.render_both(Some(&v4), None).unwrap();
+        assert!(
+            yaml.starts_with("v4:\n  ip: 192.0.2.1\n  port: 4000\n"),
+            "{}",
+            yaml
+        );
+        assert!(yaml.ends_with("\nv6: null"), "{}", yaml);
+    }
+
+    #[test]
+    fn renders_yaml_blocks() {
+        let value = serde_json::json!({
+            "server": "::1",
+            "tests": [{"server": "192.0.2.1:3478", "public": "198.51.100.7:4000"}],
+            "addrs": [],
+            "reason": "no: really",
+            "code": "10",
+            "symmetric": false,
+        });
+        assert_eq!(
+            render_yaml(&value).join("\n"),
+            "server: \"::1\"\n\
+             tests:\n  \
+             - server: 192.0.2.1:3478\n    \
+             public: 198.51.100.7:4000\n\
+             addrs: []\n\
+             reason: \"no: really\"\n\
+             code: \"10\"\n\
+             symmetric: false"
+        );
+    }
+
+    #[test]
+    fn renders_env_assignments() {
+        let binding = Binding {
+            addr: "[2001:db8::1]:4000".parse().unwrap(),
+            other_address: None,
+            rtt: Duration::ZERO,
+            response_origin: None,
+            attributes: Vec::new(),
+        };
+        let env = EnvOutput {
+            prefix: "IPLOOKUP_".to_string(),
+            port: true,
+        };
+        assert_eq!(
+            Format::Env(env)
+                .render("stun.example.com", &binding, None)
+                .unwrap(),
+            "IPLOOKUP_IP=2001:db8::1\nIPLOOKUP_PORT=4000"
+        );
+        assert_eq!(shell_quote("a b'c"), "'a b'\\''c'");
+        assert!(EnvOutput::parse_prefix("MY_IP_").is_ok());
+        assert!(EnvOutput::parse_prefix("1X").is_err());
+        assert!(EnvOutput::parse_prefix("A;B").is_err());
+    }
+
+    #[test]
+    fn renders_diff() {
+        let ip = "198.51.100.7".parse().unwrap();
+        assert_eq!(
+            render_diff(
+                Some("192.0.2.1".parse().unwrap()),
+                ip,
+                "2024-01-01T00:00:00Z"
+            ),
+            "192.0.2.1 -> 198.51.100.7 (changed at 2024-01-01T00:00:00Z)"
+        );
+        assert_eq!(
+            render_diff(None, ip, "2024-01-01T00:00:00Z"),
+            "(none) -> 198.51.100.7 (changed at 2024-01-01T00:00:00Z)"
+        );
+    }
+
+    #[test]
+    fn rejects_bad_templates() {
+        assert!(Template::parse("{address}").is_err());
+        assert!(Template::parse("{ip").is_err());
+        assert!(Template::parse("ip}").is_err());
+    }
+}
@@ -0,0 +1,180 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{Context, Result};
+use iplookup::{Binding, Family, Health, LookupError, LookupOptions, Transport};
+use log::{debug, warn};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+use tokio::net::UdpSocket;
+
+/// How the servers are queried, shared between one-shot and watch modes
+pub struct Query {
+    pub endpoints: Vec<String>,
+    pub family: Option<Family>,
+    /// Number of servers which must agree on the result, if in consensus mode
+    pub quorum: Option<usize>,
+    pub opts: LookupOptions,
+    /// HTTP echo service to ask for the public IP if none of the STUN servers answer
+    pub http_fallback: Option<String>,
+    /// HTTP echo service to compare the STUN result against
+    pub verify_http: Option<String>,
+    /// Query several servers one at a time in order, instead of racing them
+    pub in_turn: bool,
+    /// Socket which is reused across queries to a single UDP server
+    conn: Option<UdpSocket>,
+    /// Which of several servers have been failing, so that they're skipped for a while
+    health: Health,
+}
+
+impl Query {
+    pub fn new(
+        endpoints: Vec<String>,
+        family: Option<Family>,
+        quorum: Option<usize>,
+        opts: LookupOptions,
+    ) -> Query {
+        Query {
+            endpoints,
+            family,
+            quorum,
+            opts,
+            http_fallback: None,
+            verify_http: None,
+            in_turn: false,
+            conn: None,
+            health: Health::default(),
+        }
+    }
+
+    /// Returns the local address of the socket which is reused across queries, if any
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.conn.as_ref().and_then(|conn| conn.local_addr().ok())
+    }
+
+    /// Queries the server(s), returning the endpoint that answered and the public address
+    /// that it reported. If they all fail, falls back to the HTTP echo service if one is
+    /// configured, which reports port 0 since it doesn't reveal the NAT mapping.
+    pub async fn run(&mut self) -> Result<(String, Binding)> {
+        let result = match self.verify_http.clone() {
+            Some(url) => {
+                let (family, opts) = (self.family, self.opts.clone());
+                let (result, http) =
+                    tokio::join!(self.run_stun(), iplookup::lookup_http(&url, family, &opts));
+                if let Ok((_, binding)) = &result {
+                    verify(binding, &url, http);
+                }
+                result
+            }
+            None => self.run_stun().await,
+        };
+        let err = match result {
+            Ok(result) => return Ok(result),
+            Err(e) => e,
+        };
+        let url = match &self.http_fallback {
+            Some(url) => url,
+            None => return Err(err),
+        };
+        warn!("STUN lookup failed, falling back to HTTP: {:#}", err);
+        let start = Instant::now();
+        let ip = iplookup::lookup_http(url, self.family, &self.opts).await?;
+        warn!("Public IP {} is from {} over HTTP, not STUN", ip, url);
+        let binding = Binding {
+            addr: SocketAddr::new(ip, 0),
+            other_address: None,
+            rtt: start.elapsed(),
+            response_origin: None,
+            attributes: Vec::new(),
+        };
+        Ok((url.clone(), binding))
+    }
+
+    async fn run_stun(&mut self) -> Result<(String, Binding)> {
+        if let Some(quorum) = self.quorum {
+            return Ok(iplookup::lookup_consensus(
+                &self.endpoints,
+                self.family,
+                quorum,
+                &self.opts,
+            )
+            .await?);
+        }
+        if self.in_turn && self.endpoints.len() > 1 {
+            return Ok(iplookup::lookup_in_turn(&self.endpoints, self.family, &self.opts).await?);
+        }
+        if self.endpoints.len() != 1
+            || iplookup::parse_endpoint(&self.endpoints[0], self.opts.transport).0 != Transport::Udp
+        {
+            return Ok(iplookup::lookup_first_tracked(
+                &self.endpoints,
+                self.family,
+                &self.opts,
+                &mut self.health,
+            )
+            .await?);
+        }
+
+        // Single UDP server: Resolve it every time in case its address changes, but stick to
+        // the family of the socket if we've already bound one
+        let endpoint = &self.endpoints[0];
+        let family = match &self.conn {
+            Some(conn) => Some(Family::of(&conn.local_addr()?)),
+            None => self.family,
+        };
+        let addrs = iplookup::resolve_endpoint_all(endpoint, family, &self.opts).await?;
+        let conn = match &self.conn {
+            Some(conn) => conn,
+            None => {
+                // Keep whichever socket got the first response, IPv6 or IPv4
+                let (binding, conn) = iplookup::lookup_addrs(&addrs, &self.opts).await?;
+                self.conn = Some(conn);
+                return Ok((endpoint.clone(), binding));
+            }
+        };
+        let opts = &self.opts;
+        let binding = iplookup::try_each(&addrs, |dest| async move {
+            // The socket from lookup_addrs is connected to whichever address answered first
+            if conn.peer_addr().ok() != Some(dest) {
+                conn.connect(dest)
+                    .await
+                    .with_context(|| format!("Failed to connect local socket to {}", dest))?;
+            }
+            iplookup::lookup(conn, &dest, opts)
+                .await
+                .map_err(anyhow::Error::from)
+        })
+        .await?;
+        Ok((endpoint.clone(), binding))
+    }
+}
+
+/// Warns if the public IP seen by the HTTP echo service at `url` differs from the STUN result
+fn verify(binding: &Binding, url: &str, http: Result<IpAddr, LookupError>) {
+    match http {
+        Ok(ip) if ip != binding.addr.ip() => warn!(
+            "STUN reported public IP {} but {} saw {}, possibly due to split tunneling, \
+             a proxy or a captive portal",
+            binding.addr.ip(),
+            url,
+            ip
+        ),
+        Ok(ip) => debug!("{} agrees on public IP {}", url, ip),
+        Err(e) => warn!("Failed to verify public IP with {}: {:#}", url, e),
+    }
+}
@@ -0,0 +1,304 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use log::{debug, info};
+use reqwest::Client;
+use ring::{digest, hmac};
+use std::env;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// Route 53 is a global service whose API is only served from this host and region
+const API_HOST: &str = "route53.amazonaws.com";
+const REGION: &str = "us-east-1";
+const SERVICE: &str = "route53";
+
+/// Keeps a Route 53 record pointed at the public IP with `UPSERT` changes, as configured by
+/// `--route53-*`
+pub struct Route53 {
+    client: Client,
+    /// ID of the hosted zone which holds the record, e.g. `Z0123456789ABCDEFGHIJ`
+    zone: String,
+    /// Full name of the record, e.g. `home.example.com`
+    record: String,
+    /// TTL to give the record, in seconds
+    ttl: u32,
+    credentials: Credentials,
+}
+
+/// AWS credentials, as found in the environment
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    /// Only set for temporary credentials
+    session_token: Option<String>,
+}
+
+impl Credentials {
+    /// Reads the standard `$AWS_ACCESS_KEY_ID`, `$AWS_SECRET_ACCESS_KEY` and, if present,
+    /// `$AWS_SESSION_TOKEN`
+    fn from_env() -> Result<Credentials> {
+        let var = |name| {
+            env::var(name)
+                .ok()
+                .filter(|value: &String| !value.is_empty())
+        };
+        match (var("AWS_ACCESS_KEY_ID"), var("AWS_SECRET_ACCESS_KEY")) {
+            (Some(access_key_id), Some(secret_access_key)) => Ok(Credentials {
+                access_key_id,
+                secret_access_key,
+                session_token: var("AWS_SESSION_TOKEN"),
+            }),
+            _ => bail!(
+                "--route53-zone requires AWS credentials in $AWS_ACCESS_KEY_ID and \
+                 $AWS_SECRET_ACCESS_KEY"
+            ),
+        }
+    }
+}
+
+impl Route53 {
+    pub fn new(zone: String, record: String, ttl: u32) -> Result<Route53> {
+        let credentials = Credentials::from_env()?;
+        let client = Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .context("Failed to configure HTTP client")?;
+        // Accept the zone ID as the API prints it, e.g. /hostedzone/Z0123
+        let zone = zone.trim_start_matches("/hostedzone/").to_string();
+        Ok(Route53 {
+            client,
+            zone,
+            record,
+            ttl,
+            credentials,
+        })
+    }
+
+    /// Creates or replaces the A or AAAA record (matching the family of `ip`) with `ip`
+    pub async fn update(&self, ip: IpAddr) -> Result<()> {
+        let record_type = if ip.is_ipv4() { "A" } else { "AAAA" };
+        let body = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+             <ChangeResourceRecordSetsRequest xmlns=\"https://route53.amazonaws.com/doc/2013-04-01/\">\
+             <ChangeBatch><Comment>Updated by iplookup</Comment><Changes><Change>\
+             <Action>UPSERT</Action><ResourceRecordSet><Name>{}</Name><Type>{}</Type>\
+             <TTL>{}</TTL><ResourceRecords><ResourceRecord><Value>{}</Value>\
+             </ResourceRecord></ResourceRecords></ResourceRecordSet></Change></Changes>\
+             </ChangeBatch></ChangeResourceRecordSetsRequest>",
+            escape(&self.record),
+            record_type,
+            self.ttl,
+            ip
+        );
+        let path = format!("/2013-04-01/hostedzone/{}/rrset/", self.zone);
+        let mut headers = vec![
+            ("content-type", "text/xml".to_string()),
+            ("host", API_HOST.to_string()),
+            (
+                "x-amz-date",
+                Utc::now().format("%Y%m%dT%H%M%SZ").to_string(),
+            ),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let authorization = sign(
+            &self.credentials,
+            "POST",
+            &path,
+            "",
+            &headers,
+            body.as_bytes(),
+            REGION,
+            SERVICE,
+        )?;
+
+        let mut request = self
+            .client
+            .post(format!("https://{}{}", API_HOST, path))
+            .header("authorization", authorization)
+            .body(body);
+        for (name, value) in &headers {
+            if *name != "host" {
+                request = request.header(*name, value);
+            }
+        }
+        let response = request
+            .send()
+            .await
+            .context("Route 53 API request failed")?;
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .context("Failed to read Route 53 API response")?;
+        debug!("Route 53 API response ({}): {}", status, body);
+        if !status.is_success() {
+            match (element(&body, "Code"), element(&body, "Message")) {
+                (Some(code), Some(message)) => {
+                    bail!("Route 53 API error ({}): {}: {}", status, code, message)
+                }
+                _ => bail!("Route 53 API error ({}): {}", status, body),
+            }
+        }
+        info!(
+            "Updated Route 53 {} record {} in zone {} to {} (change {}, {})",
+            record_type,
+            self.record,
+            self.zone,
+            ip,
+            element(&body, "Id").unwrap_or("with no ID"),
+            element(&body, "Status").unwrap_or("unknown status")
+        );
+        Ok(())
+    }
+}
+
+/// Returns the `Authorization` header signing a request with AWS Signature Version 4. `headers`
+/// are the lowercase names and values of every header to sign, including `host` and
+/// `x-amz-date`, sorted by name.
+#[allow(clippy::too_many_arguments)]
+fn sign(
+    credentials: &Credentials,
+    method: &str,
+    path: &str,
+    query: &str,
+    headers: &[(&str, String)],
+    payload: &[u8],
+    region: &str,
+    service: &str,
+) -> Result<String> {
+    let timestamp = headers
+        .iter()
+        .find(|(name, _)| *name == "x-amz-date")
+        .map(|(_, value)| value.as_str())
+        .context("Signed requests need an x-amz-date header")?;
+    // The scope's date is the YYYYMMDD prefix of the YYYYMMDDTHHMMSSZ timestamp
+    let date = timestamp
+        .get(..8)
+        .with_context(|| format!("Malformed x-amz-date {}", timestamp))?;
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        path,
+        query,
+        canonical_headers,
+        signed_headers,
+        hex(digest::digest(&digest::SHA256, payload).as_ref())
+    );
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        timestamp,
+        scope,
+        hex(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref())
+    );
+    let key = signing_key(&credentials.secret_access_key, date, region, service);
+    let signature = hmac::sign(&key, string_to_sign.as_bytes());
+    Ok(format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id,
+        scope,
+        signed_headers,
+        hex(signature.as_ref())
+    ))
+}
+
+/// Derives the key for signing requests to `service` in `region` on `date` (as YYYYMMDD)
+fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> hmac::Key {
+    let key = format!("AWS4{}", secret);
+    let key = [date, region, service, "aws4_request"]
+        .iter()
+        .fold(key.into_bytes(), |key, part| {
+            let key = hmac::Key::new(hmac::HMAC_SHA256, &key);
+            hmac::sign(&key, part.as_bytes()).as_ref().to_vec()
+        });
+    hmac::Key::new(hmac::HMAC_SHA256, &key)
+}
+
+/// Returns the text of the first `<tag>` element in `xml`, which is enough for the flat
+/// responses of the Route 53 API
+fn element<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", tag))?;
+    Some(&xml[start..end])
+}
+
+/// Escapes `text` for use in an XML element
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Formats `bytes` as lowercase hex without separators
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The example request from the AWS Signature Version 4 documentation
+    #[test]
+    fn signs_documented_example() {
+        let credentials = Credentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+        };
+        let headers = [
+            (
+                "content-type",
+                "application/x-www-form-urlencoded; charset=utf-8".to_string(),
+            ),
+            ("host", "iam.amazonaws.com".to_string()),
+            ("x-amz-date", "20150830T123600Z".to_string()),
+        ];
+        let authorization = sign(
+            &credentials,
+            "GET",
+            "/",
+            "Action=ListUsers&Version=2010-05-08",
+            &headers,
+            b"",
+            "us-east-1",
+            "iam",
+        )
+        .unwrap();
+        assert_eq!(
+            authorization,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/iam/aws4_request, \
+             SignedHeaders=content-type;host;x-amz-date, \
+             Signature=5d672d79c15b13162d9279b0855cfba6789a8edb4c82c400e06b5924a6f2b5d7"
+        );
+    }
+}
@@ -0,0 +1,88 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::io::{self, IsTerminal, Read};
+use std::path::Path;
+
+/// Reads the list of servers in `path`, as used by `--server-file`
+pub fn load(path: &Path) -> Result<Vec<String>> {
+    let content = read(path).with_context(|| format!("Failed to read server file {:?}", path))?;
+    Ok(parse(&content))
+}
+
+/// Reads the list of servers piped into stdin, for servers given as `-`
+pub fn load_stdin() -> Result<Vec<String>> {
+    Ok(parse(&read_stdin()?))
+}
+
+/// Returns the content of `path`, or of stdin if `path` is `-`
+pub fn read(path: &Path) -> Result<String> {
+    if path.as_os_str() == "-" {
+        read_stdin()
+    } else {
+        Ok(fs::read_to_string(path)?)
+    }
+}
+
+/// Returns everything piped into stdin. Fails instead of waiting for input if stdin is a
+/// terminal, since that's more likely to be a mistake than a list of servers being typed in.
+fn read_stdin() -> Result<String> {
+    let mut stdin = io::stdin();
+    if stdin.is_terminal() {
+        bail!("stdin is a terminal, pipe the list of servers into iplookup to read them from '-'");
+    }
+    let mut content = String::new();
+    stdin
+        .read_to_string(&mut content)
+        .context("Failed to read servers from stdin")?;
+    Ok(content)
+}
+
+/// Returns one server per line of `content`, skipping blank lines and `#` comments
+fn parse(content: &str) -> Vec<String> {
+    entries(content)
+        .map(|(_line, server)| server.to_string())
+        .collect()
+}
+
+/// Returns each server in `content` along with its 1-based line number, skipping blank lines
+/// and `#` comments
+pub fn entries(content: &str) -> impl Iterator<Item = (usize, &str)> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| match line.find('#') {
+            Some(end) => (idx + 1, &line[..end]),
+            None => (idx + 1, line),
+        })
+        .map(|(line, server)| (line, server.trim()))
+        .filter(|(_line, server)| !server.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_blanks_and_comments() {
+        let content = "# rotation\nstun.example.com:3478\n\n  [::1]:3478  # local\n   \n#x\n";
+        assert_eq!(parse(content), vec!["stun.example.com:3478", "[::1]:3478"]);
+    }
+}
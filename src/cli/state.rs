@@ -0,0 +1,86 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::cli::atomic;
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The last successful result, as persisted by `--state-file`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct State {
+    /// The last public IP
+    pub ip: IpAddr,
+    /// When the IP was last seen, in seconds since the UNIX epoch
+    pub timestamp: u64,
+    /// Index of the server to start from in the next `--round-robin` run
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub next_server: usize,
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+impl State {
+    /// Returns a new state for `ip`, as seen now
+    pub fn now(ip: IpAddr) -> State {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        State {
+            ip,
+            timestamp,
+            next_server: 0,
+        }
+    }
+
+    /// Loads the state from `path`. If the file is missing or malformed, returns `None` so
+    /// that the caller can start fresh.
+    pub fn load(path: &Path) -> Option<State> {
+        let content = match fs::read(path) {
+            Ok(content) => content,
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    warn!("Ignoring unreadable state file {:?}: {}", path, e);
+                }
+                return None;
+            }
+        };
+        match serde_json::from_slice(&content) {
+            Ok(state) => Some(state),
+            Err(e) => {
+                warn!("Ignoring malformed state file {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    /// Atomically writes the state to `path`
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut content = serde_json::to_vec(self).context("Failed to serialize state")?;
+        content.push(b'\n');
+        atomic::write(path, &content)
+    }
+}
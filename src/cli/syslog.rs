@@ -0,0 +1,78 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{anyhow, Result};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::process;
+use syslog::{BasicLogger, Facility, Formatter3164};
+
+/// Sends log messages to the local syslog daemon, keeping the filtering of an env_logger
+pub struct Syslog {
+    filter: env_logger::Logger,
+    inner: BasicLogger,
+}
+
+impl Syslog {
+    /// Connects to syslog over its Unix socket, logging with `facility` whatever `filter`
+    /// would have logged to stderr
+    pub fn connect(facility: Facility, filter: env_logger::Logger) -> Result<Syslog> {
+        let formatter = Formatter3164 {
+            facility,
+            hostname: None,
+            process: env!("CARGO_PKG_NAME").to_string(),
+            pid: process::id(),
+        };
+        let logger =
+            syslog::unix(formatter).map_err(|e| anyhow!("Failed to connect to syslog: {}", e))?;
+        Ok(Syslog {
+            filter,
+            inner: BasicLogger::new(logger),
+        })
+    }
+
+    /// The most detailed level which is logged
+    pub fn level(&self) -> LevelFilter {
+        self.filter.filter()
+    }
+}
+
+impl Log for Syslog {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.filter.matches(record) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Parses a facility name such as "daemon" or "local0"
+pub fn parse_facility(name: &str) -> Result<Facility, String> {
+    name.parse().map_err(|()| {
+        format!(
+            "Unknown syslog facility '{}', expected e.g. user, daemon or local0-local7",
+            name
+        )
+    })
+}
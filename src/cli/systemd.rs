@@ -0,0 +1,94 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use log::{debug, warn};
+use std::env;
+use std::io;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+/// Sends service status notifications to systemd, for units with `Type=notify` and optionally
+/// `WatchdogSec=`. See sd_notify(3).
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+}
+
+impl Notifier {
+    /// Returns a notifier for the socket in `$NOTIFY_SOCKET`, or `None` if we aren't running
+    /// under systemd with notifications enabled
+    pub fn from_env() -> Option<Notifier> {
+        let path = env::var_os("NOTIFY_SOCKET")?;
+        match Notifier::connect(&path.to_string_lossy()) {
+            Ok(notifier) => {
+                debug!("Sending systemd notifications to {:?}", path);
+                Some(notifier)
+            }
+            Err(e) => {
+                warn!("Ignoring unusable NOTIFY_SOCKET {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    fn connect(path: &str) -> io::Result<Notifier> {
+        let addr = match path.strip_prefix('@') {
+            // Names starting with '@' are in the abstract namespace
+            Some(name) => abstract_addr(name)?,
+            None => SocketAddr::from_pathname(path)?,
+        };
+        Ok(Notifier {
+            socket: UnixDatagram::unbound()?,
+            addr,
+        })
+    }
+
+    /// Tells systemd that startup has finished
+    pub fn ready(&self) {
+        self.notify("READY=1");
+    }
+
+    /// Resets the watchdog timer, so that systemd doesn't consider the service stuck
+    pub fn watchdog(&self) {
+        self.notify("WATCHDOG=1");
+    }
+
+    /// Tells systemd that we're shutting down
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    fn notify(&self, state: &str) {
+        if let Err(e) = self.socket.send_to_addr(state.as_bytes(), &self.addr) {
+            warn!("Failed to send {} to systemd: {}", state, e);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn abstract_addr(name: &str) -> io::Result<SocketAddr> {
+    use std::os::linux::net::SocketAddrExt;
+    SocketAddr::from_abstract_name(name)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn abstract_addr(_name: &str) -> io::Result<SocketAddr> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "abstract sockets are only supported on Linux",
+    ))
+}
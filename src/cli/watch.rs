@@ -0,0 +1,277 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::cli::cloudflare::Cloudflare;
+use crate::cli::duckdns::DuckDns;
+use crate::cli::hook;
+use crate::cli::hosts::HostsFile;
+use crate::cli::metrics::Metrics;
+#[cfg(feature = "mqtt")]
+use crate::cli::mqtt::Mqtt;
+use crate::cli::noip::NoIp;
+#[cfg(feature = "notify")]
+use crate::cli::notify;
+use crate::cli::nsupdate::Nsupdate;
+use crate::cli::output::{self, Clock, Format, OutputFile};
+use crate::cli::query::Query;
+use crate::cli::route53::Route53;
+use crate::cli::state::State;
+use crate::cli::systemd::Notifier;
+use crate::cli::webhook::Webhook;
+use anyhow::{Context, Result};
+use iplookup::Binding;
+use log::{debug, info, warn};
+use std::future::Future;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{self, MissedTickBehavior};
+
+/// Options for watch mode
+pub struct Watch {
+    /// Time between checks
+    pub interval: Duration,
+    /// How to print the result when it changes
+    pub format: Format,
+    /// Print how the IP changed instead of the result
+    pub diff: bool,
+    /// With `diff`, also print when the IP is unchanged
+    pub verbose: bool,
+    /// Shell command to run when the IP changes
+    pub on_change: Option<String>,
+    /// Where to persist the last result, so that changes are detected across restarts
+    pub state_file: Option<PathBuf>,
+    /// Where to write the result whenever it changes
+    pub output_file: Option<OutputFile>,
+    /// DNS record to update whenever the IP changes
+    pub cloudflare: Option<Cloudflare>,
+    /// Route 53 record to update whenever the IP changes
+    pub route53: Option<Route53>,
+    /// DNS server to send signed updates to whenever the IP changes
+    pub nsupdate: Option<Nsupdate>,
+    /// DuckDNS domain to update whenever the IP changes
+    pub duckdns: Option<DuckDns>,
+    /// No-IP hostname to update whenever the IP changes
+    pub noip: Option<NoIp>,
+    /// MQTT topic to publish the IP to on startup and whenever it changes
+    #[cfg(feature = "mqtt")]
+    pub mqtt: Option<Mqtt>,
+    /// Show a desktop notification whenever the IP changes
+    #[cfg(feature = "notify")]
+    pub notify: bool,
+    /// Hosts file entry to update whenever the IP changes
+    pub hosts: Option<HostsFile>,
+    /// HTTP request to send whenever the IP changes
+    pub webhook: Option<Webhook>,
+    /// Where to write metrics after each check
+    pub metrics_file: Option<PathBuf>,
+    /// Print the round-trip time of each query
+    pub timing: bool,
+    /// Print which server answered each query that found a new IP
+    pub show_server: bool,
+    /// Time zone to timestamp each change with, if any
+    pub timestamp: Option<Clock>,
+}
+
+impl Watch {
+    /// Runs `query` every `interval`, acting on the result whenever the public IP changes.
+    /// Failed lookups are logged and then retried on the next poll. If `$NOTIFY_SOCKET` is set,
+    /// systemd is told that we're ready after the first successful lookup, and the watchdog is
+    /// reset after each one. Returns once SIGINT or SIGTERM is received, though not in the middle
+    /// of acting on a result, so that files aren't left half-written.
+    pub async fn run(&self, query: &mut Query) -> Result<()> {
+        let shutdown = shutdown_signal()?;
+        tokio::pin!(shutdown);
+        let mut ticker = time::interval(self.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last_ip: Option<IpAddr> = self
+            .state_file
+            .as_deref()
+            .and_then(State::load)
+            .map(|state| state.ip);
+        let mut metrics = self.metrics_file.clone().map(Metrics::new);
+        let notifier = Notifier::from_env();
+        let mut ready = false;
+        #[cfg(feature = "mqtt")]
+        let mut published = false;
+        if let Some(ip) = last_ip {
+            debug!("Loaded previous public IP from state file: {}", ip);
+        }
+        loop {
+            let result = tokio::select! {
+                result = async {
+                    ticker.tick().await;
+                    query.run().await
+                } => result,
+                signal = &mut shutdown => {
+                    info!("Received {}, exiting", signal);
+                    if let Some(notifier) = &notifier {
+                        notifier.stopping();
+                    }
+                    return Ok(());
+                }
+            };
+            if let Some(metrics) = &mut metrics {
+                if let Err(e) = metrics.record(result.as_ref().ok().map(|(_, binding)| binding)) {
+                    warn!("Failed to update metrics file: {:#}", e);
+                }
+            }
+            match result {
+                Ok((server, binding)) => {
+                    let ip = binding.addr.ip();
+                    if let Some(notifier) = &notifier {
+                        if !ready {
+                            notifier.ready();
+                            ready = true;
+                        }
+                        notifier.watchdog();
+                    }
+                    if self.timing {
+                        eprintln!("RTT: {:.1}ms", binding.rtt.as_secs_f64() * 1000.0);
+                    }
+                    if let Some(path) = &self.state_file {
+                        if let Err(e) = State::now(ip).save(path) {
+                            warn!("Failed to update state file: {:#}", e);
+                        }
+                    }
+                    if last_ip != Some(ip) {
+                        self.changed(&server, &binding, last_ip).await?;
+                        last_ip = Some(ip);
+                    } else {
+                        debug!("Public IP unchanged: {}", ip);
+                        if self.diff && self.verbose {
+                            println!("nochg {}", ip);
+                        }
+                        // The IP from the state file was never published by this run
+                        #[cfg(feature = "mqtt")]
+                        if let (Some(mqtt), false) = (&self.mqtt, published) {
+                            if let Err(e) = mqtt.publish(ip).await {
+                                warn!("Failed to publish to MQTT: {:#}", e);
+                            }
+                        }
+                    }
+                    #[cfg(feature = "mqtt")]
+                    {
+                        published = true;
+                    }
+                }
+                Err(e) => warn!("Lookup failed, retrying in {:?}: {:#}", self.interval, e),
+            }
+        }
+    }
+
+    /// Handles a new public IP, which differs from `previous`
+    async fn changed(
+        &self,
+        server: &str,
+        binding: &Binding,
+        previous: Option<IpAddr>,
+    ) -> Result<()> {
+        let timestamp = self.timestamp.map(Clock::now);
+        let line = self.format.render(server, binding, timestamp.as_deref())?;
+        match previous {
+            Some(previous) => info!(
+                "Public IP changed from {} to {}",
+                previous,
+                binding.addr.ip()
+            ),
+            None => info!("Public IP is {}", binding.addr.ip()),
+        }
+        if self.diff {
+            let time = timestamp.clone().unwrap_or_else(|| Clock::Utc.now());
+            println!(
+                "{}",
+                output::render_diff(previous, binding.addr.ip(), &time)
+            );
+        } else {
+            println!("{}", line);
+        }
+        if self.show_server && !self.format.is_structured() {
+            eprintln!("Server: {}", server);
+        }
+        if let Some(output_file) = &self.output_file {
+            if let Err(e) = output_file.write(&line) {
+                warn!("Failed to update output file: {:#}", e);
+            }
+        }
+        if let Some(cmd) = &self.on_change {
+            hook::spawn(cmd, binding.addr.ip(), previous);
+        }
+        #[cfg(feature = "notify")]
+        if let (true, Some(previous)) = (self.notify, previous) {
+            notify::spawn(binding.addr.ip(), previous);
+        }
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = &self.mqtt {
+            if let Err(e) = mqtt.publish(binding.addr.ip()).await {
+                warn!("Failed to publish to MQTT: {:#}", e);
+            }
+        }
+        if let Some(hosts) = &self.hosts {
+            if let Err(e) = hosts.update(binding.addr.ip()) {
+                warn!("Failed to update hosts file: {:#}", e);
+            }
+        }
+        if let Some(cloudflare) = &self.cloudflare {
+            if let Err(e) = cloudflare.update(binding.addr.ip()).await {
+                warn!("Failed to update Cloudflare DNS record: {:#}", e);
+            }
+        }
+        if let Some(route53) = &self.route53 {
+            if let Err(e) = route53.update(binding.addr.ip()).await {
+                warn!("Failed to update Route 53 DNS record: {:#}", e);
+            }
+        }
+        if let Some(nsupdate) = &self.nsupdate {
+            if let Err(e) = nsupdate.update(binding.addr.ip()).await {
+                warn!("Failed to send DNS UPDATE: {:#}", e);
+            }
+        }
+        if let Some(duckdns) = &self.duckdns {
+            if let Err(e) = duckdns.update(binding.addr.ip()).await {
+                warn!("Failed to update DuckDNS: {:#}", e);
+            }
+        }
+        if let Some(noip) = &self.noip {
+            if let Err(e) = noip.update(binding.addr.ip()).await {
+                warn!("Failed to update No-IP: {:#}", e);
+            }
+        }
+        if let Some(webhook) = &self.webhook {
+            if let Err(e) = webhook.send(binding.addr.ip(), previous).await {
+                warn!("Failed to send webhook: {:#}", e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns a future which resolves to the name of the signal once SIGINT or SIGTERM is
+/// received. The handlers are installed immediately, so signals which arrive before the future
+/// is polled aren't missed.
+pub fn shutdown_signal() -> Result<impl Future<Output = &'static str>> {
+    let mut interrupt = signal(SignalKind::interrupt()).context("Failed to handle SIGINT")?;
+    let mut terminate = signal(SignalKind::terminate()).context("Failed to handle SIGTERM")?;
+    Ok(async move {
+        tokio::select! {
+            _ = interrupt.recv() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+        }
+    })
+}
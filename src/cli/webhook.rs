@@ -0,0 +1,128 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, Method};
+use serde_json::json;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time;
+
+/// Number of times to resend a webhook which failed with a server error
+const RETRIES: u32 = 2;
+
+/// Sends an HTTP request with the new IP whenever it changes, as configured by `--webhook`
+pub struct Webhook {
+    client: Client,
+    url: String,
+    method: Method,
+    headers: HeaderMap,
+}
+
+impl Webhook {
+    pub fn new(
+        url: String,
+        method: Method,
+        headers: Vec<(HeaderName, HeaderValue)>,
+    ) -> Result<Webhook> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .context("Failed to configure HTTP client")?;
+        Ok(Webhook {
+            client,
+            url,
+            method,
+            headers: headers.into_iter().collect(),
+        })
+    }
+
+    /// Sends `{"ip": ..., "previous": ..., "timestamp": ...}` to the webhook. Server errors
+    /// are retried a couple of times, with a short delay in between.
+    pub async fn send(&self, ip: IpAddr, previous: Option<IpAddr>) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let body = json!({
+            "ip": ip,
+            "previous": previous,
+            "timestamp": timestamp,
+        });
+        for attempt in 0..=RETRIES {
+            if attempt > 0 {
+                time::sleep(Duration::from_secs(u64::from(attempt))).await;
+            }
+            let response = self
+                .client
+                .request(self.method.clone(), &self.url)
+                .headers(self.headers.clone())
+                .json(&body)
+                .send()
+                .await
+                .with_context(|| format!("Webhook request to {} failed", self.url))?;
+            let status = response.status();
+            if status.is_success() {
+                debug!("Webhook {} returned {}", self.url, status);
+                return Ok(());
+            }
+            if !status.is_server_error() {
+                bail!("Webhook {} returned {}", self.url, status);
+            }
+            if attempt < RETRIES {
+                warn!("Webhook {} returned {}, retrying...", self.url, status);
+            }
+        }
+        bail!(
+            "Webhook {} kept failing after {} attempts",
+            self.url,
+            RETRIES + 1
+        )
+    }
+}
+
+/// Parses a `--webhook-method` value, e.g. `PUT`
+pub fn parse_method(method: &str) -> Result<Method, String> {
+    Method::from_bytes(method.to_ascii_uppercase().as_bytes()).map_err(|e| e.to_string())
+}
+
+/// Parses a `--webhook-header` value of the form `Name: value`
+pub fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = header
+        .split_once(':')
+        .ok_or_else(|| "Expected a header of the form 'Name: value'".to_string())?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes()).map_err(|e| e.to_string())?;
+    let value = HeaderValue::from_str(value.trim()).map_err(|e| e.to_string())?;
+    Ok((name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_headers() {
+        let (name, value) = parse_header("Authorization: Bearer abc:123").unwrap();
+        assert_eq!(name, "authorization");
+        assert_eq!(value, "Bearer abc:123");
+        assert!(parse_header("Authorization").is_err());
+        assert!(parse_header("Bad Name: x").is_err());
+    }
+}
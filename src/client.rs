@@ -0,0 +1,219 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! A configured client for downstream users, as an alternative to passing [`LookupOptions`]
+//! to the free functions
+
+use crate::error::Result;
+use crate::{
+    block_on, lookup_endpoint, lookup_first, Backoff, Binding, Credentials, Family, LookupOptions,
+    PcapWriter, Transport,
+};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Looks up public addresses with a fixed set of options, built with [`LookupClient::builder`]:
+///
+/// ```no_run
+/// # async fn example() -> Result<(), iplookup::LookupError> {
+/// use iplookup::{LookupClient, Transport};
+/// use std::time::Duration;
+///
+/// let client = LookupClient::builder()
+///     .retries(3)
+///     .base_timeout(Duration::from_secs(1))
+///     .transport(Transport::Udp)
+///     .build();
+/// let binding = client.lookup("stun.l.google.com:19302").await?;
+/// println!("{}", binding.addr.ip());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct LookupClient {
+    opts: LookupOptions,
+    family: Option<Family>,
+}
+
+impl LookupClient {
+    /// Returns a builder which starts from the default options
+    pub fn builder() -> LookupClientBuilder {
+        LookupClientBuilder::default()
+    }
+
+    /// Resolves `endpoint` and queries it, like [`lookup_endpoint`]
+    pub async fn lookup(&self, endpoint: &str) -> Result<Binding> {
+        lookup_endpoint(endpoint, self.family, &self.opts).await
+    }
+
+    /// Like [`LookupClient::lookup`], but blocks on a new single-threaded runtime instead of
+    /// being async. Must not be called from within an async context.
+    pub fn lookup_blocking(&self, endpoint: &str) -> Result<Binding> {
+        block_on(self.lookup(endpoint))?
+    }
+
+    /// Queries all of `endpoints` and returns the first to answer along with its response,
+    /// like [`lookup_first`]
+    pub async fn lookup_first(&self, endpoints: &[String]) -> Result<(String, Binding)> {
+        lookup_first(endpoints, self.family, &self.opts).await
+    }
+
+    /// The options which the client was built with, for use with the free functions
+    pub fn options(&self) -> &LookupOptions {
+        &self.opts
+    }
+
+    /// The address family which servers are restricted to, if any
+    pub fn family(&self) -> Option<Family> {
+        self.family
+    }
+}
+
+/// Builds a [`LookupClient`]. Each method sets the [`LookupOptions`] field of the same name.
+#[derive(Clone, Debug, Default)]
+pub struct LookupClientBuilder {
+    opts: LookupOptions,
+    family: Option<Family>,
+}
+
+impl LookupClientBuilder {
+    /// Starts from `opts` instead of the defaults
+    pub fn options(mut self, opts: LookupOptions) -> Self {
+        self.opts = opts;
+        self
+    }
+
+    /// Only query servers over IPv4 or IPv6, or `None` to use whichever they resolve to
+    pub fn family(mut self, family: impl Into<Option<Family>>) -> Self {
+        self.family = family.into();
+        self
+    }
+
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.opts.retries = retries;
+        self
+    }
+
+    pub fn base_timeout(mut self, base_timeout: Duration) -> Self {
+        self.opts.base_timeout = base_timeout;
+        self
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.opts.backoff = backoff;
+        self
+    }
+
+    pub fn final_timeout_factor(mut self, final_timeout_factor: u32) -> Self {
+        self.opts.final_timeout_factor = final_timeout_factor;
+        self
+    }
+
+    pub fn jitter_percent(mut self, jitter_percent: u32) -> Self {
+        self.opts.jitter_percent = jitter_percent;
+        self
+    }
+
+    pub fn max_timeout(mut self, max_timeout: impl Into<Option<Duration>>) -> Self {
+        self.opts.max_timeout = max_timeout.into();
+        self
+    }
+
+    pub fn deadline(mut self, deadline: impl Into<Option<Duration>>) -> Self {
+        self.opts.deadline = deadline.into();
+        self
+    }
+
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.opts.transport = transport;
+        self
+    }
+
+    pub fn insecure(mut self, insecure: bool) -> Self {
+        self.opts.insecure = insecure;
+        self
+    }
+
+    pub fn software(mut self, software: impl Into<Option<String>>) -> Self {
+        self.opts.software = software.into();
+        self
+    }
+
+    pub fn fingerprint(mut self, fingerprint: bool) -> Self {
+        self.opts.fingerprint = fingerprint;
+        self
+    }
+
+    pub fn credentials(mut self, credentials: impl Into<Option<Credentials>>) -> Self {
+        self.opts.credentials = credentials.into();
+        self
+    }
+
+    pub fn local_addr(mut self, local_addr: impl Into<Option<IpAddr>>) -> Self {
+        self.opts.local_addr = local_addr.into();
+        self
+    }
+
+    pub fn local_port(mut self, local_port: u16) -> Self {
+        self.opts.local_port = local_port;
+        self
+    }
+
+    pub fn interface(mut self, interface: impl Into<Option<String>>) -> Self {
+        self.opts.interface = interface.into();
+        self
+    }
+
+    pub fn shared_socket(mut self, shared_socket: bool) -> Self {
+        self.opts.shared_socket = shared_socket;
+        self
+    }
+
+    pub fn dual_stack(mut self, dual_stack: bool) -> Self {
+        self.opts.dual_stack = dual_stack;
+        self
+    }
+
+    pub fn recv_buffer(mut self, recv_buffer: usize) -> Self {
+        self.opts.recv_buffer = recv_buffer;
+        self
+    }
+
+    pub fn pcap(mut self, pcap: impl Into<Option<Arc<PcapWriter>>>) -> Self {
+        self.opts.pcap = pcap.into();
+        self
+    }
+
+    pub fn dns_timeout(mut self, dns_timeout: Duration) -> Self {
+        self.opts.dns_timeout = dns_timeout;
+        self
+    }
+
+    pub fn allow_local(mut self, allow_local: bool) -> Self {
+        self.opts.allow_local = allow_local;
+        self
+    }
+
+    pub fn build(self) -> LookupClient {
+        LookupClient {
+            opts: self.opts,
+            family: self.family,
+        }
+    }
+}
@@ -0,0 +1,265 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::error::{Context, Result};
+use crate::{filter_family, parse_endpoint, Family, LookupError, LookupOptions, Transport};
+use hickory_resolver::TokioAsyncResolver;
+use log::debug;
+use rand::Rng;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net;
+use tokio::time;
+
+/// Default port for STUN over UDP and TCP
+pub const DEFAULT_PORT: u16 = 3478;
+/// Default port for STUN over TLS and DTLS
+pub const DEFAULT_TLS_PORT: u16 = 5349;
+
+/// Resolves `endpoint` to an address, after removing any `stun:`/`stuns:` scheme.
+/// Endpoints with an explicit port are resolved as-is.
+/// For a bare domain, the `_stun` (or `_stuns`) SRV records for the transport are looked up
+/// as described in RFC 5389 section 9, falling back to the domain's own addresses on the
+/// transport's default port if there aren't any. A bare IP also uses the default port.
+pub async fn resolve_endpoint(
+    endpoint: &str,
+    family: Option<Family>,
+    opts: &LookupOptions,
+) -> Result<SocketAddr> {
+    Ok(resolve_endpoint_all(endpoint, family, opts)
+        .await?
+        .remove(0))
+}
+
+/// Resolves `endpoint` like [`resolve_endpoint`], but returns all of its addresses in the
+/// order that they should be tried. The result is never empty.
+pub async fn resolve_endpoint_all(
+    endpoint: &str,
+    family: Option<Family>,
+    opts: &LookupOptions,
+) -> Result<Vec<SocketAddr>> {
+    let (transport, endpoint) = parse_endpoint(endpoint, opts.transport);
+    if has_port(endpoint) {
+        return resolve_all(endpoint, family, opts).await;
+    }
+    let port = default_port(transport);
+    let host = endpoint.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return filter_family(endpoint, vec![SocketAddr::new(ip, port)], family);
+    }
+
+    match lookup_srv(host, transport, opts).await {
+        Ok(targets) => {
+            let mut addrs = Vec::new();
+            let mut last_err = None;
+            for (target, port) in targets {
+                debug!("Resolving SRV target {}:{} for {}", target, port, host);
+                match resolve_all(&format!("{}:{}", target, port), family, opts).await {
+                    Ok(target_addrs) => addrs.extend(target_addrs),
+                    Err(e) => last_err = Some(e),
+                }
+            }
+            if addrs.is_empty() {
+                return Err(last_err.expect("lookup_srv returns at least one target"));
+            }
+            Ok(addrs)
+        }
+        Err(e) => {
+            debug!("No SRV records for {}, using port {}: {:#}", host, port, e);
+            resolve_all(&format!("{}:{}", host, port), family, opts).await
+        }
+    }
+}
+
+/// Resolves a `host:port` endpoint like [`crate::resolve_all`], but without blocking the
+/// runtime, and giving up after the configured `dns_timeout`
+async fn resolve_all(
+    endpoint: &str,
+    family: Option<Family>,
+    opts: &LookupOptions,
+) -> Result<Vec<SocketAddr>> {
+    let addrs = time::timeout(opts.dns_timeout, net::lookup_host(endpoint))
+        .await
+        .map_err(|_elapsed| {
+            LookupError::Resolve(format!(
+                "Timed out after {}ms resolving {}",
+                opts.dns_timeout.as_millis(),
+                endpoint
+            ))
+        })?
+        .with_category(|| {
+            LookupError::Resolve(format!("Invalid or unresolvable endpoint: {}", endpoint))
+        })?;
+    filter_family(endpoint, addrs.collect(), family)
+}
+
+/// Returns the default server port for `transport`
+pub fn default_port(transport: Transport) -> u16 {
+    match transport {
+        Transport::Udp | Transport::Tcp => DEFAULT_PORT,
+        Transport::Tls | Transport::Dtls => DEFAULT_TLS_PORT,
+    }
+}
+
+/// Returns whether `endpoint` specifies a port. IPv6 addresses need brackets to have a port.
+fn has_port(endpoint: &str) -> bool {
+    if endpoint.parse::<SocketAddr>().is_ok() {
+        return true;
+    }
+    match endpoint.rsplit_once(':') {
+        Some((host, port)) => !host.contains(':') && port.parse::<u16>().is_ok(),
+        None => false,
+    }
+}
+
+/// Looks up the STUN SRV records of `domain` for `transport`, returning the targets in the
+/// order they should be tried
+async fn lookup_srv(
+    domain: &str,
+    transport: Transport,
+    opts: &LookupOptions,
+) -> Result<Vec<(String, u16)>> {
+    let service = match transport {
+        Transport::Udp => "_stun._udp",
+        Transport::Tcp => "_stun._tcp",
+        Transport::Tls => "_stuns._tcp",
+        Transport::Dtls => "_stuns._udp",
+    };
+    let name = format!("{}.{}.", service, domain.trim_end_matches('.'));
+    let resolver = TokioAsyncResolver::tokio_from_system_conf().with_category(|| {
+        LookupError::Resolve("Failed to load system DNS configuration".to_string())
+    })?;
+    let lookup = time::timeout(opts.dns_timeout, resolver.srv_lookup(name.as_str()))
+        .await
+        .map_err(|_elapsed| LookupError::Resolve(format!("SRV lookup for {} timed out", name)))?
+        .with_category(|| LookupError::Resolve(format!("SRV lookup for {} failed", name)))?;
+    let records: Vec<SrvRecord> = lookup
+        .iter()
+        .map(|srv| SrvRecord {
+            priority: srv.priority(),
+            weight: srv.weight(),
+            target: srv.target().to_utf8().trim_end_matches('.').to_string(),
+            port: srv.port(),
+        })
+        // A target of "." means that the service isn't available
+        .filter(|record| !record.target.is_empty())
+        .collect();
+    if records.is_empty() {
+        return Err(LookupError::Resolve(format!(
+            "{} has no usable SRV records",
+            name
+        )));
+    }
+    Ok(order_srv(records))
+}
+
+/// An SRV record, as used for ordering
+#[derive(Clone, Debug)]
+struct SrvRecord {
+    priority: u16,
+    weight: u16,
+    target: String,
+    port: u16,
+}
+
+/// Orders SRV records per RFC 2782: by ascending priority, and within a priority, by a
+/// random selection weighted by each record's weight
+fn order_srv(mut records: Vec<SrvRecord>) -> Vec<(String, u16)> {
+    // Zero-weight records go first so that they have a small chance of being picked early
+    records.sort_by_key(|record| (record.priority, record.weight != 0));
+    let mut rng = rand::thread_rng();
+    let mut ordered = Vec::with_capacity(records.len());
+    while !records.is_empty() {
+        let priority = records[0].priority;
+        let count = records
+            .iter()
+            .take_while(|record| record.priority == priority)
+            .count();
+        let total: u32 = records[..count]
+            .iter()
+            .map(|record| u32::from(record.weight))
+            .sum();
+        let pick = rng.gen_range(0..=total);
+        let mut sum = 0;
+        let index = records[..count]
+            .iter()
+            .position(|record| {
+                sum += u32::from(record.weight);
+                sum >= pick
+            })
+            .unwrap_or(0);
+        let record = records.remove(index);
+        ordered.push((record.target, record.port));
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(priority: u16, weight: u16, target: &str) -> SrvRecord {
+        SrvRecord {
+            priority,
+            weight,
+            target: target.to_string(),
+            port: DEFAULT_PORT,
+        }
+    }
+
+    #[test]
+    fn detects_port() {
+        assert!(has_port("stun.example.com:3478"));
+        assert!(has_port("127.0.0.1:3478"));
+        assert!(has_port("[::1]:3478"));
+        assert!(!has_port("stun.example.com"));
+        assert!(!has_port("127.0.0.1"));
+        assert!(!has_port("::1"));
+        assert!(!has_port("[::1]"));
+    }
+
+    #[tokio::test]
+    async fn ip_endpoints_skip_dns() {
+        // Literal addresses are parsed without a DNS lookup, so there is no need to wait
+        let opts = LookupOptions {
+            dns_timeout: std::time::Duration::ZERO,
+            ..LookupOptions::default()
+        };
+        for (endpoint, expected) in [
+            ("127.0.0.1", "127.0.0.1:3478"),
+            ("127.0.0.1:1234", "127.0.0.1:1234"),
+            ("[::1]:1234", "[::1]:1234"),
+        ] {
+            let addrs = resolve_endpoint_all(endpoint, None, &opts).await.unwrap();
+            assert_eq!(addrs, vec![expected.parse().unwrap()]);
+        }
+    }
+
+    #[test]
+    fn srv_sorted_by_priority() {
+        let ordered = order_srv(vec![
+            record(20, 5, "c"),
+            record(10, 0, "a"),
+            record(30, 1, "d"),
+            record(20, 0, "b"),
+        ]);
+        let targets: Vec<&str> = ordered.iter().map(|(t, _)| t.as_str()).collect();
+        assert_eq!(targets[0], "a");
+        assert!(targets[1..3].contains(&"b") && targets[1..3].contains(&"c"));
+        assert_eq!(targets[3], "d");
+    }
+}
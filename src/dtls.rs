@@ -0,0 +1,290 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::error::{Context, Result};
+use crate::{
+    auth, bind_std, build_request, check_mapped, check_truncation, follow_redirect, parse_response,
+    Binding, LookupError, LookupOptions, Outcome, Reply,
+};
+use log::{debug, warn};
+use openssl::ssl::{HandshakeError, SslConnector, SslMethod, SslOptions, SslStream, SslVerifyMode};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// Conservative MTU for DTLS records, since the path MTU can't be queried through openssl's
+/// stream interface
+const MTU: u32 = 1200;
+
+/// Performs a DTLS handshake with `dest` and then sends a binding request within the DTLS
+/// session, returning the public address reported in the response. `server_name` is the
+/// hostname (or IP) used for verifying the server's certificate. Retries follow the same
+/// timeout schedule as plain UDP, but resend within the DTLS session rather than as raw
+/// datagrams. Gives up early if the configured `deadline` passes, including during the
+/// handshake.
+pub async fn lookup_dtls(
+    dest: &SocketAddr,
+    server_name: &str,
+    opts: &LookupOptions,
+) -> Result<Binding> {
+    let dest = *dest;
+    let server_name = server_name.to_string();
+    let opts = opts.clone();
+    // openssl's stream interface is blocking, so keep it off of the async runtime
+    tokio::task::spawn_blocking(move || lookup_blocking(&dest, &server_name, &opts))
+        .await
+        .context("DTLS lookup task failed")?
+}
+
+fn lookup_blocking(dest: &SocketAddr, server_name: &str, opts: &LookupOptions) -> Result<Binding> {
+    if opts.retries == 0 {
+        return Err(LookupError::InvalidOptions(
+            "Retries must be at least 1".to_string(),
+        ));
+    }
+    let schedule = opts.timeout_schedule();
+    let start = Instant::now();
+
+    let mut dest = *dest;
+    let mut redirects = 0;
+    loop {
+        match query(&dest, server_name, &schedule, start, opts)? {
+            Outcome::Mapped(binding) => {
+                check_mapped(&binding, &dest, opts)?;
+                return Ok(binding);
+            }
+            Outcome::Redirect(next) => {
+                follow_redirect(&mut redirects, &dest, &next)?;
+                dest = next;
+            }
+        }
+    }
+}
+
+/// Performs a DTLS handshake with `dest` and sends a binding request within the session,
+/// answering any authentication challenges. The `deadline` counts from `start`.
+fn query(
+    dest: &SocketAddr,
+    server_name: &str,
+    schedule: &[Duration],
+    start: Instant,
+    opts: &LookupOptions,
+) -> Result<Outcome> {
+    let socket = bind_std(&opts.local_addr_for(dest)?, opts.interface.as_deref())?;
+    // Connecting the socket means we only receive datagrams from dest
+    socket
+        .connect(dest)
+        .with_context(|| format!("Failed to connect socket to {}", dest))?;
+    socket.set_read_timeout(Some(within_deadline(schedule[0], start, dest, opts)?))?;
+
+    let mut builder =
+        SslConnector::builder(SslMethod::dtls()).context("Failed to configure DTLS")?;
+    builder.set_options(SslOptions::NO_QUERY_MTU);
+    if opts.insecure {
+        builder.set_verify(SslVerifyMode::NONE);
+    }
+    let mut config = builder
+        .build()
+        .configure()
+        .context("Failed to configure DTLS")?;
+    config.set_mtu(MTU).context("Failed to configure DTLS")?;
+    if opts.insecure {
+        config.set_verify_hostname(false);
+    }
+
+    let mut stream = handshake(
+        config.connect(server_name, DatagramStream(socket)),
+        dest,
+        schedule,
+        start,
+        opts,
+    )?;
+
+    let mut recvbuf = vec![0u8; opts.recv_buffer];
+    let mut challenge = None;
+    for _ in 0..auth::MAX_REQUESTS {
+        let (transaction_id, message_bytes) = build_request(opts, challenge.as_ref())?;
+        let (recvsize, rtt) = request(
+            &mut stream,
+            dest,
+            &message_bytes,
+            &mut recvbuf,
+            schedule,
+            start,
+            opts,
+        )?;
+        match parse_response(
+            transaction_id,
+            &recvbuf[..recvsize],
+            rtt,
+            opts,
+            challenge.as_ref(),
+        )? {
+            Reply::Mapped(binding) => return Ok(Outcome::Mapped(binding)),
+            Reply::Redirect(next) => return Ok(Outcome::Redirect(next)),
+            Reply::Challenge(next) => challenge = Some(next),
+        }
+    }
+    Err(LookupError::Auth(format!(
+        "Too many authentication challenges from {}",
+        dest
+    )))
+}
+
+/// Sends `message_bytes` within the DTLS session and waits for a response, resending on each
+/// timeout in `schedule`. Returns the size of the response written to `recvbuf`, and the time
+/// since the most recent send.
+fn request(
+    stream: &mut SslStream<DatagramStream>,
+    dest: &SocketAddr,
+    message_bytes: &[u8],
+    recvbuf: &mut [u8],
+    schedule: &[Duration],
+    start: Instant,
+    opts: &LookupOptions,
+) -> Result<(usize, Duration)> {
+    for (attempt, timeout) in schedule.iter().enumerate() {
+        let timeout = within_deadline(*timeout, start, dest, opts)?;
+        // (Re)send request within the session
+        stream
+            .get_ref()
+            .0
+            .set_read_timeout(Some(timeout))
+            .context("Failed to set socket timeout")?;
+        let sent = Instant::now();
+        stream
+            .ssl_write(message_bytes)
+            .with_context(|| format!("Failed to send STUN request to {}", dest))?;
+
+        match stream.ssl_read(recvbuf) {
+            Ok(recvsize) => {
+                check_truncation(recvsize, recvbuf, dest)?;
+                return Ok((recvsize, sent.elapsed()));
+            }
+            Err(e) if is_timeout(e.io_error()) => {
+                // Timeout occurred, try again (or exit loop)
+                if attempt + 1 == schedule.len() {
+                    warn!("Timed out after {}ms, giving up.", timeout.as_millis());
+                } else {
+                    warn!(
+                        "Timed out after {}ms, trying {} again...",
+                        timeout.as_millis(),
+                        dest
+                    );
+                }
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to receive STUN response from {}", dest));
+            }
+        }
+    }
+    Err(LookupError::Timeout(format!(
+        "Timed out waiting for response from {:?}",
+        dest
+    )))
+}
+
+/// Drives the DTLS handshake to completion, waiting up to the duration of each attempt in
+/// `schedule` for the server to respond. openssl resends handshake flights itself once its
+/// retransmission timer has expired.
+fn handshake(
+    mut result: Result<SslStream<DatagramStream>, HandshakeError<DatagramStream>>,
+    dest: &SocketAddr,
+    schedule: &[Duration],
+    start: Instant,
+    opts: &LookupOptions,
+) -> Result<SslStream<DatagramStream>> {
+    for timeout in schedule {
+        match result {
+            Ok(stream) => return Ok(stream),
+            Err(HandshakeError::WouldBlock(mid)) => {
+                debug!("DTLS handshake with {} still in progress...", dest);
+                let timeout = within_deadline(*timeout, start, dest, opts)?;
+                mid.get_ref().0.set_read_timeout(Some(timeout))?;
+                result = mid.handshake();
+            }
+            Err(e) => {
+                return Err(LookupError::Other(format!(
+                    "DTLS handshake with {} failed: {}",
+                    dest, e
+                )))
+            }
+        }
+    }
+    match result {
+        Ok(stream) => Ok(stream),
+        Err(HandshakeError::WouldBlock(_)) => Err(LookupError::Timeout(format!(
+            "Timed out waiting for DTLS handshake with {}",
+            dest
+        ))),
+        Err(e) => Err(LookupError::Other(format!(
+            "DTLS handshake with {} failed: {}",
+            dest, e
+        ))),
+    }
+}
+
+/// Shortens `timeout` to whatever is left of the configured `deadline`, counting from `start`,
+/// or fails once the deadline has passed
+fn within_deadline(
+    timeout: Duration,
+    start: Instant,
+    dest: &SocketAddr,
+    opts: &LookupOptions,
+) -> Result<Duration> {
+    let deadline = match opts.deadline {
+        Some(deadline) => deadline,
+        None => return Ok(timeout),
+    };
+    match deadline.checked_sub(start.elapsed()) {
+        Some(left) if !left.is_zero() => Ok(timeout.min(left)),
+        _ => Err(LookupError::Timeout(format!(
+            "Deadline of {}ms exceeded waiting for response from {:?}",
+            deadline.as_millis(),
+            dest
+        ))),
+    }
+}
+
+fn is_timeout(err: Option<&io::Error>) -> bool {
+    matches!(
+        err.map(io::Error::kind),
+        Some(ErrorKind::WouldBlock) | Some(ErrorKind::TimedOut)
+    )
+}
+
+/// Presents a connected UDP socket as a stream for openssl, one datagram per read/write
+#[derive(Debug)]
+struct DatagramStream(UdpSocket);
+
+impl Read for DatagramStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.recv(buf)
+    }
+}
+
+impl Write for DatagramStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
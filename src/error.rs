@@ -0,0 +1,209 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::error::Error as StdError;
+use std::fmt::Display;
+use std::io;
+use std::net::IpAddr;
+use thiserror::Error;
+
+/// Result of the lookups in this crate
+pub(crate) type Result<T, E = LookupError> = std::result::Result<T, E>;
+
+/// Reason that a lookup failed, so that callers can react differently to each
+#[derive(Debug, Error)]
+pub enum LookupError {
+    /// The endpoint couldn't be resolved to an address
+    #[error("{0}")]
+    Resolve(String),
+    /// The local socket couldn't be bound, for example because the port is in use
+    #[error("{0}")]
+    Bind(String),
+    /// No response arrived before all attempts ran out
+    #[error("{0}")]
+    Timeout(String),
+    /// The response was for a different transaction than the request
+    #[error("{0}")]
+    TransactionMismatch(String),
+    /// The response couldn't be decoded as a STUN message
+    #[error("{0}")]
+    Decode(String),
+    /// The response was valid, but didn't include a mapped address
+    #[error("{0}")]
+    NoAddress(String),
+    /// The response's mapped address isn't meaningful as a public address, such as 0.0.0.0
+    #[error("{0}")]
+    InvalidAddress(String),
+    /// The server answered with an error response
+    #[error("STUN error {code}: {reason}")]
+    ServerError { code: u16, reason: String },
+    /// Authentication with the server failed, or the server kept challenging the request
+    #[error("{0}")]
+    Auth(String),
+    /// An ALTERNATE-SERVER redirect couldn't be followed
+    #[error("{0}")]
+    Redirect(String),
+    /// The lookup options are invalid, or can't be used with this server
+    #[error("{0}")]
+    InvalidOptions(String),
+    /// The requested feature isn't supported by this build or platform
+    #[error("{0}")]
+    Unsupported(String),
+    /// Each of several servers failed, listed with its error. If they all timed out, the
+    /// failure is a `Timeout` instead.
+    #[error("All {} servers failed:\n{}", .0.len(), summarize(.0))]
+    AllFailed(Vec<(String, LookupError)>),
+    /// Fewer than `quorum` of the `servers` agreed on the public IP. `ip` was reported by the
+    /// most servers, and `errors` lists the servers which failed outright.
+    #[error("Quorum not reached: {agreed} of {servers} servers reported {ip}, needed {quorum}")]
+    NoQuorum {
+        ip: IpAddr,
+        agreed: usize,
+        servers: usize,
+        quorum: usize,
+        errors: Vec<(String, LookupError)>,
+    },
+    /// Sending or receiving failed, for example on an already bound socket
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// Any other failure, such as a TLS or DNS configuration error
+    #[error("{0}")]
+    Other(String),
+}
+
+impl LookupError {
+    /// Returns this error with `context` in front of its message
+    fn prefixed(self, context: impl Display) -> LookupError {
+        self.map_message(|message| format!("{}: {}", context, message))
+    }
+
+    /// Returns this error with its message replaced by `map(message)`
+    fn map_message(self, map: impl FnOnce(String) -> String) -> LookupError {
+        use LookupError::*;
+        match self {
+            Resolve(m) => Resolve(map(m)),
+            Bind(m) => Bind(map(m)),
+            Timeout(m) => Timeout(map(m)),
+            TransactionMismatch(m) => TransactionMismatch(map(m)),
+            Decode(m) => Decode(map(m)),
+            NoAddress(m) => NoAddress(map(m)),
+            InvalidAddress(m) => InvalidAddress(map(m)),
+            Auth(m) => Auth(map(m)),
+            Redirect(m) => Redirect(map(m)),
+            InvalidOptions(m) => InvalidOptions(map(m)),
+            Unsupported(m) => Unsupported(map(m)),
+            Io(e) => Io(io::Error::new(e.kind(), map(chain(&e)))),
+            Other(m) => Other(map(m)),
+            // These describe themselves fully
+            e @ (ServerError { .. } | AllFailed(_) | NoQuorum { .. }) => e,
+        }
+    }
+}
+
+/// Lists each server's error on its own line
+fn summarize(errors: &[(String, LookupError)]) -> String {
+    errors
+        .iter()
+        .map(|(endpoint, e)| format!("  {}: {}", endpoint, e))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Adds context to the message of a failure, like `anyhow::Context`. I/O errors keep their
+/// kind and errors from this crate keep their category, while anything else becomes `Other`.
+pub(crate) trait Context<T> {
+    fn context<C: Display>(self, context: C) -> Result<T>;
+
+    fn with_context<C: Display, F: FnOnce() -> C>(self, context: F) -> Result<T>;
+
+    /// Fails with the error returned by `category`, followed by the message of the failure
+    fn with_category<F: FnOnce() -> LookupError>(self, category: F) -> Result<T>;
+}
+
+impl<T, E> Context<T> for std::result::Result<T, E>
+where
+    E: StdError + Send + Sync + 'static,
+{
+    fn context<C: Display>(self, context: C) -> Result<T> {
+        self.map_err(|e| wrap(e, context))
+    }
+
+    fn with_context<C: Display, F: FnOnce() -> C>(self, context: F) -> Result<T> {
+        self.map_err(|e| wrap(e, context()))
+    }
+
+    fn with_category<F: FnOnce() -> LookupError>(self, category: F) -> Result<T> {
+        self.map_err(|e| category().map_message(|message| format!("{}: {}", message, chain(&e))))
+    }
+}
+
+/// Puts `context` in front of the message of `error`, keeping its category if it has one
+fn wrap<E: StdError + Send + Sync + 'static>(error: E, context: impl Display) -> LookupError {
+    let error: Box<dyn StdError + Send + Sync> = Box::new(error);
+    let error = match error.downcast::<LookupError>() {
+        Ok(e) => return e.prefixed(context),
+        Err(error) => error,
+    };
+    match error.downcast::<io::Error>() {
+        Ok(e) => LookupError::Io(*e).prefixed(context),
+        Err(error) => LookupError::Other(format!("{}: {}", context, chain(&*error))),
+    }
+}
+
+/// Formats `error` followed by each of its sources, like anyhow's `{:#}`
+pub(crate) fn chain(error: &(dyn StdError + 'static)) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(e) = source {
+        message.push_str(": ");
+        message.push_str(&e.to_string());
+        source = e.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn context_keeps_the_category() {
+        let err = Err::<(), _>(LookupError::Timeout("No response".to_string()))
+            .context("Query to 192.0.2.1:3478 failed")
+            .unwrap_err();
+        assert!(matches!(err, LookupError::Timeout(_)));
+        assert_eq!(
+            err.to_string(),
+            "Query to 192.0.2.1:3478 failed: No response"
+        );
+
+        let err = Err::<(), _>(io::Error::from(io::ErrorKind::ConnectionReset))
+            .context("Failed to send")
+            .unwrap_err();
+        match err {
+            LookupError::Io(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionReset),
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        let err = Err::<(), _>(io::Error::from(io::ErrorKind::AddrInUse))
+            .with_category(|| LookupError::Bind("Failed to bind".to_string()))
+            .unwrap_err();
+        assert!(matches!(err, LookupError::Bind(_)));
+        assert!(err.to_string().starts_with("Failed to bind: "));
+    }
+}
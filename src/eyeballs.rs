@@ -0,0 +1,151 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Querying servers which resolve to several addresses
+
+use crate::error::Result;
+use log::{debug, warn};
+use std::fmt::Display;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time;
+
+/// Head start given to IPv6 before IPv4 is also queried, as recommended by RFC 8305
+pub const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+/// Queries dual-stack `addrs` using Happy Eyeballs (RFC 8305). `query` is called with the
+/// IPv6 addresses first, and then with the IPv4 addresses once IPv6 has failed or hasn't
+/// answered within [`HAPPY_EYEBALLS_DELAY`]. The first success wins and the other query is
+/// cancelled. If `addrs` only has one family, it's passed to `query` directly.
+pub(crate) async fn race<T, F, Fut>(addrs: &[SocketAddr], query: F) -> Result<T>
+where
+    F: Fn(Vec<SocketAddr>) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.iter().partition(|addr| addr.is_ipv6());
+    if v6.is_empty() {
+        return query(v4).await;
+    }
+    if v4.is_empty() {
+        return query(v6).await;
+    }
+
+    let first = query(v6);
+    tokio::pin!(first);
+    tokio::select! {
+        result = &mut first => match result {
+            Ok(value) => return Ok(value),
+            Err(e) => {
+                debug!("IPv6 query failed, falling back to IPv4: {:#}", e);
+                return query(v4).await;
+            }
+        },
+        _ = time::sleep(HAPPY_EYEBALLS_DELAY) => {
+            debug!("No IPv6 response after {:?}, also trying IPv4", HAPPY_EYEBALLS_DELAY);
+        }
+    }
+
+    let second = query(v4);
+    tokio::pin!(second);
+    tokio::select! {
+        result = &mut first => match result {
+            Ok(value) => Ok(value),
+            Err(e) => {
+                debug!("IPv6 query failed, waiting for IPv4: {:#}", e);
+                second.await
+            }
+        },
+        result = &mut second => match result {
+            Ok(value) => Ok(value),
+            Err(e) => {
+                debug!("IPv4 query failed, waiting for IPv6: {:#}", e);
+                first.await
+            }
+        },
+    }
+}
+
+/// Queries each of `addrs` in turn, returning the first success or the last error if none
+/// of them answer. `addrs` must not be empty.
+pub async fn try_each<T, E, F, Fut>(addrs: &[SocketAddr], query: F) -> Result<T, E>
+where
+    E: Display,
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut last_err = None;
+    for (idx, dest) in addrs.iter().enumerate() {
+        match query(*dest).await {
+            Ok(value) => return Ok(value),
+            Err(e) => {
+                if idx + 1 < addrs.len() {
+                    warn!("Query to {} failed, trying the next address: {:#}", dest, e);
+                }
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.expect("resolved endpoints have at least one address"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LookupError;
+    use std::time::Instant;
+
+    fn addrs() -> Vec<SocketAddr> {
+        vec![
+            "[::1]:3478".parse().unwrap(),
+            "127.0.0.1:3478".parse().unwrap(),
+        ]
+    }
+
+    #[tokio::test]
+    async fn ipv4_wins_after_head_start() {
+        let start = Instant::now();
+        let winner = race(&addrs(), |addrs| async move {
+            if addrs[0].is_ipv6() {
+                time::sleep(Duration::from_secs(10)).await;
+            }
+            Ok(addrs[0])
+        })
+        .await
+        .unwrap();
+        assert!(winner.is_ipv4());
+        assert!(start.elapsed() >= HAPPY_EYEBALLS_DELAY);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn ipv6_failure_skips_head_start() {
+        let start = Instant::now();
+        let winner = race(&addrs(), |addrs| async move {
+            if addrs[0].is_ipv6() {
+                return Err(LookupError::Other("unreachable".to_string()));
+            }
+            Ok(addrs[0])
+        })
+        .await
+        .unwrap();
+        assert!(winner.is_ipv4());
+        assert!(start.elapsed() < HAPPY_EYEBALLS_DELAY);
+    }
+}
@@ -0,0 +1,171 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How long to skip a server after its first failure, doubled on each further failure
+pub const BASE_COOLDOWN: Duration = Duration::from_secs(30);
+/// Upper limit on how long to skip a failing server
+pub const MAX_COOLDOWN: Duration = Duration::from_secs(3600);
+
+/// Tracks which servers have recently failed, so that repeated lookups can skip them for a
+/// while instead of querying a dead server every time. The cooldown grows exponentially with
+/// each consecutive failure, and is reset when the server answers again.
+#[derive(Debug, Default)]
+pub struct Health {
+    servers: HashMap<String, ServerHealth>,
+}
+
+/// The failure record of a single server
+#[derive(Debug)]
+struct ServerHealth {
+    /// Number of consecutive failed queries
+    failures: u32,
+    /// When the server may be queried again
+    retry_at: Instant,
+}
+
+impl Health {
+    /// Returns the subset of `endpoints` which aren't cooling down after a failure. If they all
+    /// are, returns the one which will be ready soonest, so that there's always a server to try.
+    pub fn available(&self, endpoints: &[String]) -> Vec<String> {
+        self.available_at(endpoints, Instant::now())
+    }
+
+    /// Records a successful query to `endpoint`, clearing any failures
+    pub fn succeeded(&mut self, endpoint: &str) {
+        self.servers.remove(endpoint);
+    }
+
+    /// Records a failed query to `endpoint`, skipping it for longer than last time
+    pub fn failed(&mut self, endpoint: &str) {
+        self.failed_at(endpoint, Instant::now())
+    }
+
+    /// Returns whether every server is healthy
+    pub fn is_empty(&self) -> bool {
+        self.servers.is_empty()
+    }
+
+    fn available_at(&self, endpoints: &[String], now: Instant) -> Vec<String> {
+        let ready: Vec<String> = endpoints
+            .iter()
+            .filter(|endpoint| match self.servers.get(*endpoint) {
+                Some(health) => health.retry_at <= now,
+                None => true,
+            })
+            .cloned()
+            .collect();
+        if !ready.is_empty() {
+            return ready;
+        }
+        endpoints
+            .iter()
+            .min_by_key(|endpoint| self.servers.get(*endpoint).map(|health| health.retry_at))
+            .cloned()
+            .into_iter()
+            .collect()
+    }
+
+    fn failed_at(&mut self, endpoint: &str, now: Instant) {
+        let failures = self
+            .servers
+            .get(endpoint)
+            .map_or(1, |health| health.failures.saturating_add(1));
+        let cooldown = BASE_COOLDOWN
+            .saturating_mul(2_u32.saturating_pow(failures - 1))
+            .min(MAX_COOLDOWN);
+        self.servers.insert(
+            endpoint.to_string(),
+            ServerHealth {
+                failures,
+                retry_at: now + cooldown,
+            },
+        );
+    }
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let now = Instant::now();
+        let mut endpoints: Vec<&String> = self.servers.keys().collect();
+        endpoints.sort();
+        for (idx, endpoint) in endpoints.into_iter().enumerate() {
+            let health = &self.servers[endpoint];
+            if idx > 0 {
+                writeln!(f)?;
+            }
+            write!(
+                f,
+                "  {}: {} failures, retry in {}s",
+                endpoint,
+                health.failures,
+                health.retry_at.saturating_duration_since(now).as_secs()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failing_servers_cool_down() {
+        let endpoints = vec!["a:3478".to_string(), "b:3478".to_string()];
+        let mut health = Health::default();
+        let now = Instant::now();
+        health.failed_at("a:3478", now);
+        assert_eq!(health.available_at(&endpoints, now), vec!["b:3478"]);
+        assert_eq!(
+            health.available_at(&endpoints, now + BASE_COOLDOWN),
+            endpoints
+        );
+
+        // The cooldown doubles with each consecutive failure
+        health.failed_at("a:3478", now);
+        assert_eq!(
+            health.available_at(&endpoints, now + BASE_COOLDOWN),
+            vec!["b:3478"]
+        );
+        assert_eq!(
+            health.available_at(&endpoints, now + BASE_COOLDOWN * 2),
+            endpoints
+        );
+
+        // If every server is cooling down, the one that'll be ready first is still tried
+        health.failed_at("b:3478", now);
+        assert_eq!(health.available_at(&endpoints, now), vec!["b:3478"]);
+
+        health.succeeded("a:3478");
+        assert_eq!(health.available_at(&endpoints, now), vec!["a:3478"]);
+    }
+
+    #[test]
+    fn cooldown_is_capped() {
+        let mut health = Health::default();
+        let now = Instant::now();
+        for _ in 0..100 {
+            health.failed_at("a:3478", now);
+        }
+        assert_eq!(health.servers["a:3478"].retry_at, now + MAX_COOLDOWN);
+    }
+}
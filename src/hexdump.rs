@@ -0,0 +1,85 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Dumps of raw STUN messages, logged at trace level under the `iplookup::hexdump` target so
+//! that they can be enabled separately from the decoded messages
+
+use log::{log_enabled, trace, Level};
+use std::fmt::Write;
+
+/// Number of bytes shown on each line of a dump
+const LINE_BYTES: usize = 16;
+
+/// Logs a dump of `bytes`, a message which is being sent
+pub(crate) fn sent(bytes: &[u8]) {
+    if log_enabled!(Level::Trace) {
+        trace!("Sending {} bytes:\n{}", bytes.len(), dump(bytes));
+    }
+}
+
+/// Logs a dump of `bytes`, a message which was received
+pub(crate) fn received(bytes: &[u8]) {
+    if log_enabled!(Level::Trace) {
+        trace!("Received {} bytes:\n{}", bytes.len(), dump(bytes));
+    }
+}
+
+/// Formats `bytes` like `hexdump -C`, with the offset, the hex bytes split into two groups of
+/// eight, and the printable ASCII characters of each line
+fn dump(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for (line, chunk) in bytes.chunks(LINE_BYTES).enumerate() {
+        write!(out, "{:08x} ", line * LINE_BYTES).expect("writing to a String");
+        for idx in 0..LINE_BYTES {
+            if idx % 8 == 0 {
+                out.push(' ');
+            }
+            match chunk.get(idx) {
+                Some(byte) => write!(out, "{:02x} ", byte).expect("writing to a String"),
+                None => out.push_str("   "),
+            }
+        }
+        out.push_str(" |");
+        out.extend(chunk.iter().map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                char::from(byte)
+            } else {
+                '.'
+            }
+        }));
+        out.push_str("|\n");
+    }
+    write!(out, "{:08x}", bytes.len()).expect("writing to a String");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dumps_like_hexdump() {
+        let bytes = b"\x00\x01\x00\x08\x21\x12\xa4\x42iplookup test";
+        assert_eq!(
+            dump(bytes),
+            "00000000  00 01 00 08 21 12 a4 42  69 70 6c 6f 6f 6b 75 70  |....!..Biplookup|\n\
+             00000010  20 74 65 73 74                                    | test|\n\
+             00000015"
+        );
+    }
+}
@@ -0,0 +1,85 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::error::{chain, Context, Result};
+use crate::{Family, LookupError, LookupOptions};
+use std::net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Fetches `url` from an HTTP(S) "what's my IP" echo service and parses the response body as
+/// a bare IP. Unlike STUN, this only reveals the public IP that the service saw, not the NAT
+/// mapping. If `family` is provided, the request is only sent over that family. The request
+/// is given the total wait time of the retry schedule.
+pub async fn lookup_http(
+    url: &str,
+    family: Option<Family>,
+    opts: &LookupOptions,
+) -> Result<IpAddr> {
+    let timeout = opts.total_timeout();
+    let mut builder = reqwest::Client::builder().timeout(timeout);
+    if let Some(software) = &opts.software {
+        builder = builder.user_agent(software);
+    }
+    let client = builder
+        // Binding to the unspecified address of a family restricts connections to it
+        .local_address(family.map(|family| match family {
+            Family::V4 => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            Family::V6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        }))
+        .build()
+        .context("Failed to configure HTTP client")?;
+    let response = client.get(url).send().await.map_err(|e| {
+        if e.is_timeout() {
+            LookupError::Timeout(format!("Timed out after {:?} waiting for {}", timeout, url))
+        } else {
+            LookupError::Other(format!("HTTP request to {} failed: {}", url, chain(&e)))
+        }
+    })?;
+    let response = response
+        .error_for_status()
+        .with_context(|| format!("HTTP request to {} failed", url))?;
+    let body = response
+        .text()
+        .await
+        .with_context(|| format!("Failed to read HTTP response from {}", url))?;
+    parse_body(&body).with_category(|| {
+        LookupError::Decode(format!(
+            "HTTP response from {} isn't a bare IP: {:?}",
+            url, body
+        ))
+    })
+}
+
+/// Parses an echo service's response body, allowing surrounding whitespace
+fn parse_body(body: &str) -> Result<IpAddr, AddrParseError> {
+    body.trim().parse()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bare_ip() {
+        assert_eq!(
+            parse_body("192.0.2.1\n").unwrap(),
+            "192.0.2.1".parse::<IpAddr>().unwrap()
+        );
+        assert!(parse_body(" 2001:db8::1 ").unwrap().is_ipv6());
+        assert!(parse_body("<html>").is_err());
+    }
+}
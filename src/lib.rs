@@ -0,0 +1,1715 @@
+#![deny(warnings)]
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Queries a [STUN](https://tools.ietf.org/html/rfc5389) server for the public address of a
+//! local UDP socket.
+
+use crate::attributes::{Attribute, ChangedAddress};
+use crate::auth::Challenge;
+use crate::error::{Context, Result};
+use bytecodec::{DecodeExt, EncodeExt};
+use log::{debug, trace, warn};
+use rand::Rng;
+use serde::Serialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fmt;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use stun_codec::rfc5389::attributes::{AlternateServer, ErrorCode, Fingerprint, Software};
+use stun_codec::rfc5389::{errors, methods};
+use stun_codec::rfc5780::attributes::{OtherAddress, ResponseOrigin};
+use stun_codec::{Message, MessageClass, MessageDecoder, MessageEncoder, Method, TransactionId};
+use tokio::net::UdpSocket;
+use tokio::time;
+
+mod attributes;
+mod auth;
+mod client;
+mod dns;
+#[cfg(feature = "dtls")]
+mod dtls;
+mod error;
+mod eyeballs;
+mod health;
+mod hexdump;
+#[cfg(feature = "http")]
+mod http;
+#[cfg(test)]
+#[path = "../tests/common/mod.rs"]
+mod mock;
+mod multi;
+mod mux;
+mod nat;
+mod pcap;
+mod tcp;
+mod tls;
+mod turn;
+mod uri;
+
+pub use attributes::ResponseAttribute;
+pub use auth::Credentials;
+pub use client::{LookupClient, LookupClientBuilder};
+pub use dns::{
+    default_port, resolve_endpoint, resolve_endpoint_all, DEFAULT_PORT, DEFAULT_TLS_PORT,
+};
+#[cfg(feature = "dtls")]
+pub use dtls::lookup_dtls;
+pub use error::LookupError;
+pub use eyeballs::{try_each, HAPPY_EYEBALLS_DELAY};
+pub use health::Health;
+#[cfg(feature = "http")]
+pub use http::lookup_http;
+pub use multi::{lookup_consensus, lookup_first, lookup_first_tracked, lookup_in_turn};
+pub use mux::lookup_multiplexed;
+pub use nat::{
+    check_symmetric, detect_filtering, detect_mapping, detect_nat, test_hairpin, Filtering,
+    FilteringBehavior, FilteringTest, Hairpin, Mapping, MappingBehavior, MappingTest, NatBehavior,
+    SymmetricCheck,
+};
+pub use pcap::PcapWriter;
+pub use tcp::lookup_tcp;
+pub use tls::lookup_tls;
+pub use turn::{allocate_turn, Allocation};
+pub use uri::parse_endpoint;
+
+/// Public STUN servers which may be used when no server is specified
+pub const DEFAULT_SERVERS: &[&str] = &[
+    "stun.l.google.com:19302",
+    "stun1.l.google.com:19302",
+    "stun.cloudflare.com:3478",
+    "stun.stunprotocol.org:3478",
+];
+
+/// Options for a STUN lookup
+#[derive(Clone, Debug)]
+pub struct LookupOptions {
+    /// Number of times to send the request before giving up, must be at least 1
+    pub retries: u32,
+    /// Timeout for the first attempt, which grows on each subsequent attempt according to
+    /// `backoff`. This is the initial RTO of RFC 5389.
+    pub base_timeout: Duration,
+    /// How the timeout changes between attempts
+    pub backoff: Backoff,
+    /// With [`Backoff::Rfc5389`], how many times `base_timeout` to wait after the last request
+    /// before giving up. This is Rm in RFC 5389.
+    pub final_timeout_factor: u32,
+    /// Randomly lengthen or shorten each timeout by up to this percentage, so that many
+    /// clients don't retransmit in lockstep. 0 keeps the schedule exact.
+    pub jitter_percent: u32,
+    /// Upper limit on the timeout of any single attempt, after `backoff` and jitter are
+    /// applied, or `None` to let the timeout keep growing
+    pub max_timeout: Option<Duration>,
+    /// Upper limit on the total time spent waiting across all attempts, or `None` to wait for
+    /// the whole timeout schedule
+    pub deadline: Option<Duration>,
+    /// How to send the request
+    pub transport: Transport,
+    /// Skip verification of the server's certificate in encrypted transports
+    pub insecure: bool,
+    /// Value of the SOFTWARE attribute to include in requests, or `None` to omit it
+    pub software: Option<String>,
+    /// Append a FINGERPRINT attribute to requests. Fingerprints in responses are always checked.
+    pub fingerprint: bool,
+    /// Credentials to authenticate with if the server requests them
+    pub credentials: Option<Credentials>,
+    /// Local IP to send UDP and DTLS requests from, or `None` for the unspecified address
+    pub local_addr: Option<IpAddr>,
+    /// Local port to send UDP and DTLS requests from, or 0 for an ephemeral port
+    pub local_port: u16,
+    /// Network interface to send UDP and DTLS requests through, regardless of the routing
+    /// table. Only supported on Linux.
+    pub interface: Option<String>,
+    /// Query several UDP servers from a single socket per address family, instead of one
+    /// socket per server
+    pub shared_socket: bool,
+    /// Like `shared_socket`, but use a single dual-stack IPv6 socket for both families, which
+    /// reaches IPv4 servers through IPv4-mapped addresses. Falls back to a socket per family
+    /// where dual-stack sockets aren't available.
+    pub dual_stack: bool,
+    /// Size of the buffer which UDP and DTLS responses are received into. A response which
+    /// fills it is rejected, since the rest of the datagram would have been cut off.
+    pub recv_buffer: usize,
+    /// Capture file to record UDP requests and responses in, including retransmissions
+    pub pcap: Option<Arc<PcapWriter>>,
+    /// How long to wait for each DNS lookup of a server, including its SRV records
+    pub dns_timeout: Duration,
+    /// Accept loopback and link-local public addresses from any server, rather than only from
+    /// servers which are themselves loopback or link-local
+    pub allow_local: bool,
+}
+
+/// A server's response to a binding request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Binding {
+    /// Public address reported by the server
+    pub addr: SocketAddr,
+    /// The server's alternate address from OTHER-ADDRESS, or the legacy CHANGED-ADDRESS
+    pub other_address: Option<SocketAddr>,
+    /// Time between sending the request which was answered and receiving the response
+    pub rtt: Duration,
+    /// The address which the server says it sent the response from, from RESPONSE-ORIGIN
+    pub response_origin: Option<SocketAddr>,
+    /// Every attribute in the response, for inspecting nonstandard servers
+    pub attributes: Vec<ResponseAttribute>,
+}
+
+/// How the timeout changes between attempts
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backoff {
+    /// The retransmission algorithm of RFC 5389 section 7.2.1: double the timeout on each
+    /// attempt, except that the last attempt waits `final_timeout_factor` times the initial
+    /// timeout
+    Rfc5389,
+    /// Double the timeout on each attempt, including the last
+    Exponential,
+    /// Add the initial timeout on each attempt, so that the Nth attempt waits N times it
+    Linear,
+    /// Wait for the same timeout on every attempt, for networks with known, stable latency
+    Constant,
+}
+
+impl Backoff {
+    /// Returns the timeout for the zero-based `attempt` out of `opts.retries`, before jitter
+    fn timeout(self, attempt: u32, opts: &LookupOptions) -> Duration {
+        let base = opts.base_timeout;
+        match self {
+            Backoff::Rfc5389 if attempt + 1 == opts.retries => {
+                base.saturating_mul(opts.final_timeout_factor)
+            }
+            Backoff::Rfc5389 | Backoff::Exponential => {
+                base.saturating_mul(2_u32.saturating_pow(attempt))
+            }
+            Backoff::Linear => base.saturating_mul(attempt.saturating_add(1)),
+            Backoff::Constant => base,
+        }
+    }
+}
+
+/// Transport used for sending STUN requests
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    /// Datagrams, retransmitted on the `backoff` schedule
+    Udp,
+    /// A single request over a TCP connection
+    Tcp,
+    /// A single request over a TLS connection, typically on port 5349
+    Tls,
+    /// Datagrams within a DTLS session. Requires the `dtls` feature.
+    Dtls,
+}
+
+impl Default for LookupOptions {
+    fn default() -> Self {
+        // RFC 5389 with Rc=7 and Rm=16, receive timeout durations:
+        // 0.5s, 1s, 2s, 4s, 8s, 16s, 8s (total wait: 39.5s)
+        LookupOptions {
+            retries: 7,
+            base_timeout: Duration::from_millis(500),
+            backoff: Backoff::Rfc5389,
+            final_timeout_factor: 16,
+            jitter_percent: 0,
+            max_timeout: None,
+            deadline: None,
+            transport: Transport::Udp,
+            insecure: false,
+            software: Some(default_software()),
+            fingerprint: false,
+            credentials: None,
+            local_addr: None,
+            local_port: 0,
+            interface: None,
+            shared_socket: false,
+            dual_stack: false,
+            // Arbitrarily large, this shouldn't realistically be exceeded by UDP
+            recv_buffer: 2048,
+            pcap: None,
+            dns_timeout: Duration::from_secs(5),
+            allow_local: false,
+        }
+    }
+}
+
+/// Returns the default SOFTWARE attribute value: `iplookup <version>`
+pub fn default_software() -> String {
+    format!("iplookup {}", env!("CARGO_PKG_VERSION"))
+}
+
+impl LookupOptions {
+    /// Returns the receive timeout for each attempt, e.g. `base_timeout * 2^attempt` for
+    /// exponential backoff, with up to `jitter_percent` of random jitter applied to each and
+    /// none exceeding `max_timeout`
+    pub fn timeout_schedule(&self) -> Vec<Duration> {
+        self.nominal_schedule()
+            .map(|timeout| self.cap(self.jitter(timeout)))
+            .collect()
+    }
+
+    /// Returns the total time spent waiting across all attempts, without jitter, and limited to
+    /// the `deadline` if any
+    pub fn total_timeout(&self) -> Duration {
+        let total = self.nominal_schedule().sum();
+        match self.deadline {
+            Some(deadline) => deadline.min(total),
+            None => total,
+        }
+    }
+
+    /// Returns the receive timeout for each attempt before jitter is applied
+    fn nominal_schedule(&self) -> impl Iterator<Item = Duration> + '_ {
+        (0..self.retries).map(move |attempt| self.cap(self.backoff.timeout(attempt, self)))
+    }
+
+    /// Limits `timeout` to `max_timeout`, if any
+    fn cap(&self, timeout: Duration) -> Duration {
+        match self.max_timeout {
+            Some(max_timeout) => timeout.min(max_timeout),
+            None => timeout,
+        }
+    }
+
+    /// Scales `timeout` by a random factor within `jitter_percent` of 1
+    fn jitter(&self, timeout: Duration) -> Duration {
+        if self.jitter_percent == 0 {
+            return timeout;
+        }
+        let fraction = f64::from(self.jitter_percent.min(100)) / 100.0;
+        let factor = rand::thread_rng().gen_range(1.0 - fraction..=1.0 + fraction);
+        timeout.mul_f64(factor)
+    }
+
+    /// Returns the local address to send requests to `dest` from: `local_addr` if it's set,
+    /// or else the unspecified address in the family of `dest`, with `local_port`
+    pub fn local_addr_for(&self, dest: &SocketAddr) -> Result<SocketAddr> {
+        let ip = match self.local_addr {
+            Some(ip) if ip.is_ipv4() != dest.is_ipv4() => {
+                return Err(LookupError::InvalidOptions(format!(
+                    "Local address {} can't be used to reach {}",
+                    ip, dest
+                )))
+            }
+            Some(ip) => ip,
+            None if dest.is_ipv4() => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            None => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        Ok(SocketAddr::new(ip, self.local_port))
+    }
+}
+
+/// An IP address family
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    /// Returns the family of `addr`
+    pub fn of(addr: &SocketAddr) -> Family {
+        if addr.is_ipv4() {
+            Family::V4
+        } else {
+            Family::V6
+        }
+    }
+}
+
+impl fmt::Display for Family {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Family::V4 => write!(f, "IPv4"),
+            Family::V6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+/// Resolves a `host:port` endpoint, returning the first address it maps to.
+/// If `family` is provided, addresses in other families are skipped.
+pub fn resolve(endpoint: &str, family: Option<Family>) -> Result<SocketAddr> {
+    Ok(resolve_all(endpoint, family)?.remove(0))
+}
+
+/// Resolves a `host:port` endpoint, returning all of the addresses it maps to in the order
+/// that they should be tried. If `family` is provided, addresses in other families are skipped.
+/// The result is never empty.
+pub fn resolve_all(endpoint: &str, family: Option<Family>) -> Result<Vec<SocketAddr>> {
+    let addrs = endpoint.to_socket_addrs().with_category(|| {
+        LookupError::Resolve(format!("Invalid or unresolvable endpoint: {}", endpoint))
+    })?;
+    filter_family(endpoint, addrs.collect(), family)
+}
+
+/// Returns the resolved `addrs` of `endpoint` which are in `family`, failing if there are none
+pub(crate) fn filter_family(
+    endpoint: &str,
+    addrs: Vec<SocketAddr>,
+    family: Option<Family>,
+) -> Result<Vec<SocketAddr>> {
+    if addrs.is_empty() {
+        return Err(LookupError::Resolve(format!(
+            "Missing addresses in endpoint resolution: {}",
+            endpoint
+        )));
+    }
+    match family {
+        Some(family) => {
+            let addrs: Vec<SocketAddr> = addrs
+                .into_iter()
+                .filter(|addr| Family::of(addr) == family)
+                .collect();
+            if addrs.is_empty() {
+                return Err(LookupError::Resolve(format!(
+                    "No {} addresses in endpoint resolution: {}",
+                    family, endpoint
+                )));
+            }
+            Ok(addrs)
+        }
+        None => Ok(addrs),
+    }
+}
+
+/// Binds a local UDP socket in the same address family as `dest`, on the configured local
+/// address and port if any. An IPv4 socket can't send to an IPv6 destination, and vice versa.
+pub async fn bind_for(dest: &SocketAddr, opts: &LookupOptions) -> Result<UdpSocket> {
+    let local_addr = opts.local_addr_for(dest)?;
+    let socket = bind_std(&local_addr, opts.interface.as_deref())?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket)
+        .with_context(|| format!("Failed to bind local socket {}", local_addr))
+}
+
+/// Like [`bind_for`], but also connects the socket to `dest`, so that the kernel drops any
+/// datagrams which arrive from other addresses. Only for sockets which talk to a single server.
+pub(crate) async fn bind_connected(dest: &SocketAddr, opts: &LookupOptions) -> Result<UdpSocket> {
+    let conn = bind_for(dest, opts).await?;
+    connect(&conn, dest).await?;
+    Ok(conn)
+}
+
+/// Binds a dual-stack UDP socket on `[::]` and the configured local port, which can reach
+/// both IPv6 destinations and IPv4 destinations given as IPv4-mapped addresses. Fails on
+/// platforms without dual-stack sockets, such as OpenBSD, or where IPv6 is disabled.
+pub(crate) async fn bind_dual_stack(opts: &LookupOptions) -> Result<UdpSocket> {
+    let local_addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), opts.local_port);
+    let socket = bind_socket(&local_addr, opts.interface.as_deref(), false)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket)
+        .with_context(|| format!("Failed to bind local socket {}", local_addr))
+}
+
+/// Binds a blocking UDP socket to `local_addr`, and to the network `interface` if any. IPv6
+/// sockets only handle IPv6, so that an IPv4 socket can be bound to the same local port at the
+/// same time.
+pub(crate) fn bind_std(
+    local_addr: &SocketAddr,
+    interface: Option<&str>,
+) -> Result<std::net::UdpSocket> {
+    bind_socket(local_addr, interface, true)
+}
+
+/// Binds a blocking UDP socket to `local_addr`, where IPv6 sockets also handle IPv4 unless
+/// `only_v6` is set
+fn bind_socket(
+    local_addr: &SocketAddr,
+    interface: Option<&str>,
+    only_v6: bool,
+) -> Result<std::net::UdpSocket> {
+    let socket = Socket::new(
+        Domain::for_address(*local_addr),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )
+    .context("Failed to create local socket")?;
+    if local_addr.is_ipv6() {
+        socket
+            .set_only_v6(only_v6)
+            .with_context(|| format!("Failed to set IPV6_V6ONLY to {}", only_v6))?;
+    }
+    if let Some(interface) = interface {
+        bind_device(&socket, interface)?;
+    }
+    if let Err(e) = socket.bind(&(*local_addr).into()) {
+        if e.kind() == ErrorKind::AddrInUse {
+            return Err(LookupError::Bind(format!(
+                "Local address {} is already in use",
+                local_addr
+            )));
+        }
+        return Err(e).with_category(|| {
+            LookupError::Bind(format!("Failed to bind local socket {}", local_addr))
+        });
+    }
+    Ok(socket.into())
+}
+
+/// Restricts `socket` to sending and receiving through the network interface named `interface`
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &Socket, interface: &str) -> Result<()> {
+    socket
+        .bind_device(Some(interface.as_bytes()))
+        .with_category(|| {
+            LookupError::Bind(format!("Failed to bind socket to interface {}", interface))
+        })
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_socket: &Socket, interface: &str) -> Result<()> {
+    Err(LookupError::Unsupported(format!(
+        "Can't bind socket to interface {}: only supported on Linux",
+        interface
+    ))
+    .into())
+}
+
+/// Resolves `endpoint` and queries it using the configured transport, or the transport
+/// implied by its `stun:`/`stuns:` scheme. For UDP, the query is sent from a newly bound
+/// local socket. If the endpoint resolves to several addresses, each is tried in turn until
+/// one of them answers, racing IPv6 against IPv4 for dual-stack endpoints.
+pub async fn lookup_endpoint(
+    endpoint: &str,
+    family: Option<Family>,
+    opts: &LookupOptions,
+) -> Result<Binding> {
+    let addrs = resolve_endpoint_all(endpoint, family, opts).await?;
+    lookup_each(endpoint, &addrs, opts).await
+}
+
+/// Resolves `endpoint` and queries it like [`lookup_endpoint`], for programs which don't use
+/// tokio. Runs the query on a new single-threaded runtime and blocks until it finishes, so this
+/// must not be called from within an async context. Takes the same options as the async API.
+pub fn lookup_blocking(endpoint: &str, opts: &LookupOptions) -> Result<SocketAddr> {
+    Ok(block_on(lookup_endpoint(endpoint, None, opts))??.addr)
+}
+
+/// Runs `future` to completion on a new current-thread runtime
+pub(crate) fn block_on<F: std::future::Future>(future: F) -> Result<F::Output> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to start a tokio runtime")?;
+    Ok(runtime.block_on(future))
+}
+
+/// Queries the resolved `addrs` of `endpoint`, returning the first successful response or the
+/// last error if none of them answer. Addresses within a family are tried in turn, while the
+/// two families are raced against each other.
+async fn lookup_each(
+    endpoint: &str,
+    addrs: &[SocketAddr],
+    opts: &LookupOptions,
+) -> Result<Binding> {
+    eyeballs::race(addrs, |addrs| async move {
+        eyeballs::try_each(&addrs, |dest| async move {
+            lookup_addr(endpoint, &dest, opts).await
+        })
+        .await
+    })
+    .await
+}
+
+/// Queries the resolved `addrs` of a UDP server from newly bound sockets, like
+/// [`lookup_endpoint`]. Returns the socket which received the response along with it, so that
+/// later queries can be sent from the same local address. The socket is connected to the
+/// address which answered, so it has to be reconnected before querying any other address.
+pub async fn lookup_addrs(
+    addrs: &[SocketAddr],
+    opts: &LookupOptions,
+) -> Result<(Binding, UdpSocket)> {
+    eyeballs::race(addrs, |addrs| async move {
+        let conn = bind_for(&addrs[0], opts).await?;
+        let binding = eyeballs::try_each(&addrs, |dest| {
+            let conn = &conn;
+            async move { lookup_owned(conn, &dest, opts).await }
+        })
+        .await?;
+        Ok((binding, conn))
+    })
+    .await
+}
+
+/// Queries `endpoint` at its resolved address `dest`
+async fn lookup_addr(endpoint: &str, dest: &SocketAddr, opts: &LookupOptions) -> Result<Binding> {
+    let dest = *dest;
+    let (transport, endpoint) = parse_endpoint(endpoint, opts.transport);
+    match transport {
+        Transport::Udp => {
+            let conn = bind_for(&dest, opts).await?;
+            lookup_owned(&conn, &dest, opts).await
+        }
+        Transport::Tcp => lookup_tcp(&dest, opts).await,
+        Transport::Tls => lookup_tls(&dest, endpoint_host(endpoint), opts).await,
+        #[cfg(feature = "dtls")]
+        Transport::Dtls => lookup_dtls(&dest, endpoint_host(endpoint), opts).await,
+        #[cfg(not(feature = "dtls"))]
+        Transport::Dtls => Err(LookupError::Unsupported(
+            "DTLS support requires building with the 'dtls' feature".to_string(),
+        )),
+    }
+}
+
+/// Returns the host portion of a `host:port` endpoint, without any IPv6 brackets
+fn endpoint_host(endpoint: &str) -> &str {
+    let host = match endpoint.rsplit_once(':') {
+        Some((host, _port)) => host,
+        None => endpoint,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Sends a binding request to `dest` and returns the public address reported in the response.
+/// ALTERNATE-SERVER redirects are followed using the same socket, so they must stay within the
+/// socket's address family. If `conn` is connected, it must be connected to `dest`, and
+/// redirects elsewhere fail rather than reconnecting it.
+pub async fn lookup(conn: &UdpSocket, dest: &SocketAddr, opts: &LookupOptions) -> Result<Binding> {
+    lookup_from(conn, dest, opts, false).await
+}
+
+/// Like [`lookup`], on a socket which this crate bound itself: it's connected to `dest` so that
+/// the kernel drops datagrams from anywhere else, and reconnected to follow redirects
+pub(crate) async fn lookup_owned(
+    conn: &UdpSocket,
+    dest: &SocketAddr,
+    opts: &LookupOptions,
+) -> Result<Binding> {
+    connect(conn, dest).await?;
+    lookup_from(conn, dest, opts, true).await
+}
+
+/// Connects `conn` to `dest`, replacing any peer it was connected to
+async fn connect(conn: &UdpSocket, dest: &SocketAddr) -> Result<()> {
+    conn.connect(dest)
+        .await
+        .with_context(|| format!("Failed to connect local socket to {}", dest))
+}
+
+/// Runs [`lookup`], reconnecting `conn` when following redirects if it's `owned`
+async fn lookup_from(
+    conn: &UdpSocket,
+    dest: &SocketAddr,
+    opts: &LookupOptions,
+    owned: bool,
+) -> Result<Binding> {
+    let family = Family::of(&conn.local_addr()?);
+    let mut dest = *dest;
+    let mut redirects = 0;
+    loop {
+        match lookup_once(conn, &dest, opts).await? {
+            Outcome::Mapped(binding) => {
+                check_mapped(&binding, &dest, opts)?;
+                return Ok(binding);
+            }
+            Outcome::Redirect(next) => {
+                follow_redirect(&mut redirects, &dest, &next)?;
+                if Family::of(&next) != family {
+                    return Err(LookupError::Redirect(format!(
+                        "Can't follow redirect from {} to {}: local socket is {}",
+                        dest, next, family
+                    )));
+                }
+                if owned {
+                    connect(conn, &next).await?;
+                }
+                dest = next;
+            }
+        }
+    }
+}
+
+/// Sends a binding request to `dest`, answering any authentication challenges
+async fn lookup_once(conn: &UdpSocket, dest: &SocketAddr, opts: &LookupOptions) -> Result<Outcome> {
+    let mut recvbuf = vec![0u8; opts.recv_buffer];
+    let mut challenge = None;
+    for _ in 0..auth::MAX_REQUESTS {
+        let (transaction_id, message_bytes) = build_request(opts, challenge.as_ref())?;
+        let (recvsize, rtt) =
+            recv_with_retries(conn, dest, &message_bytes, &mut recvbuf, opts).await?;
+        match parse_response(
+            transaction_id,
+            &recvbuf[..recvsize],
+            rtt,
+            opts,
+            challenge.as_ref(),
+        )? {
+            Reply::Mapped(binding) => {
+                // The response is known to have arrived from dest
+                check_response_origin(&binding, dest);
+                return Ok(Outcome::Mapped(binding));
+            }
+            Reply::Redirect(next) => return Ok(Outcome::Redirect(next)),
+            Reply::Challenge(next) => challenge = Some(next),
+        }
+    }
+    Err(LookupError::Auth(format!(
+        "Too many authentication challenges from {}",
+        dest
+    )))
+}
+
+/// Maximum number of ALTERNATE-SERVER redirects to follow in a single lookup
+pub(crate) const MAX_REDIRECTS: usize = 3;
+
+/// Decoded response to a single binding request
+pub(crate) enum Reply {
+    /// The public address reported by the server
+    Mapped(Binding),
+    /// The server requires authentication, the request should be resent with credentials
+    Challenge(Challenge),
+    /// The server redirected the request to an ALTERNATE-SERVER
+    Redirect(SocketAddr),
+}
+
+/// Result of querying a single server, after answering any authentication challenges
+pub(crate) enum Outcome {
+    /// The public address reported by the server
+    Mapped(Binding),
+    /// The server redirected the request to an ALTERNATE-SERVER
+    Redirect(SocketAddr),
+}
+
+/// Counts a redirect from `from` to `to`, logging it at debug level. Fails if the redirect
+/// limit has been reached, or if the server redirects to itself.
+pub(crate) fn follow_redirect(
+    redirects: &mut usize,
+    from: &SocketAddr,
+    to: &SocketAddr,
+) -> Result<()> {
+    if from == to {
+        return Err(LookupError::Redirect(format!(
+            "Server {} redirected to itself",
+            from
+        )));
+    }
+    *redirects += 1;
+    if *redirects > MAX_REDIRECTS {
+        return Err(LookupError::Redirect(format!(
+            "Too many redirects: {} redirected to {} after {} hops",
+            from, to, MAX_REDIRECTS
+        )));
+    }
+    debug!("Redirected from {} to ALTERNATE-SERVER {}", from, to);
+    Ok(())
+}
+
+/// Builds an encoded binding request, returning it along with its transaction ID.
+/// If `challenge` is provided, the request is signed with the configured credentials.
+pub(crate) fn build_request(
+    opts: &LookupOptions,
+    challenge: Option<&Challenge>,
+) -> Result<(TransactionId, Vec<u8>)> {
+    build_request_with(opts, challenge, Vec::new())
+}
+
+/// Like `build_request`, with `extra` attributes added to the request ahead of any
+/// credentials and FINGERPRINT
+pub(crate) fn build_request_with(
+    opts: &LookupOptions,
+    challenge: Option<&Challenge>,
+    extra: Vec<Attribute>,
+) -> Result<(TransactionId, Vec<u8>)> {
+    build_message(methods::BINDING, opts, challenge, extra)
+}
+
+/// Like `build_request_with`, for a request with some other `method` than Binding
+pub(crate) fn build_message(
+    method: Method,
+    opts: &LookupOptions,
+    challenge: Option<&Challenge>,
+    extra: Vec<Attribute>,
+) -> Result<(TransactionId, Vec<u8>)> {
+    let mut transaction_id_buf = [0u8; 12];
+    rand::thread_rng()
+        .try_fill(&mut transaction_id_buf)
+        .context("Failed to generate a transaction ID")?;
+    let transaction_id = TransactionId::new(transaction_id_buf);
+    let mut message = Message::<Attribute>::new(MessageClass::Request, method, transaction_id);
+    if let Some(software) = &opts.software {
+        let software = Software::new(software.clone()).with_category(|| {
+            LookupError::InvalidOptions(
+                "Invalid SOFTWARE attribute value, must be under 128 characters".to_string(),
+            )
+        })?;
+        message.add_attribute(Attribute::Software(software));
+    }
+    for attribute in extra {
+        message.add_attribute(attribute);
+    }
+    if let (Some(credentials), Some(challenge)) = (&opts.credentials, challenge) {
+        auth::sign(&mut message, credentials, challenge)?;
+    }
+    // Must be added last, since it covers all attributes before it
+    if opts.fingerprint {
+        let fingerprint = Fingerprint::new(&message).context("Failed to compute FINGERPRINT")?;
+        message.add_attribute(Attribute::Fingerprint(fingerprint));
+    }
+    trace!("Sending: {:#?}", &message);
+    let message_bytes = MessageEncoder::new()
+        .encode_into_bytes(message)
+        .context("Codec error when encoding request")?;
+    hexdump::sent(&message_bytes);
+    Ok((transaction_id, message_bytes))
+}
+
+/// Decodes a binding response, checks that it matches `transaction_id`, and returns the
+/// public address that it contains. `rtt` is how long the response took to arrive, and
+/// `challenge` is the authentication challenge answered by the request, if any.
+pub(crate) fn parse_response(
+    transaction_id: TransactionId,
+    response: &[u8],
+    rtt: Duration,
+    opts: &LookupOptions,
+    challenge: Option<&Challenge>,
+) -> Result<Reply> {
+    let decoded = decode_response(transaction_id, methods::BINDING, response)?;
+
+    if decoded.class() == MessageClass::ErrorResponse {
+        if let Some(next) = auth::challenge(&decoded, opts.credentials.as_ref(), challenge)? {
+            return Ok(Reply::Challenge(next));
+        }
+        let try_alternate = decoded
+            .get_attribute::<ErrorCode>()
+            .is_some_and(|error| error.code() == errors::TryAlternate::CODEPOINT);
+        if try_alternate {
+            if let Some(alternate) = decoded.get_attribute::<AlternateServer>() {
+                return Ok(Reply::Redirect(alternate.address()));
+            }
+        }
+        return Err(error_response(&decoded));
+    } else if let (Some(credentials), Some(challenge)) = (&opts.credentials, challenge) {
+        auth::verify(&decoded, credentials, challenge)?;
+    }
+
+    let addr = mapped_address(&decoded).ok_or_else(|| {
+        LookupError::NoAddress(format!(
+            "No address attribute found in response: {:?}",
+            decoded
+        ))
+    })?;
+    let other_address = other_address(&decoded);
+    if let Some(other) = other_address {
+        debug!("Server's alternate address: {}", other);
+    }
+    let response_origin = decoded
+        .get_attribute::<ResponseOrigin>()
+        .map(|origin| origin.address());
+    if let Some(origin) = response_origin {
+        debug!("Server's response origin: {}", origin);
+    }
+    Ok(Reply::Mapped(Binding {
+        addr,
+        other_address,
+        rtt,
+        response_origin,
+        attributes: attributes::describe(&decoded),
+    }))
+}
+
+/// Warns if the RESPONSE-ORIGIN in `binding` differs from `recvdest`, the address which the
+/// response actually arrived from. This can happen if the server is behind its own NAT.
+pub(crate) fn check_response_origin(binding: &Binding, recvdest: &SocketAddr) {
+    if let Some(origin) = binding.response_origin {
+        if origin != *recvdest {
+            warn!(
+                "Response arrived from {} but the server reports sending it from {}, \
+                 possibly because it's behind a NAT",
+                recvdest, origin
+            );
+        }
+    }
+}
+
+/// Fails if the public address in `binding` isn't meaningful as a public address of this host:
+/// an unspecified, multicast or broadcast address, or a loopback or link-local address reported
+/// by `server` which is neither loopback nor link-local itself. Broken servers and middleboxes
+/// occasionally report these. Loopback and link-local addresses are accepted from anywhere with
+/// `allow_local`.
+pub(crate) fn check_mapped(
+    binding: &Binding,
+    server: &SocketAddr,
+    opts: &LookupOptions,
+) -> Result<()> {
+    let ip = binding.addr.ip().to_canonical();
+    let server_ip = server.ip().to_canonical();
+    let reason = if ip.is_unspecified() {
+        Some("unspecified")
+    } else if ip.is_multicast() {
+        Some("multicast")
+    } else if matches!(ip, IpAddr::V4(ip) if ip.is_broadcast()) {
+        Some("broadcast")
+    } else if opts.allow_local {
+        None
+    } else if ip.is_loopback() && !server_ip.is_loopback() {
+        Some("loopback")
+    } else if is_link_local(ip) && !(is_link_local(server_ip) || server_ip.is_loopback()) {
+        Some("link-local")
+    } else {
+        None
+    };
+    match reason {
+        Some(reason) => Err(LookupError::InvalidAddress(format!(
+            "Server {} reported {} as the public address, which is a {} address",
+            server, binding.addr, reason
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Returns whether `ip` is an IPv4 or IPv6 link-local address
+fn is_link_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_unicast_link_local(),
+    }
+}
+
+/// Decodes a STUN message, checking its FINGERPRINT if any, that it matches `transaction_id`,
+/// and that it's a success or error response to a `method` request
+pub(crate) fn decode_response(
+    transaction_id: TransactionId,
+    method: Method,
+    response: &[u8],
+) -> Result<Message<Attribute>> {
+    hexdump::received(response);
+    // Checked up front for a clearer error, the decoder would otherwise reject a mismatch as a
+    // generic assertion failure
+    check_fingerprint(response)?;
+    let mut decoder = MessageDecoder::<Attribute>::new();
+    let decoded = decoder
+        .decode_from_bytes(response)
+        .with_category(|| LookupError::Decode("Codec error when decoding response".to_string()))?
+        // Would use another .context() call, but BrokenMessage is incompatible.
+        .map_err(|e| {
+            LookupError::Decode(format!("Message error when decoding response: {:?}", e))
+        })?;
+    trace!("Received ({}b): {:#?}", response.len(), decoded);
+
+    // Check that the returned transaction ID matches what we sent
+    if transaction_id != decoded.transaction_id() {
+        return Err(LookupError::TransactionMismatch(format!(
+            "Returned transaction id {:?} doesn't match sent {:?}",
+            decoded.transaction_id(),
+            transaction_id
+        )));
+    }
+
+    // Requests and indications can't answer a request, and neither can other methods' responses
+    match decoded.class() {
+        MessageClass::SuccessResponse | MessageClass::ErrorResponse => {}
+        class => {
+            return Err(LookupError::Decode(format!(
+                "Expected a {} response but got {} {}",
+                method_name(method),
+                article(class),
+                class
+            )))
+        }
+    }
+    if decoded.method() != method {
+        return Err(LookupError::Decode(format!(
+            "Expected a {} response but got {} {} to {}",
+            method_name(method),
+            article(decoded.class()),
+            decoded.class(),
+            method_name(decoded.method())
+        )));
+    }
+
+    // Attributes outside of the known set are decoded as raw values, which are skipped
+    for unknown in decoded.unknown_attributes() {
+        debug!(
+            "Ignoring unknown attribute {:#06x} in response",
+            stun_codec::Attribute::get_type(unknown).as_u16()
+        );
+    }
+
+    Ok(decoded)
+}
+
+/// Returns a readable name for the methods which are sent by this crate
+fn method_name(method: Method) -> String {
+    match method {
+        methods::BINDING => "Binding".to_string(),
+        stun_codec::rfc5766::methods::ALLOCATE => "Allocate".to_string(),
+        stun_codec::rfc5766::methods::REFRESH => "Refresh".to_string(),
+        other => format!("{:#06x}", other.as_u16()),
+    }
+}
+
+/// Returns the indefinite article for `class`
+fn article(class: MessageClass) -> &'static str {
+    match class {
+        MessageClass::Indication | MessageClass::ErrorResponse => "an",
+        MessageClass::Request | MessageClass::SuccessResponse => "a",
+    }
+}
+
+/// Returns the server's alternate address in `decoded` from OTHER-ADDRESS, falling back to the
+/// legacy CHANGED-ADDRESS
+pub(crate) fn other_address(decoded: &Message<Attribute>) -> Option<SocketAddr> {
+    decoded
+        .get_attribute::<OtherAddress>()
+        .map(|other| other.address())
+        .or_else(|| {
+            decoded
+                .get_attribute::<ChangedAddress>()
+                .map(|changed| changed.address())
+        })
+}
+
+/// Describes the ERROR-CODE in an error response
+pub(crate) fn error_response(decoded: &Message<Attribute>) -> LookupError {
+    match decoded.get_attribute::<ErrorCode>() {
+        Some(error) => LookupError::ServerError {
+            code: error.code(),
+            reason: error.reason_phrase().to_string(),
+        },
+        None => LookupError::Decode(format!(
+            "STUN error response without an ERROR-CODE: {:?}",
+            decoded
+        )),
+    }
+}
+
+/// Returns the mapped address in `decoded`, if any. XOR-MAPPED-ADDRESS is preferred over the
+/// plain MAPPED-ADDRESS regardless of their order, since middleboxes commonly rewrite plain
+/// addresses but not XORed ones. Warns if the response contains several mapped addresses which
+/// disagree.
+pub(crate) fn mapped_address(decoded: &Message<Attribute>) -> Option<SocketAddr> {
+    let mapped: Vec<(&str, SocketAddr)> = decoded
+        .attributes()
+        .filter_map(|a| match a {
+            Attribute::MappedAddress(ma) => Some(("MAPPED-ADDRESS", ma.address())),
+            Attribute::XorMappedAddress(ma) => Some(("XOR-MAPPED-ADDRESS", ma.address())),
+            Attribute::XorMappedAddress2(ma) => Some(("XOR-MAPPED-ADDRESS (0x8020)", ma.address())),
+            _ => None,
+        })
+        .collect();
+    let (_name, chosen) = *mapped
+        .iter()
+        .find(|(name, _addr)| name.starts_with("XOR-"))
+        .or_else(|| mapped.first())?;
+    if let Some(warning) = disagreement(&mapped, chosen) {
+        warn!("{}", warning);
+    }
+    Some(chosen)
+}
+
+/// Returns a warning listing the named `mapped` addresses if any of them differ from `chosen`
+fn disagreement(mapped: &[(&str, SocketAddr)], chosen: SocketAddr) -> Option<String> {
+    if mapped.iter().all(|(_name, addr)| *addr == chosen) {
+        return None;
+    }
+    let described: Vec<String> = mapped
+        .iter()
+        .map(|(name, addr)| format!("{} {}", name, addr))
+        .collect();
+    Some(format!(
+        "Mapped addresses in response disagree, possibly due to a middlebox rewriting them: {}",
+        described.join(", ")
+    ))
+}
+
+/// STUN attribute type of FINGERPRINT
+const FINGERPRINT_TYPE: u16 = 0x8028;
+/// Value XORed with the CRC-32 to produce the FINGERPRINT, "STUN" in ASCII
+const FINGERPRINT_XOR: u32 = 0x5354_554e;
+
+/// Verifies the FINGERPRINT attribute if `message` ends with one, returning an error if it
+/// doesn't match the CRC-32 of the preceding bytes. Messages without a fingerprint are accepted.
+fn check_fingerprint(message: &[u8]) -> Result<()> {
+    // Walk the attributes to find the last one: 2 bytes type, 2 bytes length, padded value
+    let mut offset = 20;
+    let mut last = None;
+    while offset + 4 <= message.len() {
+        let attr_type = u16::from_be_bytes([message[offset], message[offset + 1]]);
+        let attr_len = u16::from_be_bytes([message[offset + 2], message[offset + 3]]) as usize;
+        last = Some((offset, attr_type, attr_len));
+        offset += 4 + ((attr_len + 3) & !3);
+    }
+    match last {
+        Some((offset, FINGERPRINT_TYPE, 4)) if offset + 8 == message.len() => {
+            let received = u32::from_be_bytes([
+                message[offset + 4],
+                message[offset + 5],
+                message[offset + 6],
+                message[offset + 7],
+            ]);
+            let expected = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(&message[..offset])
+                ^ FINGERPRINT_XOR;
+            if received != expected {
+                return Err(LookupError::Decode(format!(
+                    "FINGERPRINT mismatch in response: got {:#010x}, expected {:#010x}",
+                    received, expected
+                )));
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Sends `sendbuf` to `dest` and waits for a response from `dest`, resending after each timeout
+/// in [`LookupOptions::timeout_schedule`], which follows the configured `backoff`. Returns the
+/// size of the response written to `recvbuf`, and the time since the most recent send. Gives up
+/// early if the configured `deadline` passes. If `conn` is connected, it must be connected to
+/// `dest`, and then the kernel drops responses from elsewhere.
+pub async fn recv_with_retries(
+    conn: &UdpSocket,
+    dest: &SocketAddr,
+    sendbuf: &[u8],
+    recvbuf: &mut [u8],
+    opts: &LookupOptions,
+) -> Result<(usize, Duration)> {
+    if opts.retries == 0 {
+        return Err(LookupError::InvalidOptions(
+            "Retries must be at least 1".to_string(),
+        ));
+    }
+    within_deadline(
+        dest,
+        opts,
+        send_with_retries(conn, dest, sendbuf, recvbuf, opts),
+    )
+    .await
+}
+
+/// Runs `attempts` to completion, or fails with a timeout waiting for `dest` if the configured
+/// `deadline` passes first
+pub(crate) async fn within_deadline<T>(
+    dest: &SocketAddr,
+    opts: &LookupOptions,
+    attempts: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    match opts.deadline {
+        Some(deadline) => match time::timeout(deadline, attempts).await {
+            Ok(result) => result,
+            Err(_elapsed) => Err(LookupError::Timeout(format!(
+                "Deadline of {}ms exceeded waiting for response from {:?}",
+                deadline.as_millis(),
+                dest
+            ))),
+        },
+        None => attempts.await,
+    }
+}
+
+/// Former name of [`recv_with_retries`], from before the backoff became configurable
+#[deprecated(note = "renamed to recv_with_retries")]
+pub async fn recv_exponential_backoff(
+    conn: &UdpSocket,
+    dest: &SocketAddr,
+    sendbuf: &[u8],
+    recvbuf: &mut [u8],
+    opts: &LookupOptions,
+) -> Result<(usize, Duration)> {
+    recv_with_retries(conn, dest, sendbuf, recvbuf, opts).await
+}
+
+/// Fails if a `recvsize` byte response from `origin` filled the whole of `recvbuf`, in which
+/// case any remainder of the datagram was discarded
+pub(crate) fn check_truncation(recvsize: usize, recvbuf: &[u8], origin: &SocketAddr) -> Result<()> {
+    if recvsize >= recvbuf.len() {
+        return Err(LookupError::Decode(format!(
+            "Response from {} filled the whole {}-byte receive buffer, so it was possibly \
+             truncated",
+            origin,
+            recvbuf.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Receives a datagram on `conn`, which is connected to `dest`. An ICMP port unreachable from
+/// `dest` surfaces as a refused connection, which is treated like no response at all so that
+/// the retry schedule still applies.
+async fn recv_connected(
+    conn: &UdpSocket,
+    dest: &SocketAddr,
+    recvbuf: &mut [u8],
+) -> std::io::Result<usize> {
+    loop {
+        match conn.recv(recvbuf).await {
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                debug!("{} is unreachable, waiting for a response anyway", dest);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Runs the retry loop for [`recv_with_retries`], across the whole timeout schedule
+async fn send_with_retries(
+    conn: &UdpSocket,
+    dest: &SocketAddr,
+    sendbuf: &[u8],
+    recvbuf: &mut [u8],
+    opts: &LookupOptions,
+) -> Result<(usize, Duration)> {
+    let schedule = opts.timeout_schedule();
+    debug!(
+        "Timeout schedule: {:?} (total wait: {:?})",
+        schedule,
+        schedule.iter().sum::<Duration>()
+    );
+    // A socket connected to dest only receives from it, leaving the origin check below to
+    // shared sockets which talk to several servers
+    let connected = match conn.peer_addr() {
+        Ok(peer) if peer == *dest => true,
+        Ok(peer) => {
+            return Err(LookupError::InvalidOptions(format!(
+                "Local socket is connected to {}, so it can't query {}",
+                peer, dest
+            )))
+        }
+        Err(_) => false,
+    };
+    for (attempt, timeout) in schedule.iter().enumerate() {
+        // (Re)send request. UDP sends shouldn't time out but just in case...
+        let sent = Instant::now();
+        let send = async {
+            if connected {
+                conn.send(sendbuf).await
+            } else {
+                conn.send_to(sendbuf, dest).await
+            }
+        };
+        let _sendsize = time::timeout(Duration::from_millis(1000), send)
+            .await
+            .map_err(|_elapsed| {
+                LookupError::Timeout(format!("Timed out sending STUN request to {}", dest))
+            })??;
+        pcap::sent(opts.pcap.as_deref(), conn, dest, sendbuf);
+
+        let timeout_ms = timeout.as_millis();
+        let deadline = time::Instant::now() + *timeout;
+        // Keep listening until the timeout, since the response may be right behind a stray one
+        loop {
+            let recv = async {
+                if connected {
+                    recv_connected(conn, dest, recvbuf)
+                        .await
+                        .map(|size| (size, *dest))
+                } else {
+                    conn.recv_from(recvbuf).await
+                }
+            };
+            match time::timeout_at(deadline, recv).await {
+                Ok(Ok((recvsize, recvdest))) => {
+                    pcap::received(opts.pcap.as_deref(), conn, &recvdest, &recvbuf[..recvsize]);
+                    // Before returning, check that the response is from who we're waiting for
+                    if *dest == recvdest {
+                        check_truncation(recvsize, recvbuf, dest)?;
+                        return Ok((recvsize, sent.elapsed()));
+                    }
+                    // If it doesn't match, discard it and resume waiting
+                    warn!(
+                        "Response origin {:?} doesn't match request target {:?}",
+                        recvdest, dest
+                    );
+                }
+                Ok(Err(e)) => {
+                    // A receive error occurred, give up
+                    return Err(e)
+                        .with_context(|| format!("Failed to receive STUN response from {}", dest));
+                }
+                Err(_elapsed) => {
+                    // Timeout occurred, try again (or exit loop)
+                    if attempt + 1 == schedule.len() {
+                        warn!("Timed out after {}ms, giving up.", timeout_ms);
+                    } else {
+                        warn!("Timed out after {}ms, trying {} again...", timeout_ms, dest);
+                    }
+                    break;
+                }
+            }
+        }
+    }
+    Err(LookupError::Timeout(format!(
+        "Retries exhausted after {} attempts waiting for response from {:?}",
+        schedule.len(),
+        dest
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stun_codec::rfc5389::attributes::{MappedAddress, XorMappedAddress};
+
+    #[tokio::test]
+    async fn bind_ipv4_for_ipv4_endpoint() {
+        let dest = resolve("127.0.0.1:3478", None).unwrap();
+        assert!(dest.is_ipv4());
+        let conn = bind_for(&dest, &LookupOptions::default()).await.unwrap();
+        assert!(conn.local_addr().unwrap().is_ipv4());
+    }
+
+    #[tokio::test]
+    async fn bind_ipv6_for_ipv6_endpoint() {
+        let dest = resolve("[::1]:3478", None).unwrap();
+        assert!(dest.is_ipv6());
+        let conn = bind_for(&dest, &LookupOptions::default()).await.unwrap();
+        assert!(conn.local_addr().unwrap().is_ipv6());
+    }
+
+    #[test]
+    fn resolve_filters_family() {
+        assert!(resolve("127.0.0.1:3478", Some(Family::V4)).is_ok());
+        assert!(resolve("127.0.0.1:3478", Some(Family::V6)).is_err());
+        assert!(resolve("[::1]:3478", Some(Family::V6)).is_ok());
+        assert!(resolve("[::1]:3478", Some(Family::V4)).is_err());
+    }
+
+    #[test]
+    fn constant_backoff_keeps_timeout() {
+        let opts = LookupOptions {
+            retries: 3,
+            base_timeout: Duration::from_millis(500),
+            backoff: Backoff::Constant,
+            ..LookupOptions::default()
+        };
+        assert_eq!(opts.timeout_schedule(), vec![Duration::from_millis(500); 3]);
+        assert_eq!(opts.total_timeout(), Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn linear_backoff_adds_base_timeout() {
+        let ms = |ms| Duration::from_millis(ms);
+        let opts = LookupOptions {
+            retries: 4,
+            base_timeout: ms(500),
+            backoff: Backoff::Linear,
+            ..LookupOptions::default()
+        };
+        assert_eq!(
+            opts.timeout_schedule(),
+            vec![ms(500), ms(1000), ms(1500), ms(2000)]
+        );
+        assert_eq!(opts.total_timeout(), ms(5000));
+    }
+
+    #[test]
+    fn max_timeout_caps_schedule() {
+        let ms = |ms| Duration::from_millis(ms);
+        let opts = LookupOptions {
+            retries: 5,
+            backoff: Backoff::Exponential,
+            max_timeout: Some(ms(3000)),
+            ..LookupOptions::default()
+        };
+        assert_eq!(
+            opts.timeout_schedule(),
+            vec![ms(500), ms(1000), ms(2000), ms(3000), ms(3000)]
+        );
+        assert_eq!(opts.total_timeout(), ms(9500));
+
+        // Jitter can shorten a capped timeout but never lengthen it past the cap
+        let opts = LookupOptions {
+            jitter_percent: 50,
+            ..opts
+        };
+        for _ in 0..100 {
+            let schedule = opts.timeout_schedule();
+            assert!(schedule.iter().all(|timeout| *timeout <= ms(3000)));
+            assert!(schedule[4] >= ms(1500));
+        }
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let opts = LookupOptions {
+            jitter_percent: 20,
+            ..LookupOptions::default()
+        };
+        for _ in 0..100 {
+            for (timeout, nominal) in opts.timeout_schedule().iter().zip(opts.nominal_schedule()) {
+                assert!(*timeout >= nominal.mul_f64(0.8) && *timeout <= nominal.mul_f64(1.2));
+            }
+        }
+        assert_eq!(opts.total_timeout(), Duration::from_millis(39500));
+    }
+
+    #[test]
+    fn rfc5389_backoff_waits_rm_at_end() {
+        let ms = |ms| Duration::from_millis(ms);
+        let opts = LookupOptions::default();
+        assert_eq!(
+            opts.timeout_schedule(),
+            vec![
+                ms(500),
+                ms(1000),
+                ms(2000),
+                ms(4000),
+                ms(8000),
+                ms(16000),
+                ms(8000)
+            ]
+        );
+
+        let opts = LookupOptions {
+            backoff: Backoff::Exponential,
+            ..opts
+        };
+        assert_eq!(opts.timeout_schedule().last(), Some(&ms(32000)));
+    }
+
+    #[tokio::test]
+    async fn deadline_cuts_retries_short() {
+        let conn = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        // Nothing listens on this address once its socket is dropped
+        let dead = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut recvbuf = [0u8; 16];
+        let opts = LookupOptions {
+            retries: 3,
+            base_timeout: Duration::from_millis(50),
+            final_timeout_factor: 4,
+            ..LookupOptions::default()
+        };
+        let err = recv_with_retries(&conn, &dead, b"ping", &mut recvbuf, &opts)
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Retries exhausted after 3 attempts"));
+
+        let opts = LookupOptions {
+            deadline: Some(Duration::from_millis(100)),
+            ..opts
+        };
+        let err = recv_with_retries(&conn, &dead, b"ping", &mut recvbuf, &opts)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().starts_with("Deadline of 100ms exceeded"));
+        assert!(matches!(err, LookupError::Timeout(_)));
+    }
+
+    /// Answers a single request to `server` with a stray datagram from another socket, followed
+    /// by the real response
+    fn spawn_stray_responder(server: std::net::UdpSocket) -> std::thread::JoinHandle<()> {
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 16];
+            let (_size, client) = server.recv_from(&mut buf).unwrap();
+            let stray = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            stray.send_to(b"stray", client).unwrap();
+            server.send_to(b"pong", client).unwrap();
+        })
+    }
+
+    /// A single attempt, so the response must arrive in the same attempt as any stray datagram
+    fn single_attempt() -> LookupOptions {
+        LookupOptions {
+            retries: 1,
+            base_timeout: Duration::from_millis(1000),
+            final_timeout_factor: 1,
+            ..LookupOptions::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn connected_socket_drops_other_sources() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let dest = server.local_addr().unwrap();
+        let conn = bind_connected(&dest, &LookupOptions::default())
+            .await
+            .unwrap();
+        let responder = spawn_stray_responder(server);
+        let mut recvbuf = [0u8; 16];
+        let (recvsize, _rtt) =
+            recv_with_retries(&conn, &dest, b"ping", &mut recvbuf, &single_attempt())
+                .await
+                .unwrap();
+        responder.join().unwrap();
+        assert_eq!(&recvbuf[..recvsize], b"pong");
+        // The kernel dropped the stray datagram, so there's nothing left to receive
+        assert!(conn.try_recv_from(&mut recvbuf).is_err());
+    }
+
+    #[tokio::test]
+    async fn unconnected_socket_skips_stray_response() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let dest = server.local_addr().unwrap();
+        let conn = bind_for(&dest, &LookupOptions::default()).await.unwrap();
+        let responder = spawn_stray_responder(server);
+        let mut recvbuf = [0u8; 16];
+        let (recvsize, _rtt) =
+            recv_with_retries(&conn, &dest, b"ping", &mut recvbuf, &single_attempt())
+                .await
+                .unwrap();
+        responder.join().unwrap();
+        assert_eq!(&recvbuf[..recvsize], b"pong");
+    }
+
+    #[tokio::test]
+    async fn lookup_each_skips_dead_address() {
+        let dead = mock::dead_addr();
+        let server = mock::MockServer::spawn().await;
+        let live = server.addr;
+        let opts = LookupOptions {
+            retries: 1,
+            base_timeout: Duration::from_millis(100),
+            final_timeout_factor: 1,
+            ..LookupOptions::default()
+        };
+        let binding = lookup_each("localhost", &[dead, live], &opts)
+            .await
+            .unwrap();
+        assert!(binding.addr.ip().is_loopback());
+
+        let err = lookup_each("localhost", &[dead], &opts).await.unwrap_err();
+        assert!(matches!(err, LookupError::Timeout(_)));
+    }
+
+    /// Returns the reason that `check_mapped` rejects `mapped` from `server`, if it does
+    fn rejection(mapped: &str, server: &str, allow_local: bool) -> Option<String> {
+        let binding = Binding {
+            addr: mapped.parse().unwrap(),
+            other_address: None,
+            rtt: Duration::ZERO,
+            response_origin: None,
+            attributes: Vec::new(),
+        };
+        let opts = LookupOptions {
+            allow_local,
+            ..LookupOptions::default()
+        };
+        check_mapped(&binding, &server.parse().unwrap(), &opts)
+            .err()
+            .map(|err| {
+                assert!(matches!(err, LookupError::InvalidAddress(_)));
+                err.to_string()
+            })
+    }
+
+    #[test]
+    fn rejects_meaningless_public_addresses() {
+        let server = "198.51.100.1:3478";
+        assert_eq!(rejection("203.0.113.5:4000", server, false), None);
+        // Private addresses are legitimate behind a carrier-grade or double NAT
+        assert_eq!(rejection("10.1.2.3:4000", server, false), None);
+
+        for (mapped, category) in [
+            ("0.0.0.0:4000", "unspecified"),
+            ("[::]:4000", "unspecified"),
+            ("224.0.0.1:4000", "multicast"),
+            ("[ff02::1]:4000", "multicast"),
+            ("255.255.255.255:4000", "broadcast"),
+            ("127.0.0.1:4000", "loopback"),
+            ("[::1]:4000", "loopback"),
+            ("[::ffff:127.0.0.1]:4000", "loopback"),
+            ("169.254.1.2:4000", "link-local"),
+            ("[fe80::1]:4000", "link-local"),
+        ] {
+            assert_eq!(
+                rejection(mapped, server, false),
+                Some(format!(
+                    "Server {} reported {} as the public address, which is a {} address",
+                    server, mapped, category
+                ))
+            );
+        }
+
+        // Local addresses are expected from local servers, or when they're allowed
+        assert_eq!(rejection("127.0.0.1:4000", "127.0.0.1:3478", false), None);
+        assert_eq!(rejection("[fe80::1]:4000", "[fe80::2]:3478", false), None);
+        assert_eq!(rejection("127.0.0.1:4000", server, true), None);
+        assert_eq!(rejection("[fe80::1]:4000", server, true), None);
+        assert!(rejection("0.0.0.0:4000", "127.0.0.1:3478", true).is_some());
+    }
+
+    #[test]
+    fn error_response_reports_error_code() {
+        let transaction_id = TransactionId::new([7u8; 12]);
+        let mut message = Message::<Attribute>::new(
+            MessageClass::ErrorResponse,
+            methods::BINDING,
+            transaction_id,
+        );
+        message.add_attribute(Attribute::ErrorCode(
+            ErrorCode::new(400, "Bad Request".to_string()).unwrap(),
+        ));
+        let response = MessageEncoder::new().encode_into_bytes(message).unwrap();
+
+        let opts = LookupOptions::default();
+        let err = parse_response(transaction_id, &response, Duration::ZERO, &opts, None)
+            .err()
+            .expect("error response should fail");
+        assert_eq!(err.to_string(), "STUN error 400: Bad Request");
+        assert!(matches!(err, LookupError::ServerError { code: 400, .. }));
+    }
+
+    /// Encodes a message of `class` and `method` carrying a mapped address, and returns the
+    /// error from parsing it as a Binding response
+    fn parse_error(class: MessageClass, method: Method) -> String {
+        let transaction_id = TransactionId::new([7u8; 12]);
+        let mapped: SocketAddr = "203.0.113.5:4000".parse().unwrap();
+        let mut message = Message::<Attribute>::new(class, method, transaction_id);
+        message.add_attribute(Attribute::XorMappedAddress(XorMappedAddress::new(mapped)));
+        let response = MessageEncoder::new().encode_into_bytes(message).unwrap();
+
+        let opts = LookupOptions::default();
+        let err = parse_response(transaction_id, &response, Duration::ZERO, &opts, None)
+            .err()
+            .expect("unexpected message should fail");
+        assert!(matches!(err, LookupError::Decode(_)));
+        err.to_string()
+    }
+
+    #[test]
+    fn indication_is_rejected() {
+        assert_eq!(
+            parse_error(MessageClass::Indication, methods::BINDING),
+            "Expected a Binding response but got an indication"
+        );
+    }
+
+    #[test]
+    fn request_is_rejected() {
+        assert_eq!(
+            parse_error(MessageClass::Request, methods::BINDING),
+            "Expected a Binding response but got a request"
+        );
+    }
+
+    #[test]
+    fn other_method_is_rejected() {
+        assert_eq!(
+            parse_error(
+                MessageClass::SuccessResponse,
+                stun_codec::rfc5766::methods::ALLOCATE
+            ),
+            "Expected a Binding response but got a success response to Allocate"
+        );
+        assert_eq!(
+            parse_error(
+                MessageClass::ErrorResponse,
+                stun_codec::rfc5766::methods::REFRESH
+            ),
+            "Expected a Binding response but got an error response to Refresh"
+        );
+    }
+
+    #[test]
+    fn unknown_optional_attributes_are_skipped() {
+        let transaction_id = TransactionId::new([7u8; 12]);
+        let mapped: SocketAddr = "203.0.113.5:4000".parse().unwrap();
+        let mut message = Message::<Attribute>::new(
+            MessageClass::SuccessResponse,
+            methods::BINDING,
+            transaction_id,
+        );
+        message.add_attribute(Attribute::XorMappedAddress(XorMappedAddress::new(mapped)));
+        let mut response = MessageEncoder::new().encode_into_bytes(message).unwrap();
+
+        // Insert a comprehension-optional vendor attribute (0x8070) ahead of XOR-MAPPED-ADDRESS,
+        // padded to a multiple of 4 bytes, and bump the message length to match
+        let vendor = [
+            0x80, 0x70, 0x00, 0x05, b'h', b'e', b'l', b'l', b'o', 0, 0, 0,
+        ];
+        response.splice(20..20, vendor);
+        let length = u16::from_be_bytes([response[2], response[3]]) + vendor.len() as u16;
+        response[2..4].copy_from_slice(&length.to_be_bytes());
+
+        let opts = LookupOptions::default();
+        match parse_response(transaction_id, &response, Duration::ZERO, &opts, None) {
+            Ok(Reply::Mapped(binding)) => assert_eq!(binding.addr, mapped),
+            Ok(_) => panic!("expected a mapped address"),
+            Err(e) => panic!("unknown attribute wasn't skipped: {:#}", e),
+        }
+    }
+
+    #[test]
+    fn disagreeing_mapped_addresses_still_return_one() {
+        let plain: SocketAddr = "192.168.1.10:4000".parse().unwrap();
+        let xor: SocketAddr = "203.0.113.5:4000".parse().unwrap();
+        let mut message = Message::<Attribute>::new(
+            MessageClass::SuccessResponse,
+            methods::BINDING,
+            TransactionId::new([7u8; 12]),
+        );
+        message.add_attribute(Attribute::MappedAddress(MappedAddress::new(plain)));
+        message.add_attribute(Attribute::XorMappedAddress(XorMappedAddress::new(xor)));
+        assert_eq!(mapped_address(&message), Some(xor));
+
+        let mapped = [("MAPPED-ADDRESS", plain), ("XOR-MAPPED-ADDRESS", xor)];
+        assert_eq!(
+            disagreement(&mapped, xor).as_deref(),
+            Some(
+                "Mapped addresses in response disagree, possibly due to a middlebox rewriting \
+                 them: MAPPED-ADDRESS 192.168.1.10:4000, XOR-MAPPED-ADDRESS 203.0.113.5:4000"
+            )
+        );
+
+        // Matching addresses are expected, and aren't worth a warning
+        let mapped = [("MAPPED-ADDRESS", xor), ("XOR-MAPPED-ADDRESS", xor)];
+        assert_eq!(disagreement(&mapped, xor), None);
+    }
+
+    #[test]
+    fn xor_mapped_address_is_preferred() {
+        let plain: SocketAddr = "192.168.1.10:4000".parse().unwrap();
+        let xor: SocketAddr = "203.0.113.5:4000".parse().unwrap();
+        let mut message = Message::<Attribute>::new(
+            MessageClass::SuccessResponse,
+            methods::BINDING,
+            TransactionId::new([7u8; 12]),
+        );
+        message.add_attribute(Attribute::MappedAddress(MappedAddress::new(plain)));
+        message.add_attribute(Attribute::XorMappedAddress(XorMappedAddress::new(xor)));
+        assert_eq!(mapped_address(&message), Some(xor));
+
+        // Without an XORed address, fall back to the plain one
+        let mut message = Message::<Attribute>::new(
+            MessageClass::SuccessResponse,
+            methods::BINDING,
+            TransactionId::new([7u8; 12]),
+        );
+        message.add_attribute(Attribute::MappedAddress(MappedAddress::new(plain)));
+        assert_eq!(mapped_address(&message), Some(plain));
+    }
+
+    #[test]
+    fn response_origin_is_captured() {
+        let transaction_id = TransactionId::new([7u8; 12]);
+        let mapped: SocketAddr = "203.0.113.5:4000".parse().unwrap();
+        let origin: SocketAddr = "10.0.0.1:3478".parse().unwrap();
+        let mut message = Message::<Attribute>::new(
+            MessageClass::SuccessResponse,
+            methods::BINDING,
+            transaction_id,
+        );
+        message.add_attribute(Attribute::XorMappedAddress(XorMappedAddress::new(mapped)));
+        message.add_attribute(Attribute::ResponseOrigin(ResponseOrigin::new(origin)));
+        let response = MessageEncoder::new().encode_into_bytes(message).unwrap();
+
+        let opts = LookupOptions::default();
+        match parse_response(transaction_id, &response, Duration::ZERO, &opts, None) {
+            Ok(Reply::Mapped(binding)) => {
+                assert_eq!(binding.addr, mapped);
+                assert_eq!(binding.response_origin, Some(origin));
+            }
+            Ok(_) => panic!("expected a mapped address"),
+            Err(e) => panic!("failed to parse response: {:#}", e),
+        }
+    }
+}
@@ -18,162 +18,1488 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use anyhow::{anyhow, bail, Context, Result};
-use bytecodec::{DecodeExt, EncodeExt};
-use rand::Rng;
+mod cli;
+
+use ::syslog::Facility;
+use anyhow::{bail, Context, Result};
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::error::ErrorKind;
+use clap::{ArgAction, ArgGroup, CommandFactory, Parser};
+use cli::batch;
+use cli::cloudflare::Cloudflare;
+use cli::count;
+use cli::duckdns::DuckDns;
+use cli::hosts::HostsFile;
+use cli::keepalive;
+use cli::local;
+use cli::metrics::Metrics;
+#[cfg(feature = "mqtt")]
+use cli::mqtt::{self, Mqtt};
+use cli::noip::NoIp;
+use cli::nsupdate::{self, Nsupdate};
+use cli::output::{self, Clock, EnvOutput, Format, OutputFile, Template};
+use cli::query::Query;
+use cli::route53::Route53;
+use cli::servers;
+use cli::state::State;
+use cli::syslog::{self, Syslog};
+use cli::watch::Watch;
+use cli::webhook::{self, Webhook};
+use hickory_proto::rr::dnssec::rdata::tsig::TsigAlgorithm;
+use iplookup::{
+    allocate_turn, check_symmetric, default_software, detect_filtering, detect_mapping, detect_nat,
+    resolve_endpoint_all, test_hairpin, Backoff, Credentials, Family, LookupClient, LookupError,
+    PcapWriter, Transport, DEFAULT_SERVERS,
+};
+use log::{debug, error, warn, LevelFilter};
+use reqwest::header::{HeaderName, HeaderValue};
+use reqwest::Method;
+#[cfg(feature = "mqtt")]
+use rumqttc::QoS;
 use std::env;
-use std::io::ErrorKind;
-use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
 use std::time::Duration;
-use stun_codec::rfc5389::{methods, Attribute};
-use stun_codec::{Message, MessageClass, MessageDecoder, MessageEncoder, TransactionId};
-
-fn print_syntax() {
-    eprintln!(
-        "Syntax: {} <host:port>\nEnv: DEBUG: enabled with any non-empty value",
-        env::args().nth(0).unwrap()
-    );
+
+/// Queries a STUN server for your public IP
+#[derive(Debug, Parser)]
+#[command(version, about)]
+#[command(after_help = "Exit codes:
+  0   Success
+  1   Other failure
+  2   Invalid arguments
+  3   The server couldn't be resolved
+  4   No response from the server after all retries
+  5   The server's response couldn't be decoded
+  6   With --check-ipv6, the public address isn't a global IPv6 address
+  7   The server answered with an error, rejected the credentials or sent bad redirects
+  8   The local socket couldn't be bound
+  9   Several servers failed, or too few of them agreed for --consensus
+  10  With --compare, the public IP changed")]
+#[command(group(ArgGroup::new("encrypted").args(["tls", "dtls"])))]
+#[command(group(ArgGroup::new("recorded").args(["state_file", "compare"]).multiple(true)))]
+// Each mode replaces the plain lookup with something else, so at most one can be given
+#[command(group(ArgGroup::new("mode").multiple(false).args([
+    "batch",
+    "resolve_only",
+    "both",
+    "watch",
+    "keepalive",
+    "count",
+    "nat_type",
+    "mapping_behavior",
+    "filtering_behavior",
+    "symmetric_check",
+    "hairpin_test",
+    "turn",
+])))]
+// Modes which probe the server for something other than the public IP
+#[command(group(ArgGroup::new("probe").multiple(true).args([
+    "nat_type",
+    "mapping_behavior",
+    "filtering_behavior",
+    "symmetric_check",
+    "hairpin_test",
+    "turn",
+])))]
+// Places the public IP is recorded or published, by a plain lookup or --watch
+#[command(group(ArgGroup::new("report").multiple(true).args([
+    "compare",
+    "state_file",
+    "metrics_file",
+    "output_file",
+    "cloudflare_zone",
+    "nsupdate",
+    "route53_zone",
+    "duckdns",
+    "noip",
+    "update_hosts",
+]).conflicts_with_all(["batch", "resolve_only", "both", "keepalive", "count", "probe"])))]
+// Extra checks which only apply to a plain lookup
+#[command(group(ArgGroup::new("one_shot").multiple(true).args([
+    "check_ipv6",
+    "round_robin",
+    "detect_nat",
+    "show_local",
+]).conflicts_with("mode")))]
+// Structured output, which modes printing a running summary can't use
+#[command(group(ArgGroup::new("structured").multiple(true).args(["json", "yaml"])
+    .conflicts_with_all(["keepalive", "count"])))]
+// Ways of finding the public IP, for modes which look it up
+#[command(group(ArgGroup::new("lookup").multiple(true).args(["consensus", "http_fallback", "verify_http"])
+    .conflicts_with_all(["batch", "resolve_only", "both", "keepalive", "probe"])))]
+#[command(group(ArgGroup::new("transport").multiple(true).args(["tcp", "tls", "dtls"])
+    .conflicts_with_all(["keepalive", "probe"])))]
+// Formats for the public IP, which modes printing something else can't use
+#[command(group(ArgGroup::new("ip_format").multiple(true).args(["with_port", "format", "env_output"])
+    .conflicts_with_all([
+        "resolve_only",
+        "keepalive",
+        "count",
+        "nat_type",
+        "mapping_behavior",
+        "filtering_behavior",
+        "symmetric_check",
+        "hairpin_test",
+    ])))]
+struct Args {
+    /// STUN server(s) to query. If multiple servers are listed, they are queried concurrently
+    /// and the first successful response is used. If the port is omitted from a domain, its
+    /// STUN SRV records are used, falling back to the default port (3478, or 5349 for TLS).
+    /// Servers may also be given as stun: or stuns: URIs, where stuns: implies --tls.
+    /// If none are listed, the server in the IPLOOKUP_SERVER envvar is queried.
+    /// A '-' reads more servers from stdin, one per line.
+    #[arg(value_name = "host[:port]")]
+    endpoints: Vec<String>,
+
+    /// Also query the servers listed in PATH, one per line, or in stdin if PATH is '-'.
+    /// Blank lines and anything following a '#' are ignored.
+    #[arg(long, value_name = "PATH")]
+    server_file: Option<PathBuf>,
+
+    /// Survey the servers listed in PATH, one per line, or in stdin if PATH is '-', printing the
+    /// result or error for each and a summary of how many answered. Blank lines and anything
+    /// following a '#' are ignored, and malformed lines are reported and skipped.
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["endpoints", "server_file", "use_defaults"]
+    )]
+    batch: Option<PathBuf>,
+
+    /// Print the addresses which the servers resolve to, including through SRV records, and
+    /// exit without sending any requests
+    #[arg(long)]
+    resolve_only: bool,
+
+    /// Maximum number of servers to query at a time in --batch mode
+    #[arg(long, value_name = "N", default_value_t = 10, requires = "batch", value_parser = clap::value_parser!(u16).range(1..))]
+    concurrency: u16,
+
+    /// If no servers are listed, race a built-in list of public STUN servers
+    #[arg(long)]
+    use_defaults: bool,
+
+    /// Query each server in turn and only succeed if enough of them agree on the public IP
+    #[arg(long)]
+    consensus: bool,
+
+    /// Number of servers which must agree in --consensus mode [default: a majority]
+    #[arg(long, value_name = "N", requires = "consensus")]
+    quorum: Option<usize>,
+
+    /// Only use IPv4 addresses when resolving the endpoint
+    #[arg(short = '4', conflicts_with = "ipv6")]
+    ipv4: bool,
+
+    /// Only use IPv6 addresses when resolving the endpoint
+    #[arg(short = '6')]
+    ipv6: bool,
+
+    /// Check for working IPv6 connectivity: query over IPv6 only, and exit with 6 unless the
+    /// public address is a global unicast address rather than e.g. link-local or unique local
+    #[arg(long, conflicts_with_all = ["ipv4"])]
+    check_ipv6: bool,
+
+    /// Look up both the public IPv4 and IPv6 addresses, querying the server(s) once over each
+    /// family, and print each result prefixed with its family. Succeeds if either is found.
+    #[arg(long, conflicts_with_all = ["ipv4", "ipv6", "local_addr"])]
+    both: bool,
+
+    /// Query the server over TCP instead of UDP.
+    /// The request is sent once and given the total wait time of the retry schedule.
+    #[arg(long)]
+    tcp: bool,
+
+    /// Query the server over TLS (STUNS), which typically uses port 5349.
+    /// The server's certificate is verified against the system root store.
+    #[arg(long, conflicts_with = "tcp")]
+    tls: bool,
+
+    /// Query the server over DTLS, performing a handshake before sending the request.
+    /// The server's certificate is verified against the system root store.
+    /// Requires building with the 'dtls' feature.
+    #[arg(long, conflicts_with_all = ["tcp", "tls"])]
+    dtls: bool,
+
+    /// Accept any certificate from the server in --tls or --dtls mode, e.g. for self-signed
+    /// test servers
+    #[arg(long, requires = "encrypted")]
+    insecure: bool,
+
+    /// Send UDP and DTLS requests from local port N instead of an ephemeral port, e.g. for
+    /// firewalls which only allow STUN from a fixed port. Several UDP servers are queried
+    /// over a single shared socket, as with --shared-socket.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    local_port: Option<u16>,
+
+    /// Send UDP and DTLS requests through network interface NAME, regardless of the routing
+    /// table, so that the public address reflects that uplink. Only supported on Linux.
+    #[arg(long, value_name = "NAME")]
+    interface: Option<String>,
+
+    /// Query several UDP servers from a single local socket per address family, matching
+    /// responses to servers by transaction ID, instead of binding a socket for each server.
+    /// Servers which require authentication or redirect elsewhere aren't supported.
+    #[arg(long)]
+    shared_socket: bool,
+
+    /// Like --shared-socket, but query servers of both families from a single dual-stack
+    /// [::] socket, reaching IPv4 servers through IPv4-mapped addresses. Falls back to a
+    /// socket per family where dual-stack sockets aren't supported.
+    #[arg(long, conflicts_with_all = ["ipv4", "ipv6", "local_addr"])]
+    dual_stack: bool,
+
+    /// Send UDP and DTLS requests from local IP ADDR, which also limits the server's addresses
+    /// to the same family
+    #[arg(long, value_name = "ADDR")]
+    local_addr: Option<IpAddr>,
+
+    /// Number of times to send the request before giving up, Rc in RFC 5389
+    /// [default: 7, or 5 with --simple-backoff]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    retries: Option<u32>,
+
+    /// Timeout for the first attempt in milliseconds, which grows on each retry according to
+    /// --backoff. This is the initial RTO in RFC 5389.
+    /// [default: 500, or 1000 with --simple-backoff]
+    #[arg(long, value_name = "MS")]
+    base_timeout: Option<u64>,
+
+    /// After the last attempt, wait N times --base-timeout for a response before giving up,
+    /// Rm in RFC 5389
+    #[arg(long, value_name = "N", default_value_t = 16, value_parser = clap::value_parser!(u32).range(1..), conflicts_with = "simple_backoff")]
+    final_timeout_factor: u32,
+
+    /// Size in bytes of the buffer to receive UDP and DTLS responses into. Responses which
+    /// fill it are rejected as possibly truncated.
+    #[arg(long, value_name = "BYTES", default_value_t = 2048, value_parser = clap::value_parser!(u16).range(20..))]
+    recv_buffer: u16,
+
+    /// How long to wait for each DNS lookup of a server, in milliseconds, including the
+    /// lookup of its STUN SRV records
+    #[arg(long, value_name = "MS", default_value_t = 5000, value_parser = clap::value_parser!(u64).range(1..))]
+    dns_timeout: u64,
+
+    /// Accept a loopback or link-local public address from any server. By default these are
+    /// only accepted from servers which are loopback or link-local themselves, such as a local
+    /// test server. Unspecified, multicast and broadcast addresses are always rejected.
+    #[arg(long)]
+    allow_local: bool,
+
+    /// Use the retry schedule of earlier versions, doubling the timeout on every attempt
+    /// including the last, instead of RFC 5389's
+    #[arg(long)]
+    simple_backoff: bool,
+
+    /// How the timeout grows between attempts: rfc5389 doubles it and waits
+    /// --final-timeout-factor times --base-timeout after the last request, exponential doubles
+    /// it every time, linear adds --base-timeout each time, and constant keeps it fixed
+    /// [default: rfc5389]
+    #[arg(
+        long,
+        value_name = "STRATEGY",
+        value_parser = PossibleValuesParser::new(["rfc5389", "exponential", "linear", "constant"])
+            .map(|name| match name.as_str() {
+                "exponential" => Backoff::Exponential,
+                "linear" => Backoff::Linear,
+                "constant" => Backoff::Constant,
+                _ => Backoff::Rfc5389,
+            }),
+        conflicts_with = "simple_backoff"
+    )]
+    backoff: Option<Backoff>,
+
+    /// Wait MS milliseconds for every attempt, instead of doubling the timeout on each retry
+    #[arg(long, value_name = "MS", conflicts_with_all = ["base_timeout", "simple_backoff", "backoff"], value_parser = clap::value_parser!(u64).range(1..))]
+    attempt_timeout: Option<u64>,
+
+    /// Randomly lengthen or shorten each timeout by up to PERCENT, e.g. 20 for +/- 20%, so
+    /// that many clients don't retransmit in lockstep
+    #[arg(long, value_name = "PERCENT", default_value_t = 0, value_parser = clap::value_parser!(u32).range(0..=100))]
+    jitter: u32,
+
+    /// Never wait more than MS milliseconds for a single attempt. The timeout grows according
+    /// to --backoff until it reaches MS and then stays there, and --jitter never lengthens it
+    /// beyond MS.
+    #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(1..))]
+    max_timeout: Option<u64>,
+
+    /// Give up after MS milliseconds in total, even if the retry schedule would wait longer.
+    /// Also limits the time spent on --tcp, --tls, --dtls and HTTP requests.
+    #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(1..))]
+    deadline: Option<u64>,
+
+    /// Value of the SOFTWARE attribute sent to the server [default: iplookup <version>]
+    #[arg(long, value_name = "TEXT", conflicts_with = "no_software")]
+    software: Option<String>,
+
+    /// Don't send a SOFTWARE attribute identifying this client
+    #[arg(long)]
+    no_software: bool,
+
+    /// Append a FINGERPRINT attribute to the request, for servers which require one
+    #[arg(long)]
+    fingerprint: bool,
+
+    /// Username for servers which require long-term credentials.
+    /// A request is only signed after the server responds with a challenge.
+    #[arg(long, value_name = "NAME", requires = "password")]
+    username: Option<String>,
+
+    /// Password for servers which require long-term credentials
+    #[arg(long, value_name = "PASS", requires = "username")]
+    password: Option<String>,
+
+    /// Only authenticate if the server's challenge is for REALM
+    #[arg(long, value_name = "REALM", requires = "username")]
+    realm: Option<String>,
+
+    /// Print the round-trip time of the query to stderr
+    #[arg(long)]
+    timing: bool,
+
+    /// Print every attribute in the response to stderr as a JSON array of objects with the
+    /// attribute's type, codepoint and value, for inspecting nonstandard servers
+    #[arg(long, conflicts_with_all = ["watch", "count", "keepalive", "both", "batch", "resolve_only"])]
+    dump_attributes: bool,
+
+    /// Print which server answered to stderr, which is useful when several are raced. It's
+    /// always included as the "server" field with --json.
+    #[arg(long)]
+    show_server: bool,
+
+    /// Add an ISO 8601 timestamp of when the lookup completed, in UTC unless --local-time is
+    /// given. It's a "timestamp" field with --json, and otherwise printed to stderr.
+    #[arg(long, conflicts_with_all = ["count", "keepalive", "both", "probe"])]
+    timestamp: bool,
+
+    /// Give --timestamp in the local time zone instead of UTC
+    #[arg(long, requires = "timestamp")]
+    local_time: bool,
+
+    /// Log everything, including the request and response, to stderr. Equivalent to
+    /// --log-level=trace. If not specified, enabled when the DEBUG envvar is non-empty.
+    #[arg(
+        conflicts_with = "log_level",
+        long,
+        value_name = "bool",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    debug: Option<bool>,
+
+    /// Don't log anything to stderr, including retries. The final error is still printed
+    /// unless this is given twice (-qq).
+    #[arg(short, long, action = ArgAction::Count, conflicts_with_all = ["debug", "log_level"])]
+    quiet: u8,
+
+    /// Level of detail to log to stderr: off, error, warn, info, debug or trace.
+    /// If not specified, RUST_LOG is used, defaulting to warn which logs retries.
+    #[arg(long, value_name = "LEVEL")]
+    log_level: Option<LevelFilter>,
+
+    /// Record the UDP STUN requests and responses, including retransmissions, in a pcap file at
+    /// PATH which can be opened in e.g. Wireshark. The Ethernet, IP and UDP headers are
+    /// synthesized from the local and remote addresses. Other transports aren't recorded.
+    #[arg(long, value_name = "PATH")]
+    pcap: Option<PathBuf>,
+
+    /// Log a hexdump of each STUN message that is sent or received, like RUST_LOG's
+    /// iplookup::hexdump=trace
+    #[arg(long, conflicts_with = "quiet")]
+    hexdump: bool,
+
+    /// Send log messages to syslog instead of stderr, defaulting to info level so that IP
+    /// changes in --watch mode are logged. The result is still printed to stdout.
+    #[arg(long, conflicts_with = "quiet")]
+    syslog: bool,
+
+    /// Syslog facility to log with, e.g. daemon or local0
+    #[arg(
+        long,
+        value_name = "FACILITY",
+        value_parser = syslog::parse_facility,
+        default_value = "user",
+        requires = "syslog"
+    )]
+    syslog_facility: Facility,
+
+    /// Print the result as a JSON object instead of a bare IP
+    #[arg(long)]
+    json: bool,
+
+    /// Print the result as YAML, with the same fields as --json
+    #[arg(long, conflicts_with_all = ["json", "batch"])]
+    yaml: bool,
+
+    /// Print the public port along with the IP, as ip:port
+    #[arg(long, conflicts_with_all = ["json", "yaml"])]
+    with_port: bool,
+
+    /// Print the result using TEMPLATE, where {ip}, {port}, {server}, {rtt} and {family} are
+    /// replaced with the result, e.g. "{ip}:{port} via {server}"
+    #[arg(long, value_name = "TEMPLATE", value_parser = Template::parse, conflicts_with_all = ["json", "yaml", "with_port"])]
+    format: Option<Template>,
+
+    /// Print the result as shell variable assignments, e.g. IPLOOKUP_IP=192.0.2.1, for use with
+    /// eval "$(iplookup --env-output)". With --with-port, IPLOOKUP_PORT is set too.
+    #[arg(long, conflicts_with_all = ["json", "yaml", "format"])]
+    env_output: bool,
+
+    /// Prefix for the variable names printed by --env-output
+    #[arg(long, value_name = "PREFIX", default_value = "IPLOOKUP_", value_parser = EnvOutput::parse_prefix, requires = "env_output")]
+    env_prefix: String,
+
+    /// Keep running, checking the public IP every SECS seconds and printing it when it changes
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    watch: Option<u64>,
+
+    /// In --watch mode, run CMD with sh whenever the public IP changes.
+    /// The new IP is passed as $1 and as $IPLOOKUP_IP.
+    #[arg(long, value_name = "CMD", requires = "watch")]
+    on_change: Option<String>,
+
+    /// Save the last public IP and when it was seen to PATH. In --watch mode, the saved IP is
+    /// loaded on startup so that changes are detected across restarts.
+    #[arg(long, value_name = "PATH")]
+    state_file: Option<PathBuf>,
+
+    /// Query one server per run instead of racing them all, starting one server further along
+    /// the list each run, as saved in --state-file. Falls through to the next server if one
+    /// fails.
+    #[arg(
+        long,
+        requires = "state_file",
+        conflicts_with_all = ["consensus"]
+    )]
+    round_robin: bool,
+
+    /// Keep a NAT mapping open: query a single UDP server every SECS seconds from the same
+    /// local port, printing the mapped address each time and warning whenever it changes
+    #[arg(
+        long,
+        value_name = "SECS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    keepalive: Option<u64>,
+
+    /// Query the server(s) N times, one second apart, and print a summary of the round-trip
+    /// times and losses like ping. Warns if the public IP changes between queries.
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    count: Option<u32>,
+
+    /// Also write the result to PATH, replacing the file atomically so that readers never see
+    /// a partial result. In --watch mode, the file is updated whenever the public IP changes.
+    #[arg(long, value_name = "PATH")]
+    output_file: Option<PathBuf>,
+
+    /// Don't end --output-file with a newline
+    #[arg(long, requires = "output_file")]
+    no_newline: bool,
+
+    /// If none of the STUN servers answer, get the public IP from the "what's my IP" service
+    /// at URL instead, which must return a bare IP. The port is reported as 0.
+    #[arg(long, value_name = "URL")]
+    http_fallback: Option<String>,
+
+    /// Also ask the "what's my IP" service at URL for the public IP, and warn if it differs
+    /// from the STUN result. This can reveal split tunneling, proxies or captive portals.
+    #[arg(long, value_name = "URL")]
+    verify_http: Option<String>,
+
+    /// In --watch mode, send a JSON object with the new "ip", the "previous" IP and a
+    /// "timestamp" to URL whenever the public IP changes. Server errors are retried twice.
+    #[arg(long, value_name = "URL", requires = "watch")]
+    webhook: Option<String>,
+
+    /// HTTP method for --webhook
+    #[arg(long, value_name = "METHOD", default_value = "POST", value_parser = webhook::parse_method, requires = "webhook")]
+    webhook_method: Method,
+
+    /// Extra header to send with --webhook, e.g. "Authorization: Bearer TOKEN".
+    /// May be given multiple times.
+    #[arg(long, value_name = "NAME: VALUE", value_parser = webhook::parse_header, requires = "webhook")]
+    webhook_header: Vec<(HeaderName, HeaderValue)>,
+
+    /// Cloudflare zone ID holding the DNS record to keep pointed at the public IP.
+    /// The A (or AAAA) record is updated after a successful lookup, or whenever the IP
+    /// changes in --watch mode, unless it already has the right value.
+    #[arg(long, value_name = "ZONE_ID", requires_all = ["cloudflare_record", "cloudflare_token"])]
+    cloudflare_zone: Option<String>,
+
+    /// Name of the Cloudflare DNS record to update, e.g. home.example.com
+    #[arg(long, value_name = "NAME", requires = "cloudflare_zone")]
+    cloudflare_record: Option<String>,
+
+    /// Cloudflare API token with permission to edit the zone's DNS records
+    #[arg(
+        long,
+        value_name = "TOKEN",
+        env = "CLOUDFLARE_API_TOKEN",
+        hide_env_values = true
+    )]
+    cloudflare_token: Option<String>,
+
+    /// AWS Route 53 hosted zone ID holding the DNS record to keep pointed at the public IP,
+    /// using credentials from $AWS_ACCESS_KEY_ID, $AWS_SECRET_ACCESS_KEY and, for temporary
+    /// credentials, $AWS_SESSION_TOKEN. The A (or AAAA) record is created or replaced after a
+    /// successful lookup, or whenever the IP changes in --watch mode.
+    #[arg(long, value_name = "ZONE_ID", requires = "route53_record")]
+    route53_zone: Option<String>,
+
+    /// Name of the Route 53 record to update, e.g. home.example.com
+    #[arg(long, value_name = "NAME", requires = "route53_zone")]
+    route53_record: Option<String>,
+
+    /// TTL in seconds to give the Route 53 record
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = 300,
+        requires = "route53_zone"
+    )]
+    route53_ttl: u32,
+
+    /// DuckDNS subdomain to keep pointed at the public IP, e.g. home for home.duckdns.org.
+    /// It's updated after a successful lookup, or whenever the IP changes in --watch mode. With
+    /// --state-file, one-off lookups only update it if the IP differs from the saved one.
+    #[arg(long, value_name = "DOMAIN", requires = "duckdns_token")]
+    duckdns: Option<String>,
+
+    /// DuckDNS account token
+    #[arg(
+        long,
+        value_name = "TOKEN",
+        env = "DUCKDNS_TOKEN",
+        hide_env_values = true
+    )]
+    duckdns_token: Option<String>,
+
+    /// No-IP hostname to keep pointed at the public IP, e.g. home.ddns.net. It's updated after
+    /// a successful lookup, or whenever the IP changes in --watch mode. With --state-file,
+    /// one-off lookups only update it if the IP differs from the saved one.
+    #[arg(long, value_name = "HOSTNAME", requires_all = ["noip_username", "noip_password"])]
+    noip: Option<String>,
+
+    /// No-IP account username or email address
+    #[arg(long, value_name = "USERNAME", requires = "noip")]
+    noip_username: Option<String>,
+
+    /// No-IP account password
+    #[arg(
+        long,
+        value_name = "PASSWORD",
+        env = "NOIP_PASSWORD",
+        hide_env_values = true
+    )]
+    noip_password: Option<String>,
+
+    /// In --watch mode, show a desktop notification with the old and new IP whenever the
+    /// public IP changes
+    #[cfg(feature = "notify")]
+    #[arg(long, requires = "watch")]
+    notify: bool,
+
+    /// MQTT broker to publish the public IP to, as mqtt://HOST[:PORT] or mqtts://HOST[:PORT]
+    /// for TLS. The IP is published as a retained message after a successful lookup, and in
+    /// --watch mode on startup and whenever it changes.
+    // Joins the "report" group here rather than in its list, since it depends on a feature
+    #[cfg(feature = "mqtt")]
+    #[arg(long, value_name = "URL", requires = "mqtt_topic", group = "report")]
+    mqtt: Option<String>,
+
+    /// Topic to publish the public IP to with --mqtt, e.g. home/public_ip
+    #[cfg(feature = "mqtt")]
+    #[arg(long, value_name = "TOPIC", requires = "mqtt")]
+    mqtt_topic: Option<String>,
+
+    /// Username for the --mqtt broker
+    #[cfg(feature = "mqtt")]
+    #[arg(long, value_name = "USERNAME", requires_all = ["mqtt", "mqtt_password"])]
+    mqtt_username: Option<String>,
+
+    /// Password for the --mqtt broker
+    #[cfg(feature = "mqtt")]
+    #[arg(
+        long,
+        value_name = "PASSWORD",
+        env = "MQTT_PASSWORD",
+        hide_env_values = true
+    )]
+    mqtt_password: Option<String>,
+
+    /// Quality of service to publish with: 0 (at most once), 1 (at least once) or 2 (exactly
+    /// once)
+    #[cfg(feature = "mqtt")]
+    #[arg(long, value_name = "QOS", default_value = "1", value_parser = mqtt::parse_qos, requires = "mqtt")]
+    mqtt_qos: QoS,
+
+    /// Keep NAME pointed at the public IP in the hosts file, after a successful lookup or
+    /// whenever the IP changes in --watch mode. Only NAME's own line is rewritten, or one is
+    /// added, and the file is left untouched if it can't be parsed.
+    #[arg(long, value_name = "NAME")]
+    update_hosts: Option<String>,
+
+    /// Hosts file to update with --update-hosts
+    #[arg(
+        long,
+        value_name = "PATH",
+        default_value = "/etc/hosts",
+        requires = "update_hosts"
+    )]
+    hosts_file: PathBuf,
+
+    /// Authoritative DNS server to send TSIG-signed RFC 2136 updates to, as HOST or HOST:PORT,
+    /// keeping a record pointed at the public IP. The A (or AAAA) record is updated after a
+    /// successful lookup, or whenever the IP changes in --watch mode, unless the server already
+    /// has the right value.
+    #[arg(long, value_name = "SERVER", requires_all = ["nsupdate_zone", "nsupdate_record", "nsupdate_key_name", "nsupdate_key_secret"])]
+    nsupdate: Option<String>,
+
+    /// Zone to update with --nsupdate, e.g. example.com
+    #[arg(long, value_name = "ZONE", requires = "nsupdate")]
+    nsupdate_zone: Option<String>,
+
+    /// Name of the record to update with --nsupdate, e.g. home.example.com
+    #[arg(long, value_name = "NAME", requires = "nsupdate")]
+    nsupdate_record: Option<String>,
+
+    /// Name of the TSIG key to sign --nsupdate updates with
+    #[arg(long, value_name = "NAME", requires = "nsupdate")]
+    nsupdate_key_name: Option<String>,
+
+    /// Base64 secret of the TSIG key, as in the key's "secret" line in BIND's configuration
+    #[arg(
+        long,
+        value_name = "SECRET",
+        env = "NSUPDATE_KEY_SECRET",
+        hide_env_values = true
+    )]
+    nsupdate_key_secret: Option<String>,
+
+    /// Algorithm of the TSIG key: hmac-sha256, hmac-sha384 or hmac-sha512
+    #[arg(long, value_name = "ALGORITHM", default_value = "hmac-sha256", value_parser = nsupdate::parse_algorithm, requires = "nsupdate")]
+    nsupdate_algorithm: TsigAlgorithm,
+
+    /// TTL in seconds to give the record updated with --nsupdate
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = 300,
+        requires = "nsupdate"
+    )]
+    nsupdate_ttl: u32,
+
+    /// Write Prometheus metrics about each lookup to PATH, e.g. for the node_exporter textfile
+    /// collector. In --watch mode, the file is updated after every check.
+    #[arg(long, value_name = "PATH")]
+    metrics_file: Option<PathBuf>,
+
+    /// Classify the NAT's mapping and filtering behavior using RFC 5780, instead of printing
+    /// the public IP. The server must support RFC 5780 (advertising OTHER-ADDRESS).
+    /// Tests which expect no response wait for the full retry schedule.
+    #[arg(long)]
+    nat_type: bool,
+
+    /// Classify only the NAT's mapping behavior, by comparing the public ports which the
+    /// server's primary address and its OTHER-ADDRESS report for the same local port. Unlike
+    /// --nat-type, every test expects a response, so this is quick.
+    #[arg(long)]
+    mapping_behavior: bool,
+
+    /// Classify only the NAT's filtering behavior, by asking the server with CHANGE-REQUEST to
+    /// respond from its other IP and/or port and checking which responses arrive. The server
+    /// must support RFC 5780. Tests which get no response wait for the full retry schedule.
+    #[arg(long)]
+    filtering_behavior: bool,
+
+    /// Check for a symmetric NAT by querying two different servers from the same local port and
+    /// comparing the public ports they report. Works with any two STUN servers, unlike
+    /// --mapping-behavior.
+    #[arg(long)]
+    symmetric_check: bool,
+
+    /// Check whether the NAT supports hairpinning: after the server reports the public address
+    /// of one local socket, send a request to that address from a second local socket and see
+    /// whether it arrives. A NAT with address-dependent filtering may drop the request even if
+    /// it can hairpin, and a failure waits for the full retry schedule.
+    #[arg(long)]
+    hairpin_test: bool,
+
+    /// After the lookup, check whether the public IP is assigned to one of the host's network
+    /// interfaces and report on stderr whether the host is behind a NAT
+    #[arg(long)]
+    detect_nat: bool,
+
+    /// After the lookup, list the host's non-loopback interface addresses on stderr, to compare
+    /// the LAN IP against the public IP
+    #[arg(long)]
+    show_local: bool,
+
+    /// Check that a TURN server (RFC 5766) will relay traffic: allocate a relayed address using
+    /// the --username and --password credentials, print it instead of the public address, and
+    /// release it again. Requires a single server.
+    #[arg(long, requires = "username", conflicts_with = "format")]
+    turn: bool,
+
+    /// Compare the public IP against the one saved in PATH, then save the new IP to PATH.
+    /// Exits with 0 if the IP is unchanged, or 10 if it changed or no IP was saved yet.
+    #[arg(long, value_name = "PATH", conflicts_with = "watch")]
+    compare: Option<PathBuf>,
+
+    /// Instead of the IP, print how it changed from the one saved by --state-file or
+    /// --compare, as "OLD -> NEW (changed at TIME)". Nothing is printed if it's unchanged,
+    /// unless -v is given.
+    #[arg(long, requires = "recorded", conflicts_with_all = ["json", "yaml", "format", "env_output"])]
+    diff: bool,
+
+    /// With --diff, print "nochg IP" when the IP is unchanged
+    #[arg(short, long, requires = "diff")]
+    verbose: bool,
 }
 
-fn main() -> Result<()> {
-    if env::args().len() <= 1 {
-        print_syntax();
-        bail!("Missing required argument");
-    }
-    let endpoint = env::args().nth(1).unwrap();
-    if endpoint.starts_with('-') {
-        // Probably a commandline argument like '-h'/'--help', avoid parsing as a hostname
-        print_syntax();
-        bail!("Unrecognized argument");
-    }
-    // Probably an endpoint, try to resolve it in case it's a hostname
-    let addr = endpoint
-        .to_socket_addrs()
-        .with_context(|| format!("Invalid or unresolvable endpoint: {}", endpoint))?
-        .next()
-        .with_context(|| format!("Missing addresses in endpoint resolution: {}", endpoint))?;
-    let local_addr = "0.0.0.0:0".to_socket_addrs()?.next().unwrap();
-    let mut conn = UdpSocket::bind(local_addr)?;
-
-    // If the "DEBUG" envvar is non-empty, enable debug
-    let debug = match env::var_os("DEBUG") {
-        Some(val) => !val.is_empty(),
-        None => false,
-    };
+/// Exit code for failures which don't fall into one of the categories below
+const EXIT_FAILURE: u8 = 1;
+/// Exit code for invalid arguments, matching clap's own usage errors
+const EXIT_USAGE: u8 = 2;
+/// Exit code when the server endpoint couldn't be resolved
+const EXIT_RESOLVE: u8 = 3;
+/// Exit code when no response arrived after all retries
+const EXIT_TIMEOUT: u8 = 4;
+/// Exit code when the response couldn't be decoded
+const EXIT_DECODE: u8 = 5;
+/// Exit code for --check-ipv6 when the public address isn't a global IPv6 address
+const EXIT_NOT_GLOBAL: u8 = 6;
+/// Exit code when the server answered with an error response
+const EXIT_SERVER_ERROR: u8 = 7;
+/// Exit code when the local socket couldn't be bound
+const EXIT_BIND: u8 = 8;
+/// Exit code when several servers failed in different ways, or a quorum wasn't reached
+const EXIT_ALL_FAILED: u8 = 9;
+/// Exit code for --compare when the public IP has changed
+const EXIT_CHANGED: u8 = 10;
 
-    match run_client(&mut conn, &addr, debug) {
-        Ok(addr) => {
-            println!("{}", addr.ip());
-            Ok(())
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    // Argument errors are reported by clap, which exits with 2
+    let args = Args::parse();
+    let quiet = args.quiet;
+    let syslog = args.syslog;
+    match run(args).await {
+        Ok(code) => code,
+        Err(e) => {
+            if syslog {
+                error!("{:#}", e);
+            }
+            if quiet < 2 {
+                eprintln!("Error: {:?}", e);
+            }
+            ExitCode::from(match e.downcast_ref() {
+                Some(LookupError::Resolve(_)) => EXIT_RESOLVE,
+                Some(LookupError::Timeout(_)) => EXIT_TIMEOUT,
+                Some(
+                    LookupError::Decode(_)
+                    | LookupError::TransactionMismatch(_)
+                    | LookupError::NoAddress(_)
+                    | LookupError::InvalidAddress(_),
+                ) => EXIT_DECODE,
+                Some(
+                    LookupError::ServerError { .. }
+                    | LookupError::Auth(_)
+                    | LookupError::Redirect(_),
+                ) => EXIT_SERVER_ERROR,
+                Some(LookupError::Bind(_)) => EXIT_BIND,
+                Some(LookupError::InvalidOptions(_) | LookupError::Unsupported(_)) => EXIT_USAGE,
+                Some(LookupError::AllFailed(_) | LookupError::NoQuorum { .. }) => EXIT_ALL_FAILED,
+                Some(LookupError::Io(_) | LookupError::Other(_)) | None => EXIT_FAILURE,
+            })
         }
-        Err(ioerr) => Err(ioerr),
     }
 }
 
-/// Runs the client: Sends a request and prints the response
-fn run_client(conn: &mut UdpSocket, dest: &SocketAddr, debug: bool) -> Result<SocketAddr> {
-    // Build and send request
-    let mut transaction_id_buf = [0u8; 12];
-    rand::thread_rng().try_fill(&mut transaction_id_buf)?;
-    let transaction_id = TransactionId::new(transaction_id_buf);
-    let message =
-        Message::<Attribute>::new(MessageClass::Request, methods::BINDING, transaction_id);
-    if debug {
-        eprintln!("Sending: {:#?}", &message);
-    }
-    let message_bytes = MessageEncoder::new()
-        .encode_into_bytes(message)
-        .context("Codec error when encoding request")?;
-
-    // Wait for response, use arbitrarily large buf that shouldn't realistically be exceeded by UDP
-    let mut recvbuf = [0u8; 2048];
-
-    let recvsize = recv_exponential_backoff(conn, &dest, &message_bytes, &mut recvbuf)?;
-
-    let mut decoder = MessageDecoder::<Attribute>::new();
-    let decoded = decoder
-        .decode_from_bytes(&recvbuf[..recvsize])
-        .context("Codec error when decoding response")?
-        // Would use another .context() call, but BrokenMessage is incompatible.
-        .map_err(|e| anyhow!("Message error when decoding response: {:?}", e))?;
-    if debug {
-        eprintln!("Received ({}b): {:#?}", recvsize, decoded);
-    }
-
-    // Check that the returned transaction ID matches what we sent
-    if transaction_id != decoded.transaction_id() {
-        bail!(
-            "Returned transaction id {:?} doesn't match sent {:?}",
-            decoded.transaction_id(),
-            transaction_id
-        );
-    }
-
-    let result = decoded
-        .attributes()
-        .filter_map(|a| {
-            if let Attribute::MappedAddress(ma) = a {
-                Some(ma.address())
-            } else if let Attribute::XorMappedAddress(ma) = a {
-                Some(ma.address())
-            } else if let Attribute::XorMappedAddress2(ma) = a {
-                Some(ma.address())
-            } else {
-                None
-            }
-        })
-        .nth(0);
+/// Sets up logging of bare messages to stderr, or to syslog with `syslog`'s facility. Unless
+/// `level` is specified for iplookup's own messages, uses RUST_LOG, or else only logs warnings
+/// (info for syslog). `hexdump` adds dumps of raw messages. Nothing is logged if `quiet` is set.
+fn init_logging(
+    quiet: bool,
+    level: Option<LevelFilter>,
+    hexdump: bool,
+    syslog: Option<Facility>,
+) -> Result<()> {
+    let mut builder = env_logger::Builder::new();
+    builder.format(|buf, record| writeln!(buf, "{}", record.args()));
+    if quiet {
+        builder.filter_level(LevelFilter::Off).init();
+        return Ok(());
+    }
+    builder.filter_level(match syslog {
+        Some(_) => LevelFilter::Info,
+        None => LevelFilter::Warn,
+    });
+    if let Ok(filters) = env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    if let Some(level) = level {
+        builder.filter_module("iplookup", level);
+    }
+    if hexdump {
+        builder.filter_module("iplookup::hexdump", LevelFilter::Trace);
+    }
+    if let Some(facility) = syslog {
+        let logger = Syslog::connect(facility, builder.build())?;
+        log::set_max_level(logger.level());
+        log::set_boxed_logger(Box::new(logger))?;
+        return Ok(());
+    }
+    builder.init();
+    Ok(())
+}
 
-    result.with_context(|| format!("No address attribute found in response: {:?}", decoded))
+/// Exits with clap's usage error for a combination of arguments that clap itself can't check
+fn usage_error(kind: ErrorKind, message: impl std::fmt::Display) -> ! {
+    Args::command().error(kind, message).exit()
 }
 
-fn recv_exponential_backoff(
-    conn: &mut UdpSocket,
-    dest: &SocketAddr,
-    sendbuf: &Vec<u8>,
-    mut recvbuf: &mut [u8],
-) -> Result<usize> {
-    // UDP sends shouldn't time out but just in case...
-    conn.set_write_timeout(Some(Duration::from_millis(1000)))?;
-    // Receive timeout durations: 1s, 2s, 4s, 8s, 16s (total wait: 31s)
-    const RETRIES: u32 = 5;
-    for timeout_exponent in 0..RETRIES {
-        // (Re)send request.
-        let _sendsize = conn.send_to(sendbuf, dest)?;
-
-        let timeout_ms = 1000 * 2_u64.pow(timeout_exponent);
-        conn.set_read_timeout(Some(Duration::from_millis(timeout_ms)))?;
-        match conn.recv_from(&mut recvbuf) {
-            Ok((recvsize, recvdest)) => {
-                // Before returning, check that the response is from who we're waiting for
-                if *dest == recvdest {
-                    return Ok(recvsize);
-                }
-                // If it doesn't match, resend and resume waiting, unless this was the last retry
-                eprintln!(
-                    "Response origin {:?} doesn't match request target {:?}",
-                    recvdest, dest
+async fn run(args: Args) -> Result<ExitCode> {
+    // A --local-addr also restricts which of the server's addresses can be used
+    let family = if args.ipv4 || args.local_addr.is_some_and(|ip| ip.is_ipv4()) {
+        Some(Family::V4)
+    } else if args.ipv6 || args.check_ipv6 || args.local_addr.is_some_and(|ip| ip.is_ipv6()) {
+        Some(Family::V6)
+    } else {
+        None
+    };
+
+    // An explicit --debug wins, otherwise enable debug if the "DEBUG" envvar is non-empty
+    let debug = args.debug.unwrap_or_else(|| match env::var_os("DEBUG") {
+        Some(val) => !val.is_empty(),
+        None => false,
+    });
+    init_logging(
+        args.quiet > 0,
+        args.log_level.or(debug.then_some(LevelFilter::Trace)),
+        args.hexdump,
+        args.syslog.then_some(args.syslog_facility),
+    )?;
+
+    let mut endpoints = Vec::new();
+    for endpoint in args.endpoints {
+        // Like other Unix tools, "-" stands for a list of servers piped into stdin
+        if endpoint == "-" {
+            let piped = servers::load_stdin()?;
+            if piped.is_empty() {
+                usage_error(
+                    ErrorKind::MissingRequiredArgument,
+                    "no servers were piped into stdin",
                 );
             }
-            Err(e) => {
-                match e.kind() {
-                    ErrorKind::WouldBlock | ErrorKind::TimedOut => {
-                        // Timeout occurred, try again (or exit loop)
-                        if timeout_exponent + 1 == RETRIES {
-                            eprintln!("Timed out after {}ms, giving up.", timeout_ms);
-                        } else {
-                            eprintln!("Timed out after {}ms, trying {} again...", timeout_ms, dest);
-                        }
+            endpoints.extend(piped);
+        } else {
+            endpoints.push(endpoint);
+        }
+    }
+    if let Some(path) = &args.server_file {
+        endpoints.extend(servers::load(path)?);
+    }
+    if endpoints.is_empty() && args.batch.is_none() {
+        // Fall back to the "IPLOOKUP_SERVER" envvar, and then to the built-in list
+        match env::var("IPLOOKUP_SERVER") {
+            Ok(server) if !server.trim().is_empty() => endpoints.push(server.trim().to_string()),
+            _ if args.use_defaults => {
+                endpoints = DEFAULT_SERVERS.iter().map(|s| s.to_string()).collect()
+            }
+            _ => match &args.server_file {
+                Some(path) => usage_error(
+                    ErrorKind::MissingRequiredArgument,
+                    format!("no servers were listed in {:?}", path),
+                ),
+                None => usage_error(
+                    ErrorKind::MissingRequiredArgument,
+                    "no STUN server was listed, and IPLOOKUP_SERVER is not set",
+                ),
+            },
+        }
+    }
+
+    // Start one server further along than last time
+    let rotation = if args.round_robin {
+        let path = args
+            .state_file
+            .as_deref()
+            .expect("--round-robin requires --state-file");
+        let previous = State::load(path);
+        let start = previous.as_ref().map_or(0, |state| state.next_server) % endpoints.len();
+        endpoints.rotate_left(start);
+        Some((start, previous))
+    } else {
+        None
+    };
+
+    let client = LookupClient::builder()
+        .family(family)
+        .retries(
+            args.retries
+                .unwrap_or(if args.simple_backoff { 5 } else { 7 }),
+        )
+        .base_timeout(Duration::from_millis(
+            args.attempt_timeout
+                .or(args.base_timeout)
+                .unwrap_or(if args.simple_backoff { 1000 } else { 500 }),
+        ))
+        .backoff(if args.attempt_timeout.is_some() {
+            Backoff::Constant
+        } else if let Some(backoff) = args.backoff {
+            backoff
+        } else if args.simple_backoff {
+            Backoff::Exponential
+        } else {
+            Backoff::Rfc5389
+        })
+        .final_timeout_factor(args.final_timeout_factor)
+        .jitter_percent(args.jitter)
+        .max_timeout(args.max_timeout.map(Duration::from_millis))
+        .deadline(args.deadline.map(Duration::from_millis))
+        .transport(if args.tcp {
+            Transport::Tcp
+        } else if args.tls {
+            Transport::Tls
+        } else if args.dtls {
+            Transport::Dtls
+        } else {
+            Transport::Udp
+        })
+        .insecure(args.insecure)
+        .software(if args.no_software {
+            None
+        } else {
+            Some(args.software.unwrap_or_else(default_software))
+        })
+        .fingerprint(args.fingerprint)
+        .credentials(match (args.username, args.password) {
+            (Some(username), Some(password)) => Some(Credentials {
+                username,
+                password,
+                realm: args.realm,
+            }),
+            _ => None,
+        })
+        .local_addr(args.local_addr)
+        .local_port(args.local_port.unwrap_or(0))
+        .interface(args.interface)
+        .shared_socket(args.shared_socket)
+        .dual_stack(args.dual_stack)
+        .recv_buffer(usize::from(args.recv_buffer))
+        .dns_timeout(Duration::from_millis(args.dns_timeout))
+        .allow_local(args.allow_local)
+        .pcap(match &args.pcap {
+            Some(path) => Some(Arc::new(PcapWriter::create(path)?)),
+            None => None,
+        })
+        .build();
+    let opts = client.options();
+    let quorum = if args.consensus {
+        Some(args.quorum.unwrap_or(endpoints.len() / 2 + 1))
+    } else {
+        None
+    };
+    let format = if let Some(template) = args.format {
+        Format::Template(template)
+    } else if args.env_output {
+        Format::Env(EnvOutput {
+            prefix: args.env_prefix,
+            port: args.with_port,
+        })
+    } else if args.json {
+        Format::Json
+    } else if args.yaml {
+        Format::Yaml
+    } else if args.with_port {
+        Format::IpPort
+    } else {
+        Format::Ip
+    };
+
+    if args.resolve_only {
+        let mut last_err = None;
+        for endpoint in &endpoints {
+            match resolve_endpoint_all(endpoint, family, opts).await {
+                Ok(addrs) => println!(
+                    "{}",
+                    format.render_resolved(endpoint, &addrs, endpoints.len() > 1)?
+                ),
+                Err(e) => {
+                    // The last error is returned, but earlier ones would be lost
+                    if let Some(earlier) = last_err.replace(e) {
+                        warn!("{:#}", earlier);
                     }
-                    _ => {
-                        // A different error occurred, give up
-                        return Err(e).with_context(|| {
-                            format!("Failed to receive STUN response from {}", dest)
-                        })?;
+                }
+            }
+        }
+        return match last_err {
+            Some(e) => Err(e.into()),
+            None => Ok(ExitCode::SUCCESS),
+        };
+    }
+
+    if let Some(path) = &args.batch {
+        batch::run(path, usize::from(args.concurrency), family, opts, &format).await?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if args.nat_type {
+        let server = match endpoints.as_slice() {
+            [server] => server,
+            _ => usage_error(
+                ErrorKind::WrongNumberOfValues,
+                "--nat-type requires a single server",
+            ),
+        };
+        let behavior = detect_nat(server, family, opts).await?;
+        println!("{}", format.render_nat(server, &behavior)?);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if args.mapping_behavior {
+        let server = match endpoints.as_slice() {
+            [server] => server,
+            _ => usage_error(
+                ErrorKind::WrongNumberOfValues,
+                "--mapping-behavior requires a single server",
+            ),
+        };
+        let behavior = detect_mapping(server, family, opts).await?;
+        println!("{}", format.render_mapping(server, &behavior)?);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if args.filtering_behavior {
+        let server = match endpoints.as_slice() {
+            [server] => server,
+            _ => usage_error(
+                ErrorKind::WrongNumberOfValues,
+                "--filtering-behavior requires a single server",
+            ),
+        };
+        let behavior = detect_filtering(server, family, opts).await?;
+        println!("{}", format.render_filtering(server, &behavior)?);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if args.symmetric_check {
+        let (first, second) = match endpoints.as_slice() {
+            [first, second] => (first, second),
+            _ => usage_error(
+                ErrorKind::WrongNumberOfValues,
+                "--symmetric-check requires two servers",
+            ),
+        };
+        let check = check_symmetric(first, second, family, opts).await?;
+        println!("{}", format.render_symmetric(&check)?);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if args.hairpin_test {
+        let server = match endpoints.as_slice() {
+            [server] => server,
+            _ => usage_error(
+                ErrorKind::WrongNumberOfValues,
+                "--hairpin-test requires a single server",
+            ),
+        };
+        let hairpin = test_hairpin(server, family, opts).await?;
+        println!("{}", format.render_hairpin(&hairpin)?);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if args.turn {
+        let server = match endpoints.as_slice() {
+            [server] => server,
+            _ => usage_error(
+                ErrorKind::WrongNumberOfValues,
+                "--turn requires a single server",
+            ),
+        };
+        let allocation = allocate_turn(server, family, opts)
+            .await
+            .context("TURN allocation failed")?;
+        println!("{}", format.render_turn(server, &allocation)?);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if args.both {
+        let mut v4 = Query::new(endpoints.clone(), Some(Family::V4), None, opts.clone());
+        let mut v6 = Query::new(endpoints, Some(Family::V6), None, opts.clone());
+        let (v4, v6) = tokio::join!(v4.run(), v6.run());
+        let v4 = v4.map_err(|e| warn!("IPv4 lookup failed: {:#}", e)).ok();
+        let v6 = v6.map_err(|e| warn!("IPv6 lookup failed: {:#}", e)).ok();
+        if v4.is_none() && v6.is_none() {
+            bail!("Neither the public IPv4 nor IPv6 address could be found");
+        }
+        println!("{}", format.render_both(v4.as_ref(), v6.as_ref())?);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let mut query = Query::new(endpoints, family, quorum, opts.clone());
+    query.http_fallback = args.http_fallback;
+    query.verify_http = args.verify_http;
+    query.in_turn = args.round_robin;
+
+    let timestamp = match (args.timestamp, args.local_time) {
+        (true, true) => Some(Clock::Local),
+        (true, false) => Some(Clock::Utc),
+        (false, _) => None,
+    };
+    let newline = !args.no_newline;
+    let output_file = args.output_file.map(|path| OutputFile { path, newline });
+
+    let cloudflare = match (
+        args.cloudflare_zone,
+        args.cloudflare_record,
+        args.cloudflare_token,
+    ) {
+        (Some(zone), Some(record), Some(token)) => Some(Cloudflare::new(zone, record, token)?),
+        _ => None,
+    };
+    let route53 = match (args.route53_zone, args.route53_record) {
+        (Some(zone), Some(record)) => Some(Route53::new(zone, record, args.route53_ttl)?),
+        _ => None,
+    };
+    let duckdns = match (args.duckdns, args.duckdns_token) {
+        (Some(domain), Some(token)) => Some(DuckDns::new(domain, token)?),
+        _ => None,
+    };
+    let noip = match (args.noip, args.noip_username, args.noip_password) {
+        (Some(hostname), Some(username), Some(password)) => {
+            Some(NoIp::new(hostname, username, password)?)
+        }
+        _ => None,
+    };
+    #[cfg(feature = "mqtt")]
+    let mqtt = match (args.mqtt, args.mqtt_topic) {
+        (Some(url), Some(topic)) => {
+            let credentials = args.mqtt_username.zip(args.mqtt_password);
+            Some(Mqtt::new(&url, topic, credentials, args.mqtt_qos)?)
+        }
+        _ => None,
+    };
+    let hosts = match args.update_hosts {
+        Some(name) => Some(HostsFile::new(args.hosts_file, name)?),
+        None => None,
+    };
+    let nsupdate = match (
+        args.nsupdate,
+        args.nsupdate_zone,
+        args.nsupdate_record,
+        args.nsupdate_key_name,
+        args.nsupdate_key_secret,
+    ) {
+        (Some(server), Some(zone), Some(record), Some(key_name), Some(secret)) => {
+            let key = nsupdate::tsig_key(&key_name, &secret, args.nsupdate_algorithm)?;
+            Some(Nsupdate::new(
+                server,
+                &zone,
+                &record,
+                key,
+                args.nsupdate_ttl,
+                Duration::from_millis(args.dns_timeout),
+            )?)
+        }
+        _ => None,
+    };
+
+    if let Some(secs) = args.keepalive {
+        match query.endpoints.as_slice() {
+            [server] if iplookup::parse_endpoint(server, Transport::Udp).0 == Transport::Udp => {}
+            _ => usage_error(
+                ErrorKind::WrongNumberOfValues,
+                "--keepalive requires a single UDP server",
+            ),
+        }
+        keepalive::run(&mut query, Duration::from_secs(secs)).await?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(count) = args.count {
+        count::run(&mut query, count).await?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(secs) = args.watch {
+        let watch = Watch {
+            interval: Duration::from_secs(secs),
+            format,
+            on_change: args.on_change,
+            state_file: args.state_file,
+            metrics_file: args.metrics_file,
+            output_file,
+            cloudflare,
+            route53,
+            nsupdate,
+            duckdns,
+            noip,
+            hosts,
+            #[cfg(feature = "mqtt")]
+            mqtt,
+            #[cfg(feature = "notify")]
+            notify: args.notify,
+            diff: args.diff,
+            verbose: args.verbose,
+            webhook: match args.webhook {
+                Some(url) => Some(Webhook::new(url, args.webhook_method, args.webhook_header)?),
+                None => None,
+            },
+            timing: args.timing,
+            show_server: args.show_server,
+            timestamp,
+        };
+        watch.run(&mut query).await?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let result = query.run().await;
+    if let Some(path) = args.metrics_file {
+        let mut metrics = Metrics::new(path);
+        let recorded = metrics.record(result.as_ref().ok().map(|(_, binding)| binding));
+        // Don't let a failure to write metrics hide why the lookup failed
+        if result.is_ok() {
+            recorded?;
+        } else if let Err(e) = recorded {
+            warn!("{:#}", e);
+        }
+    }
+    let next_server = rotation
+        .as_ref()
+        .map_or(0, |(start, _)| (start + 1) % query.endpoints.len());
+    match result {
+        Ok((server, binding)) => {
+            let addr = binding.addr;
+            let timestamp = timestamp.map(Clock::now);
+            let line = format.render(&server, &binding, timestamp.as_deref())?;
+            if args.diff {
+                let previous = args
+                    .compare
+                    .as_deref()
+                    .or(args.state_file.as_deref())
+                    .and_then(State::load)
+                    .map(|state| state.ip);
+                if previous == Some(addr.ip()) {
+                    if args.verbose {
+                        println!("nochg {}", addr.ip());
                     }
+                } else {
+                    let time = timestamp.clone().unwrap_or_else(|| Clock::Utc.now());
+                    println!("{}", output::render_diff(previous, addr.ip(), &time));
+                }
+            } else {
+                println!("{}", line);
+            }
+            if let Some(output_file) = &output_file {
+                output_file.write(&line)?;
+            }
+            if let Some(cloudflare) = &cloudflare {
+                cloudflare.update(addr.ip()).await?;
+            }
+            if let Some(hosts) = &hosts {
+                hosts.update(addr.ip())?;
+            }
+            #[cfg(feature = "mqtt")]
+            if let Some(mqtt) = &mqtt {
+                mqtt.publish(addr.ip()).await?;
+            }
+            if let Some(route53) = &route53 {
+                route53.update(addr.ip()).await?;
+            }
+            if let Some(nsupdate) = &nsupdate {
+                nsupdate.update(addr.ip()).await?;
+            }
+            // Dynamic DNS services only want to hear about actual changes
+            let unchanged = args
+                .state_file
+                .as_deref()
+                .and_then(State::load)
+                .is_some_and(|state| state.ip == addr.ip());
+            if let Some(duckdns) = &duckdns {
+                if unchanged {
+                    debug!("Public IP unchanged, not updating DuckDNS");
+                } else {
+                    duckdns.update(addr.ip()).await?;
+                }
+            }
+            if let Some(noip) = &noip {
+                if unchanged {
+                    debug!("Public IP unchanged, not updating No-IP");
+                } else {
+                    noip.update(addr.ip()).await?;
+                }
+            }
+            if args.dump_attributes {
+                eprintln!("{}", serde_json::to_string(&binding.attributes)?);
+            }
+            if args.show_server && !format.is_structured() {
+                eprintln!("Server: {}", server);
+            }
+            if args.timing {
+                eprintln!("RTT: {:.1}ms", binding.rtt.as_secs_f64() * 1000.0);
+            }
+            if args.detect_nat || args.show_local {
+                let interfaces = local::interface_addrs()?;
+                if args.show_local {
+                    eprintln!("{}", local::render_local(&interfaces));
+                }
+                if args.detect_nat {
+                    eprintln!(
+                        "{}",
+                        local::describe_nat(&binding, query.local_addr(), &interfaces)
+                    );
                 }
             }
+            if let Some(path) = &args.state_file {
+                let state = State {
+                    next_server,
+                    ..State::now(addr.ip())
+                };
+                state.save(path)?;
+            }
+            if args.check_ipv6 {
+                let global = match addr.ip() {
+                    IpAddr::V6(ip) => local::is_global_ipv6(&ip),
+                    IpAddr::V4(_) => false,
+                };
+                if !global {
+                    warn!("Public address {} isn't a global IPv6 address", addr.ip());
+                    return Ok(ExitCode::from(EXIT_NOT_GLOBAL));
+                }
+            }
+            if let Some(path) = &args.compare {
+                let previous = State::load(path).map(|state| state.ip);
+                State::now(addr.ip()).save(path)?;
+                if previous != Some(addr.ip()) {
+                    return Ok(ExitCode::from(EXIT_CHANGED));
+                }
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Err(e) => {
+            // Still move on to the next server, keeping the last known IP
+            if let (Some((_, Some(previous))), Some(path)) = (rotation, &args.state_file) {
+                let state = State {
+                    next_server,
+                    ..previous
+                };
+                if let Err(save_err) = state.save(path) {
+                    warn!("{:#}", save_err);
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the arguments which enable `mode`, one of the "mode" group's members
+    fn mode_args(mode: &str) -> &'static [&'static str] {
+        match mode {
+            "batch" => &["--batch", "-"],
+            "resolve_only" => &["--resolve-only"],
+            "both" => &["--both"],
+            "watch" => &["--watch", "60"],
+            "keepalive" => &["--keepalive", "30"],
+            "count" => &["--count", "3"],
+            "nat_type" => &["--nat-type"],
+            "mapping_behavior" => &["--mapping-behavior"],
+            "filtering_behavior" => &["--filtering-behavior"],
+            "symmetric_check" => &["--symmetric-check"],
+            "hairpin_test" => &["--hairpin-test"],
+            "turn" => &["--turn"],
+            _ => panic!("no arguments listed for mode {}", mode),
+        }
+    }
+
+    fn parse(args: &[&str]) -> Result<Args, clap::Error> {
+        let base = ["iplookup", "--username", "user", "--password", "pass"];
+        Args::try_parse_from(base.iter().chain(args))
+    }
+
+    #[test]
+    fn modes_are_exclusive() {
+        let command = Args::command();
+        let group = command
+            .get_groups()
+            .find(|group| group.get_id() == "mode")
+            .unwrap();
+        let modes: Vec<&str> = group.get_args().map(|id| id.as_str()).collect();
+        for first in &modes {
+            parse(mode_args(first)).unwrap();
+            for second in &modes {
+                if first == second {
+                    continue;
+                }
+                let args = [mode_args(first), mode_args(second)].concat();
+                let err = parse(&args).unwrap_err();
+                assert_eq!(err.kind(), ErrorKind::ArgumentConflict, "{:?}", args);
+            }
+        }
+        for args in [
+            &["--count", "3", "--env-output"][..],
+            &["--keepalive", "30", "--env-output"],
+            &["--timestamp", "--mapping-behavior"],
+            &["--check-ipv6", "--hairpin-test"],
+        ] {
+            let err = parse(args).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::ArgumentConflict, "{:?}", args);
+        }
+        // Options which modify a lookup still combine with the modes which do one
+        for args in [
+            &[
+                "--watch",
+                "60",
+                "--consensus",
+                "--state-file",
+                "x",
+                "--update-hosts",
+                "h",
+            ][..],
+            &["--count", "3", "--consensus", "--http-fallback", "http://x"],
+            &["--compare", "x", "--check-ipv6", "--json"],
+            &["--turn", "--with-port", "--env-output"],
+        ] {
+            parse(args).unwrap();
+        }
+    }
+
+    #[test]
+    fn help_lists_exit_codes() {
+        let help = Args::command().render_long_help().to_string();
+        for code in [
+            EXIT_FAILURE,
+            EXIT_USAGE,
+            EXIT_RESOLVE,
+            EXIT_TIMEOUT,
+            EXIT_DECODE,
+            EXIT_NOT_GLOBAL,
+            EXIT_SERVER_ERROR,
+            EXIT_BIND,
+            EXIT_ALL_FAILED,
+            EXIT_CHANGED,
+        ] {
+            let line = format!("\n  {:<4}", code);
+            assert!(
+                help.contains(&line),
+                "exit code {} missing from --help",
+                code
+            );
         }
     }
-    bail!("Timed out waiting for response from {:?}", dest)
 }
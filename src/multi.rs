@@ -0,0 +1,275 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::error::Result;
+use crate::health::Health;
+use crate::{
+    bind_for, eyeballs, lookup, lookup_endpoint, lookup_multiplexed, parse_endpoint,
+    resolve_endpoint_all, Binding, Family, LookupError, LookupOptions, Transport,
+};
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::{debug, warn};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use tokio::net::UdpSocket;
+
+/// Queries all of `endpoints` concurrently, each from its own socket, and returns the first
+/// successful result along with the endpoint that provided it. The remaining queries are
+/// cancelled. If every query fails, the errors are combined into the returned error.
+///
+/// UDP servers are queried over a shared socket with [`lookup_multiplexed`] instead if
+/// `shared_socket` or `dual_stack` is set, or if a fixed `local_port` is needed for several
+/// servers.
+pub async fn lookup_first(
+    endpoints: &[String],
+    family: Option<Family>,
+    opts: &LookupOptions,
+) -> Result<(String, Binding)> {
+    lookup_first_tracked(endpoints, family, opts, &mut Health::default()).await
+}
+
+/// Like [`lookup_first`], but skips any of `endpoints` which are cooling down after failing
+/// in earlier lookups, and records the outcome of each query in `health`. Queries which are
+/// cancelled because another server answered first don't count either way.
+pub async fn lookup_first_tracked(
+    endpoints: &[String],
+    family: Option<Family>,
+    opts: &LookupOptions,
+    health: &mut Health,
+) -> Result<(String, Binding)> {
+    if !health.is_empty() {
+        debug!("Server health:\n{}", health);
+    }
+    let endpoints = health.available(endpoints);
+    if endpoints.len() > 1 && (opts.shared_socket || opts.dual_stack || opts.local_port != 0) {
+        let all_udp = endpoints
+            .iter()
+            .all(|endpoint| parse_endpoint(endpoint, opts.transport).0 == Transport::Udp);
+        if all_udp {
+            // Only the winner is known, so failures are recorded if nothing answered
+            let result = lookup_multiplexed(&endpoints, family, opts).await;
+            match &result {
+                Ok((endpoint, _binding)) => health.succeeded(endpoint),
+                Err(_) => endpoints
+                    .iter()
+                    .for_each(|endpoint| health.failed(endpoint)),
+            }
+            return result;
+        }
+        if opts.local_port != 0 {
+            return Err(LookupError::InvalidOptions(
+                "A fixed local port can only be used to query several servers over UDP".to_string(),
+            ));
+        }
+    }
+    let mut pending: FuturesUnordered<_> = endpoints
+        .iter()
+        .map(|endpoint| async move { (endpoint, lookup_endpoint(endpoint, family, opts).await) })
+        .collect();
+
+    let mut errors: Vec<(&String, LookupError)> = Vec::new();
+    while let Some((endpoint, result)) = pending.next().await {
+        match result {
+            Ok(binding) => {
+                if endpoints.len() > 1 {
+                    debug!(
+                        "Got response from {} first, cancelling {} other queries",
+                        endpoint,
+                        pending.len()
+                    );
+                }
+                health.succeeded(endpoint);
+                return Ok((endpoint.clone(), binding));
+            }
+            Err(e) => {
+                if endpoints.len() > 1 {
+                    debug!("Query to {} failed: {:#}", endpoint, e);
+                }
+                health.failed(endpoint);
+                errors.push((endpoint, e))
+            }
+        }
+    }
+
+    Err(combine_errors(errors))
+}
+
+/// Queries each of `endpoints` in turn until one of them answers, and returns its result along
+/// with the endpoint that provided it. Unlike [`lookup_first`], later servers are only queried
+/// if the earlier ones fail.
+pub async fn lookup_in_turn(
+    endpoints: &[String],
+    family: Option<Family>,
+    opts: &LookupOptions,
+) -> Result<(String, Binding)> {
+    let mut errors: Vec<(&String, LookupError)> = Vec::new();
+    for (idx, endpoint) in endpoints.iter().enumerate() {
+        match lookup_endpoint(endpoint, family, opts).await {
+            Ok(binding) => return Ok((endpoint.clone(), binding)),
+            Err(e) => {
+                if idx + 1 < endpoints.len() {
+                    warn!(
+                        "Query to {} failed, trying the next server: {:#}",
+                        endpoint, e
+                    );
+                }
+                errors.push((endpoint, e));
+            }
+        }
+    }
+    Err(combine_errors(errors))
+}
+
+/// Combines the errors from querying several servers. A single error is returned as-is, and
+/// the combined error is a timeout if every server timed out.
+pub(crate) fn combine_errors(mut errors: Vec<(&String, LookupError)>) -> LookupError {
+    if errors.len() == 1 {
+        // Only one server: Return its error as-is
+        let (_endpoint, e) = errors.pop().expect("checked length");
+        return e;
+    }
+    let all_timed_out = errors
+        .iter()
+        .all(|(_endpoint, e)| matches!(e, LookupError::Timeout(_)));
+    let failed = LookupError::AllFailed(
+        errors
+            .into_iter()
+            .map(|(endpoint, e)| (endpoint.clone(), e))
+            .collect(),
+    );
+    if all_timed_out {
+        LookupError::Timeout(failed.to_string())
+    } else {
+        failed
+    }
+}
+
+/// Queries each of `endpoints` in turn and only succeeds if at least `quorum` of them report
+/// the same public IP. Any disagreement between servers is noted on stderr. The queries share
+/// a local socket (one per address family), so that differing public ports can be reported as
+/// a sign of a symmetric NAT. Returns one of the agreeing results along with the endpoint that
+/// provided it.
+pub async fn lookup_consensus(
+    endpoints: &[String],
+    family: Option<Family>,
+    quorum: usize,
+    opts: &LookupOptions,
+) -> Result<(String, Binding)> {
+    if quorum == 0 || quorum > endpoints.len() {
+        return Err(LookupError::InvalidOptions(format!(
+            "Quorum must be between 1 and the number of servers ({}), got {}",
+            endpoints.len(),
+            quorum
+        )));
+    }
+
+    // Group the successful results by the public IP that they reported
+    let mut conns: HashMap<Family, UdpSocket> = HashMap::new();
+    let mut by_ip: HashMap<IpAddr, Vec<(&String, Binding)>> = HashMap::new();
+    let mut errors: Vec<(&String, LookupError)> = Vec::new();
+    for endpoint in endpoints {
+        match lookup_shared(&mut conns, endpoint, family, opts).await {
+            Ok(binding) => by_ip
+                .entry(binding.addr.ip())
+                .or_default()
+                .push((endpoint, binding)),
+            Err(e) => {
+                warn!("Query to {} failed: {:#}", endpoint, e);
+                errors.push((endpoint, e));
+            }
+        }
+    }
+
+    if by_ip.len() > 1 {
+        warn!("Servers disagree on the public IP:");
+        for (ip, results) in &by_ip {
+            let servers: Vec<&str> = results.iter().map(|(e, _)| e.as_str()).collect();
+            warn!("  {}: {}", ip, servers.join(", "));
+        }
+    }
+
+    let (ip, mut results) = match by_ip.into_iter().max_by_key(|(_ip, results)| results.len()) {
+        Some(best) => best,
+        None => return Err(combine_errors(errors)),
+    };
+
+    // Servers agreeing on the IP but not on the port is a sign of a symmetric NAT
+    let mut ports: Vec<u16> = results
+        .iter()
+        .map(|(_, binding)| binding.addr.port())
+        .collect();
+    ports.sort_unstable();
+    ports.dedup();
+    if ports.len() > 1 {
+        warn!(
+            "Servers reported different ports for {}, possibly due to a symmetric NAT: {:?}",
+            ip, ports
+        );
+    }
+
+    if results.len() < quorum {
+        return Err(LookupError::NoQuorum {
+            ip,
+            agreed: results.len(),
+            servers: endpoints.len(),
+            quorum,
+            errors: errors
+                .into_iter()
+                .map(|(endpoint, e)| (endpoint.clone(), e))
+                .collect(),
+        });
+    }
+    debug!(
+        "{} of {} servers agree on {}",
+        results.len(),
+        endpoints.len(),
+        ip
+    );
+    let (endpoint, binding) = results.swap_remove(0);
+    Ok((endpoint.clone(), binding))
+}
+
+/// Resolves `endpoint` and queries it from the socket in `conns` matching its family,
+/// binding a socket first if needed. Each resolved address is tried in turn until one of them
+/// answers, racing IPv6 against IPv4 for dual-stack endpoints. Connection-based transports don't
+/// have a socket to share, so they use a new connection for each query.
+async fn lookup_shared(
+    conns: &mut HashMap<Family, UdpSocket>,
+    endpoint: &str,
+    family: Option<Family>,
+    opts: &LookupOptions,
+) -> Result<Binding> {
+    if parse_endpoint(endpoint, opts.transport).0 != Transport::Udp {
+        return lookup_endpoint(endpoint, family, opts).await;
+    }
+    let addrs = resolve_endpoint_all(endpoint, family, opts).await?;
+    for dest in &addrs {
+        if let Entry::Vacant(entry) = conns.entry(Family::of(dest)) {
+            entry.insert(bind_for(dest, opts).await?);
+        }
+    }
+    let conns = &*conns;
+    eyeballs::race(&addrs, |addrs| async move {
+        eyeballs::try_each(&addrs, |dest| async move {
+            lookup(&conns[&Family::of(&dest)], &dest, opts).await
+        })
+        .await
+    })
+    .await
+}
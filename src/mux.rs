@@ -0,0 +1,322 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::error::{Context, Result};
+use crate::multi::combine_errors;
+use crate::pcap;
+use crate::{
+    bind_dual_stack, bind_for, build_request, check_mapped, check_response_origin,
+    check_truncation, parse_response, resolve_endpoint, Binding, Family, LookupError,
+    LookupOptions, Reply,
+};
+use futures::future::{join_all, select_all};
+use log::{debug, warn};
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+use stun_codec::TransactionId;
+use tokio::net::UdpSocket;
+use tokio::time;
+
+/// A binding request which hasn't been answered yet
+struct Pending<'a> {
+    endpoint: &'a String,
+    dest: SocketAddr,
+    transaction_id: TransactionId,
+    request: Vec<u8>,
+    sent: Instant,
+}
+
+/// Queries all of `endpoints` over UDP from a single local socket per address family, and
+/// returns the first successful result along with the endpoint that provided it. Responses are
+/// matched to their servers by transaction ID, and unanswered requests are resent together on
+/// each timeout. Authentication challenges and redirects aren't followed.
+///
+/// If `dual_stack` is set, a single dual-stack socket is used for both families instead,
+/// unless the platform doesn't support one.
+pub async fn lookup_multiplexed(
+    endpoints: &[String],
+    family: Option<Family>,
+    opts: &LookupOptions,
+) -> Result<(String, Binding)> {
+    if opts.retries == 0 {
+        return Err(LookupError::InvalidOptions(
+            "Retries must be at least 1".to_string(),
+        ));
+    }
+    let mut errors: Vec<(&String, LookupError)> = Vec::new();
+    let resolved =
+        join_all(endpoints.iter().map(|endpoint| async move {
+            (endpoint, resolve_endpoint(endpoint, family, opts).await)
+        }))
+        .await;
+
+    // A dual-stack socket only helps if the servers can be in either family
+    let dual_stack = match (opts.dual_stack, family, opts.local_addr) {
+        (true, None, None) => match bind_dual_stack(opts).await {
+            Ok(conn) => Some(conn),
+            Err(e) => {
+                debug!("Falling back to a socket per address family: {:#}", e);
+                None
+            }
+        },
+        _ => None,
+    };
+    let is_dual_stack = dual_stack.is_some();
+    let mut conns: Vec<UdpSocket> = dual_stack.into_iter().collect();
+    let mut pending: Vec<Pending> = Vec::new();
+    for (endpoint, result) in resolved {
+        let dest = match result {
+            Ok(dest) => dest,
+            Err(e) => {
+                debug!("Query to {} failed: {:#}", endpoint, e);
+                errors.push((endpoint, e));
+                continue;
+            }
+        };
+        if !is_dual_stack && conn_for(&conns, &dest).is_none() {
+            conns.push(bind_for(&dest, opts).await?);
+        }
+        let (transaction_id, request) = build_request(opts, None)?;
+        pending.push(Pending {
+            endpoint,
+            dest,
+            transaction_id,
+            request,
+            sent: Instant::now(),
+        });
+    }
+
+    let mut recvbuf = vec![0u8; opts.recv_buffer];
+    let schedule = opts.timeout_schedule();
+    let expiry = opts
+        .deadline
+        .map(|deadline| time::Instant::now() + deadline);
+    let mut expired = false;
+    for (attempt, timeout) in schedule.iter().enumerate() {
+        if pending.is_empty() || expired {
+            break;
+        }
+        // (Re)send every unanswered request, reusing its transaction ID
+        for query in pending.iter_mut() {
+            let (conn, target) = if is_dual_stack {
+                (&conns[0], to_ipv6(query.dest))
+            } else {
+                let conn = conn_for(&conns, &query.dest).expect("bound a socket for each family");
+                (conn, query.dest)
+            };
+            query.sent = Instant::now();
+            conn.send_to(&query.request, target)
+                .await
+                .with_context(|| format!("Failed to send STUN request to {}", query.dest))?;
+            pcap::sent(opts.pcap.as_deref(), conn, &target, &query.request);
+        }
+
+        let mut wait_until = time::Instant::now() + *timeout;
+        if let Some(expiry) = expiry {
+            if expiry <= wait_until {
+                wait_until = expiry;
+                expired = true;
+            }
+        }
+        while !pending.is_empty() {
+            let (recvsize, origin, conn) =
+                match time::timeout_at(wait_until, recv_any(&conns, &mut recvbuf)).await {
+                    Ok(result) => result.context("Failed to receive STUN response")?,
+                    Err(_elapsed) => break,
+                };
+            pcap::received(opts.pcap.as_deref(), conn, &origin, &recvbuf[..recvsize]);
+            // IPv4 servers reply from IPv4-mapped addresses on a dual-stack socket
+            let origin = SocketAddr::new(origin.ip().to_canonical(), origin.port());
+            let response = &recvbuf[..recvsize];
+            let idx = match transaction_id_of(response).and_then(|id| {
+                pending
+                    .iter()
+                    .position(|query| query.transaction_id.as_bytes() == id)
+            }) {
+                Some(idx) => idx,
+                None => {
+                    debug!(
+                        "Ignoring response from {} with an unknown transaction ID",
+                        origin
+                    );
+                    continue;
+                }
+            };
+            if pending[idx].dest != origin {
+                warn!(
+                    "Response origin {:?} doesn't match request target {:?}",
+                    origin, pending[idx].dest
+                );
+                continue;
+            }
+
+            let query = pending.remove(idx);
+            let rtt = query.sent.elapsed();
+            let parsed = check_truncation(recvsize, &recvbuf, &origin)
+                .and_then(|()| parse_response(query.transaction_id, response, rtt, opts, None));
+            let result = match parsed {
+                Ok(Reply::Mapped(binding)) => {
+                    check_response_origin(&binding, &origin);
+                    check_mapped(&binding, &origin, opts).map(|()| binding)
+                }
+                Ok(Reply::Challenge(_)) => Err(LookupError::Auth(
+                    "Server requires authentication, which isn't supported with a shared socket"
+                        .to_string(),
+                )),
+                Ok(Reply::Redirect(next)) => Err(LookupError::Redirect(format!(
+                    "Server redirected to {}, which isn't followed with a shared socket",
+                    next
+                ))),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(binding) => {
+                    debug!(
+                        "Got response from {} first, cancelling {} other queries",
+                        query.endpoint,
+                        pending.len()
+                    );
+                    return Ok((query.endpoint.clone(), binding));
+                }
+                Err(e) => {
+                    debug!("Query to {} failed: {:#}", query.endpoint, e);
+                    errors.push((query.endpoint, e));
+                }
+            }
+        }
+
+        if !pending.is_empty() && !expired {
+            let timeout_ms = timeout.as_millis();
+            if attempt + 1 == schedule.len() {
+                warn!("Timed out after {}ms, giving up.", timeout_ms);
+            } else {
+                warn!(
+                    "Timed out after {}ms, trying {} servers again...",
+                    timeout_ms,
+                    pending.len()
+                );
+            }
+        }
+    }
+
+    for query in pending {
+        let message = match opts.deadline {
+            Some(deadline) if expired => format!(
+                "Deadline of {}ms exceeded waiting for response from {:?}",
+                deadline.as_millis(),
+                query.dest
+            ),
+            _ => format!(
+                "Retries exhausted after {} attempts waiting for response from {:?}",
+                schedule.len(),
+                query.dest
+            ),
+        };
+        errors.push((query.endpoint, LookupError::Timeout(message)));
+    }
+    Err(combine_errors(errors))
+}
+
+/// Returns the socket in `conns` which is in the same address family as `dest`
+fn conn_for<'a>(conns: &'a [UdpSocket], dest: &SocketAddr) -> Option<&'a UdpSocket> {
+    conns.iter().find(|conn| match conn.local_addr() {
+        Ok(local) => Family::of(&local) == Family::of(dest),
+        Err(_) => false,
+    })
+}
+
+/// Returns `addr` as an IPv6 address, mapping IPv4 addresses into `::ffff:0:0/96`
+fn to_ipv6(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) => SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+        SocketAddr::V6(_) => addr,
+    }
+}
+
+/// Returns the transaction ID from the header of an encoded STUN message, if it's long enough
+fn transaction_id_of(message: &[u8]) -> Option<&[u8]> {
+    message.get(8..20)
+}
+
+/// Receives the next datagram on any of `conns`, returning its size and origin along with the
+/// socket which received it
+async fn recv_any<'a>(
+    conns: &'a [UdpSocket],
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, &'a UdpSocket)> {
+    loop {
+        let (ready, idx, _others) =
+            select_all(conns.iter().map(|conn| Box::pin(conn.readable()))).await;
+        ready?;
+        match conns[idx].try_recv_from(buf) {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+            result => return result.map(|(size, origin)| (size, origin, &conns[idx])),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{self, MockServer};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn shared_socket_skips_dead_server() {
+        let dead = mock::dead_addr();
+        let server = MockServer::spawn().await;
+        let live = server.addr;
+        let opts = LookupOptions {
+            retries: 2,
+            base_timeout: Duration::from_millis(100),
+            final_timeout_factor: 2,
+            ..LookupOptions::default()
+        };
+        let endpoints = vec![dead.to_string(), live.to_string()];
+        let (endpoint, binding) = lookup_multiplexed(&endpoints, None, &opts).await.unwrap();
+        assert_eq!(endpoint, live.to_string());
+        assert!(binding.addr.ip().is_loopback());
+
+        let endpoints = vec![dead.to_string(), dead.to_string()];
+        let err = lookup_multiplexed(&endpoints, None, &opts)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, LookupError::Timeout(_)));
+    }
+
+    #[tokio::test]
+    async fn dual_stack_socket_reaches_ipv4_server() {
+        let server = MockServer::spawn().await;
+        let live = server.addr;
+        let opts = LookupOptions {
+            retries: 2,
+            final_timeout_factor: 2,
+            base_timeout: Duration::from_millis(100),
+            dual_stack: true,
+            ..LookupOptions::default()
+        };
+        let endpoints = vec![live.to_string()];
+        let (_, binding) = lookup_multiplexed(&endpoints, None, &opts).await.unwrap();
+        assert!(binding.addr.ip().is_loopback());
+        assert_eq!(
+            to_ipv6("192.0.2.1:3478".parse().unwrap()),
+            "[::ffff:192.0.2.1]:3478".parse::<SocketAddr>().unwrap()
+        );
+    }
+}
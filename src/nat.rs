@@ -0,0 +1,777 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::attributes::Attribute;
+use crate::error::{Context, Result};
+use crate::{
+    bind_for, bind_std, build_request_with, check_truncation, decode_response, error_response,
+    lookup, mapped_address, other_address, parse_endpoint, resolve_endpoint, within_deadline,
+    Family, LookupError, LookupOptions, Transport,
+};
+use crate::{hexdump, pcap};
+use bytecodec::DecodeExt;
+use log::{debug, warn};
+use serde::Serialize;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use stun_codec::rfc5389::attributes::ErrorCode;
+use stun_codec::rfc5389::{errors, methods};
+use stun_codec::rfc5780::attributes::ChangeRequest;
+use stun_codec::{Message, MessageClass, MessageDecoder};
+use tokio::net::UdpSocket;
+use tokio::time;
+
+/// How the NAT assigns public addresses to a local socket (RFC 5780 section 4.3)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mapping {
+    /// The public address is the local address, there's no NAT
+    NoNat,
+    /// The same public address is used for all destinations
+    EndpointIndependent,
+    /// A different public address is used for each destination IP
+    AddressDependent,
+    /// A different public address is used for each destination IP and port
+    AddressAndPortDependent,
+}
+
+/// Which inbound packets the NAT passes to a local socket (RFC 5780 section 4.4)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Filtering {
+    /// Packets from any address are accepted
+    EndpointIndependent,
+    /// Only packets from IPs the socket has sent to are accepted
+    AddressDependent,
+    /// Only packets from IPs and ports the socket has sent to are accepted
+    AddressAndPortDependent,
+}
+
+/// The public address and NAT behavior found by `detect_nat`
+#[derive(Clone, Debug, Serialize)]
+pub struct NatBehavior {
+    /// Public address reported for the primary server address
+    pub public: SocketAddr,
+    /// The server's alternate address, which was used for the tests
+    pub other_address: SocketAddr,
+    pub mapping: Mapping,
+    pub filtering: Filtering,
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mapping::NoNat => write!(f, "no NAT"),
+            Mapping::EndpointIndependent => write!(f, "endpoint-independent mapping"),
+            Mapping::AddressDependent => write!(f, "address-dependent mapping"),
+            Mapping::AddressAndPortDependent => write!(f, "address and port-dependent mapping"),
+        }
+    }
+}
+
+impl fmt::Display for Filtering {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Filtering::EndpointIndependent => write!(f, "endpoint-independent filtering"),
+            Filtering::AddressDependent => write!(f, "address-dependent filtering"),
+            Filtering::AddressAndPortDependent => {
+                write!(f, "address and port-dependent filtering")
+            }
+        }
+    }
+}
+
+impl fmt::Display for MappingBehavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.mapping)?;
+        for test in &self.tests {
+            write!(f, "\n{} reported {}", test.server, test.public)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Hairpin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.origin {
+            Some(origin) => write!(
+                f,
+                "hairpinning: yes, a request to {} reached {} from {}",
+                self.public, self.local, origin
+            ),
+            None => write!(
+                f,
+                "hairpinning: no, a request to {} never reached {}",
+                self.public, self.local
+            ),
+        }
+    }
+}
+
+impl fmt::Display for SymmetricCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.symmetric {
+            write!(f, "symmetric NAT: yes, the servers saw different ports")?;
+        } else {
+            write!(f, "symmetric NAT: no, both servers saw the same port")?;
+        }
+        for test in &self.tests {
+            write!(f, "\n{} reported {}", test.server, test.public)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for FilteringBehavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.filtering)?;
+        for test in &self.tests {
+            let change = match (test.change_ip, test.change_port) {
+                (true, true) => "IP and port",
+                (true, false) => "IP",
+                (false, _) => "port",
+            };
+            match test.response_origin {
+                Some(origin) => write!(f, "\nchanged {}: response from {}", change, origin)?,
+                None => write!(f, "\nchanged {}: no response (filtered)", change)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for NatBehavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}, {}", self.mapping, self.filtering)
+    }
+}
+
+/// The result of `detect_mapping`
+#[derive(Clone, Debug, Serialize)]
+pub struct MappingBehavior {
+    /// Public address reported for the primary server address
+    pub public: SocketAddr,
+    /// The server's alternate address, which was used for the tests
+    pub other_address: SocketAddr,
+    pub mapping: Mapping,
+    /// The public address reported for each server address that was queried, in order
+    pub tests: Vec<MappingTest>,
+}
+
+/// The public address which a server address reported during mapping tests
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct MappingTest {
+    pub server: SocketAddr,
+    pub public: SocketAddr,
+}
+
+/// The result of `detect_filtering`
+#[derive(Clone, Debug, Serialize)]
+pub struct FilteringBehavior {
+    /// Public address reported for the primary server address
+    pub public: SocketAddr,
+    /// The server's alternate address, which it was asked to respond from
+    pub other_address: SocketAddr,
+    pub filtering: Filtering,
+    /// Each CHANGE-REQUEST that was sent and whether the response got through, in order
+    pub tests: Vec<FilteringTest>,
+}
+
+/// A request for the server to respond from a different address, and where the response
+/// came from if it got through the NAT
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct FilteringTest {
+    pub change_ip: bool,
+    pub change_port: bool,
+    /// `None` if no response arrived before the retry schedule ran out
+    pub response_origin: Option<SocketAddr>,
+}
+
+/// The result of `check_symmetric`
+#[derive(Clone, Debug, Serialize)]
+pub struct SymmetricCheck {
+    /// The local socket which queried both servers
+    pub local: SocketAddr,
+    /// Whether the servers reported different public ports for the same local socket
+    pub symmetric: bool,
+    /// The public address reported by each server, in order
+    pub tests: Vec<MappingTest>,
+}
+
+/// The result of `test_hairpin`
+#[derive(Clone, Debug, Serialize)]
+pub struct Hairpin {
+    /// The local socket whose public address was the target of the test
+    pub local: SocketAddr,
+    /// The public address reported by the server for `local`
+    pub public: SocketAddr,
+    /// Whether a request sent to `public` from a second local socket arrived at `local`
+    pub supported: bool,
+    /// Where the looped-back request appeared to come from, if it arrived
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origin: Option<SocketAddr>,
+}
+
+/// Classifies the local NAT's mapping and filtering behavior using the RFC 5780 test sequence.
+/// The server at `endpoint` must support RFC 5780, advertising a second address with
+/// OTHER-ADDRESS and honoring CHANGE-REQUEST. Only UDP is supported.
+///
+/// Filtering tests expect some requests to go unanswered, so each of those waits for the full
+/// retry schedule in `opts` before concluding that the NAT dropped the response.
+pub async fn detect_nat(
+    endpoint: &str,
+    family: Option<Family>,
+    opts: &LookupOptions,
+) -> Result<NatBehavior> {
+    let session = Session::start(endpoint, family, opts).await?;
+    let (mapping, _tests) = session.test_mapping(opts).await?;
+    let (filtering, _tests) = session.test_filtering(opts).await?;
+    Ok(NatBehavior {
+        public: session.public,
+        other_address: session.other,
+        mapping,
+        filtering,
+    })
+}
+
+/// Classifies only the local NAT's mapping behavior, by comparing the public addresses which
+/// the primary and alternate addresses of the server at `endpoint` report for the same local
+/// socket. Like [`detect_nat`], the server must advertise OTHER-ADDRESS, but CHANGE-REQUEST
+/// isn't needed and every test expects a response.
+pub async fn detect_mapping(
+    endpoint: &str,
+    family: Option<Family>,
+    opts: &LookupOptions,
+) -> Result<MappingBehavior> {
+    let session = Session::start(endpoint, family, opts).await?;
+    let (mapping, tests) = session.test_mapping(opts).await?;
+    Ok(MappingBehavior {
+        public: session.public,
+        other_address: session.other,
+        mapping,
+        tests,
+    })
+}
+
+/// Classifies only the local NAT's filtering behavior, by asking the server at `endpoint` to
+/// respond from its alternate IP and/or port with CHANGE-REQUEST and checking which responses
+/// get through. Like [`detect_nat`], the server must support RFC 5780, and an unanswered test
+/// waits for the full retry schedule in `opts` before it's counted as filtered.
+pub async fn detect_filtering(
+    endpoint: &str,
+    family: Option<Family>,
+    opts: &LookupOptions,
+) -> Result<FilteringBehavior> {
+    let session = Session::start(endpoint, family, opts).await?;
+    let (filtering, tests) = session.test_filtering(opts).await?;
+    Ok(FilteringBehavior {
+        public: session.public,
+        other_address: session.other,
+        filtering,
+        tests,
+    })
+}
+
+/// Checks for a symmetric NAT by querying two independent servers from the same local socket
+/// and comparing the public ports they report. Unlike [`detect_mapping`], this works with any
+/// STUN servers, but it can't tell address-dependent from address and port-dependent mapping.
+/// Only UDP is supported.
+pub async fn check_symmetric(
+    first: &str,
+    second: &str,
+    family: Option<Family>,
+    opts: &LookupOptions,
+) -> Result<SymmetricCheck> {
+    for endpoint in [first, second] {
+        if parse_endpoint(endpoint, opts.transport).0 != Transport::Udp {
+            return Err(LookupError::Unsupported(
+                "The symmetric NAT check is only supported over UDP".to_string(),
+            ));
+        }
+    }
+    let dest1 = resolve_endpoint(first, family, opts).await?;
+    // Both servers must be reached from the same socket, so they need the same family
+    let dest2 = resolve_endpoint(second, Some(Family::of(&dest1)), opts).await?;
+    if dest1 == dest2 {
+        return Err(LookupError::InvalidOptions(format!(
+            "{} and {} are the same server ({})",
+            first, second, dest1
+        )));
+    }
+    let conn = bind_toward(&dest1, opts).await?;
+    let local = conn.local_addr()?;
+
+    let mut tests = Vec::new();
+    for dest in [dest1, dest2] {
+        let binding = lookup(&conn, &dest, opts).await?;
+        debug!("Symmetric check: {} -> {}", dest, binding.addr);
+        tests.push(MappingTest {
+            server: dest,
+            public: binding.addr,
+        });
+    }
+    Ok(SymmetricCheck {
+        local,
+        symmetric: tests[0].public.port() != tests[1].public.port(),
+        tests,
+    })
+}
+
+/// Checks whether the local NAT supports hairpinning, following RFC 5780 section 4.5: after
+/// `endpoint` reports the public address of one local socket, a second local socket sends a
+/// binding request to that public address, and the test passes if the first socket receives
+/// it. The request is resent according to the retry schedule in `opts`, and a failure waits for
+/// all of it, unless the configured `deadline` passes first.
+///
+/// The NAT's filtering applies to looped-back packets too, so a NAT which doesn't use
+/// endpoint-independent filtering may drop the request even if it can hairpin. Without a NAT,
+/// the request just goes to the local socket directly.
+pub async fn test_hairpin(
+    endpoint: &str,
+    family: Option<Family>,
+    opts: &LookupOptions,
+) -> Result<Hairpin> {
+    if parse_endpoint(endpoint, opts.transport).0 != Transport::Udp {
+        return Err(LookupError::Unsupported(
+            "The hairpinning test is only supported over UDP".to_string(),
+        ));
+    }
+    let dest = resolve_endpoint(endpoint, family, opts).await?;
+    let conn = bind_toward(&dest, opts).await?;
+    let local = conn.local_addr()?;
+    let public = lookup(&conn, &dest, opts).await?.addr;
+    debug!("Hairpinning test: {} is mapped to {}", local, public);
+
+    // The second socket can't reuse --local-port, which the first socket already holds
+    let sender_addr = SocketAddr::new(local.ip(), 0);
+    let sender = bind_std(&sender_addr, opts.interface.as_deref())?;
+    sender.set_nonblocking(true)?;
+    let sender = UdpSocket::from_std(sender)
+        .with_context(|| format!("Failed to bind local socket {}", sender_addr))?;
+
+    let (transaction_id, message_bytes) = build_request_with(opts, None, Vec::new())?;
+    let mut recvbuf = vec![0u8; opts.recv_buffer];
+    let attempts = async {
+        for timeout in opts.timeout_schedule() {
+            sender
+                .send_to(&message_bytes, public)
+                .await
+                .with_context(|| format!("Failed to send STUN request to {}", public))?;
+            pcap::sent(opts.pcap.as_deref(), &sender, &public, &message_bytes);
+            let deadline = time::Instant::now() + timeout;
+            // Keep listening until the timeout, ignoring anything else which reaches the socket
+            while let Ok(result) = time::timeout_at(deadline, conn.recv_from(&mut recvbuf)).await {
+                let (recvsize, origin) =
+                    result.with_context(|| format!("Failed to receive on {}", local))?;
+                pcap::received(opts.pcap.as_deref(), &conn, &origin, &recvbuf[..recvsize]);
+                hexdump::received(&recvbuf[..recvsize]);
+                match MessageDecoder::<Attribute>::new().decode_from_bytes(&recvbuf[..recvsize]) {
+                    Ok(Ok(request))
+                        if request.class() == MessageClass::Request
+                            && request.method() == methods::BINDING
+                            && request.transaction_id() == transaction_id =>
+                    {
+                        debug!("Hairpinning test: request arrived from {}", origin);
+                        return Ok(Some(origin));
+                    }
+                    _ => debug!("Ignoring {} bytes from {}", recvsize, origin),
+                }
+            }
+        }
+        Ok(None)
+    };
+    let origin = within_deadline(&public, opts, attempts).await?;
+    Ok(Hairpin {
+        local,
+        public,
+        supported: origin.is_some(),
+        origin,
+    })
+}
+
+/// A local socket which has queried the primary address of an RFC 5780 server
+struct Session {
+    conn: UdpSocket,
+    local: SocketAddr,
+    primary: SocketAddr,
+    /// Public address reported by the primary address
+    public: SocketAddr,
+    /// The server's alternate address
+    other: SocketAddr,
+}
+
+impl Session {
+    /// Runs mapping test I against `endpoint`, which also finds the alternate address
+    async fn start(endpoint: &str, family: Option<Family>, opts: &LookupOptions) -> Result<Self> {
+        if parse_endpoint(endpoint, opts.transport).0 != Transport::Udp {
+            return Err(LookupError::Unsupported(
+                "NAT type detection is only supported over UDP".to_string(),
+            ));
+        }
+        let primary = resolve_endpoint(endpoint, family, opts).await?;
+        let conn = bind_toward(&primary, opts).await?;
+        let local = conn.local_addr()?;
+
+        let (response, _origin) = probe(&conn, &primary, None, opts)
+            .await?
+            .ok_or_else(|| LookupError::Timeout(format!("No response from {}", primary)))?;
+        let public = mapped(&response)?;
+        let other = other_address(&response).ok_or_else(|| {
+            LookupError::Unsupported(format!(
+                "Server {} doesn't support NAT behavior discovery (no OTHER-ADDRESS in response)",
+                primary
+            ))
+        })?;
+        if other.ip() == primary.ip() || other.port() == primary.port() {
+            return Err(LookupError::Decode(format!(
+                "Server {} reported OTHER-ADDRESS {}, which must differ in both IP and port",
+                primary, other
+            )));
+        }
+        debug!(
+            "Mapping test I: {} -> {}, alternate address {}",
+            primary, public, other
+        );
+        Ok(Session {
+            conn,
+            local,
+            primary,
+            public,
+            other,
+        })
+    }
+
+    /// Runs mapping tests II and III as needed, returning the mapping behavior and the public
+    /// address reported in each test including test I
+    async fn test_mapping(&self, opts: &LookupOptions) -> Result<(Mapping, Vec<MappingTest>)> {
+        let mut tests = vec![MappingTest {
+            server: self.primary,
+            public: self.public,
+        }];
+        if self.public == self.local {
+            return Ok((Mapping::NoNat, tests));
+        }
+
+        // Mapping test II: Alternate IP, primary port
+        let dest = SocketAddr::new(self.other.ip(), self.primary.port());
+        let public2 = probe_mapped(&self.conn, &dest, opts).await?;
+        debug!("Mapping test II: {} -> {}", dest, public2);
+        tests.push(MappingTest {
+            server: dest,
+            public: public2,
+        });
+        if public2 == self.public {
+            return Ok((Mapping::EndpointIndependent, tests));
+        }
+
+        // Mapping test III: Alternate IP and port
+        let public3 = probe_mapped(&self.conn, &self.other, opts).await?;
+        debug!("Mapping test III: {} -> {}", self.other, public3);
+        tests.push(MappingTest {
+            server: self.other,
+            public: public3,
+        });
+        let mapping = if public3 == public2 {
+            Mapping::AddressDependent
+        } else {
+            Mapping::AddressAndPortDependent
+        };
+        Ok((mapping, tests))
+    }
+
+    /// Runs filtering tests II and III as needed, returning the filtering behavior and the
+    /// outcome of each test
+    async fn test_filtering(
+        &self,
+        opts: &LookupOptions,
+    ) -> Result<(Filtering, Vec<FilteringTest>)> {
+        // Filtering test II: Ask for the response to come from the alternate IP and port
+        let test2 = probe_changed(&self.conn, &self.primary, true, true, opts).await?;
+        let mut tests = vec![test2];
+        if test2.response_origin.is_some() {
+            return Ok((Filtering::EndpointIndependent, tests));
+        }
+
+        // Filtering test III: Ask for the response to come from the alternate port only
+        let test3 = probe_changed(&self.conn, &self.primary, false, true, opts).await?;
+        tests.push(test3);
+        let filtering = if test3.response_origin.is_some() {
+            Filtering::AddressDependent
+        } else {
+            Filtering::AddressAndPortDependent
+        };
+        Ok((filtering, tests))
+    }
+}
+
+/// Binds a UDP socket to the local IP which would be used to reach `dest`, so that its local
+/// address can be compared against the public address to detect the absence of a NAT.
+/// The configured local address and port are used if any.
+async fn bind_toward(dest: &SocketAddr, opts: &LookupOptions) -> Result<UdpSocket> {
+    if opts.local_addr.is_some() {
+        return bind_for(dest, opts).await;
+    }
+    let unspecified: SocketAddr = if dest.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    // Connecting a UDP socket doesn't send anything, but picks the outgoing interface
+    let route = bind_std(&unspecified, opts.interface.as_deref())?;
+    route
+        .connect(dest)
+        .with_context(|| format!("No route to {}", dest))?;
+    let local_addr = SocketAddr::new(route.local_addr()?.ip(), opts.local_port);
+    let socket = bind_std(&local_addr, opts.interface.as_deref())?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket)
+        .with_context(|| format!("Failed to bind local socket {}", local_addr))
+}
+
+/// Sends a request to `dest` and returns the mapped address in its response, failing if there
+/// was no response
+async fn probe_mapped(
+    conn: &UdpSocket,
+    dest: &SocketAddr,
+    opts: &LookupOptions,
+) -> Result<SocketAddr> {
+    let (response, _origin) = probe(conn, dest, None, opts).await?.ok_or_else(|| {
+        LookupError::Timeout(format!("No response from alternate address {}", dest))
+    })?;
+    mapped(&response)
+}
+
+/// Sends a request to `dest` with a CHANGE-REQUEST for the response to come from a different
+/// IP and/or port, returning whether a response made it through the NAT
+async fn probe_changed(
+    conn: &UdpSocket,
+    dest: &SocketAddr,
+    ip: bool,
+    port: bool,
+    opts: &LookupOptions,
+) -> Result<FilteringTest> {
+    let change = ChangeRequest::new(ip, port);
+    let response_origin = match probe(conn, dest, Some(change), opts).await? {
+        Some((response, origin)) => {
+            // Make sure that the server honored the request, or the test is meaningless
+            if let Some(error) = response.get_attribute::<ErrorCode>() {
+                if error.code() == errors::UnknownAttribute::CODEPOINT {
+                    return Err(LookupError::Unsupported(format!(
+                        "Server {} doesn't support CHANGE-REQUEST (STUN error {}: {})",
+                        dest,
+                        error.code(),
+                        error.reason_phrase()
+                    )));
+                }
+            }
+            mapped(&response).map_err(|e| {
+                LookupError::Unsupported(format!("Server {} rejected CHANGE-REQUEST: {}", dest, e))
+            })?;
+            if (ip && origin.ip() == dest.ip()) || (port && origin.port() == dest.port()) {
+                return Err(LookupError::Unsupported(format!(
+                    "Server {} doesn't support CHANGE-REQUEST: it ignored the request and \
+                     responded from {}",
+                    dest, origin
+                )));
+            }
+            debug!(
+                "Filtering test (change ip={}, port={}): response from {}",
+                ip, port, origin
+            );
+            Some(origin)
+        }
+        None => {
+            debug!(
+                "Filtering test (change ip={}, port={}): no response, treating as filtered",
+                ip, port
+            );
+            None
+        }
+    };
+    Ok(FilteringTest {
+        change_ip: ip,
+        change_port: port,
+        response_origin,
+    })
+}
+
+/// Sends a binding request to `dest`, resending according to the retry schedule, and returns
+/// the first matching response along with where it came from. Unlike `lookup`, responses are
+/// accepted from any address, since CHANGE-REQUEST asks the server to respond from elsewhere.
+/// Returns `None` if no response arrived, or fails if the configured `deadline` passes first.
+async fn probe(
+    conn: &UdpSocket,
+    dest: &SocketAddr,
+    change: Option<ChangeRequest>,
+    opts: &LookupOptions,
+) -> Result<Option<(Message<Attribute>, SocketAddr)>> {
+    let extra = change.into_iter().map(Attribute::from).collect();
+    let (transaction_id, message_bytes) = build_request_with(opts, None, extra)?;
+    let mut recvbuf = vec![0u8; opts.recv_buffer];
+    let attempts = async {
+        for timeout in opts.timeout_schedule() {
+            conn.send_to(&message_bytes, dest)
+                .await
+                .with_context(|| format!("Failed to send STUN request to {}", dest))?;
+            pcap::sent(opts.pcap.as_deref(), conn, dest, &message_bytes);
+            let deadline = time::Instant::now() + timeout;
+            // Keep listening until the timeout, ignoring stray responses to earlier tests
+            while let Ok(result) = time::timeout_at(deadline, conn.recv_from(&mut recvbuf)).await {
+                let (recvsize, origin) = result
+                    .with_context(|| format!("Failed to receive STUN response from {}", dest))?;
+                pcap::received(opts.pcap.as_deref(), conn, &origin, &recvbuf[..recvsize]);
+                if let Err(e) = check_truncation(recvsize, &recvbuf, &origin) {
+                    warn!("Ignoring response: {:#}", e);
+                    continue;
+                }
+                match decode_response(transaction_id, methods::BINDING, &recvbuf[..recvsize]) {
+                    Ok(response) => return Ok(Some((response, origin))),
+                    Err(e) => {
+                        debug!("Ignoring response from {}: {:#}", origin, e);
+                    }
+                }
+            }
+        }
+        Ok(None)
+    };
+    within_deadline(dest, opts, attempts).await
+}
+
+/// Returns the mapped address in `response`, or the error that the server returned instead
+fn mapped(response: &Message<Attribute>) -> Result<SocketAddr> {
+    if response.class() == MessageClass::ErrorResponse {
+        return Err(error_response(response));
+    }
+    mapped_address(response).ok_or_else(|| {
+        LookupError::NoAddress(format!(
+            "No address attribute found in response: {:?}",
+            response
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockServer;
+    use std::time::Duration;
+    use stun_codec::rfc5389;
+
+    #[tokio::test]
+    async fn unknown_change_request_is_reported() {
+        let server = MockServer::spawn_with(|request, _peer| {
+            let mut response = Message::new(
+                MessageClass::ErrorResponse,
+                methods::BINDING,
+                request.transaction_id(),
+            );
+            response.add_attribute(rfc5389::Attribute::ErrorCode(
+                errors::UnknownAttribute.into(),
+            ));
+            Some(response)
+        })
+        .await;
+        let server_addr = server.addr;
+
+        let conn = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let opts = LookupOptions {
+            retries: 1,
+            base_timeout: Duration::from_millis(500),
+            ..LookupOptions::default()
+        };
+        let err = probe_changed(&conn, &server_addr, true, true, &opts)
+            .await
+            .expect_err("420 response should fail");
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Server {} doesn't support CHANGE-REQUEST (STUN error 420: Unknown Attribute)",
+                server_addr
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_stray_is_ignored() {
+        let server = MockServer::spawn().await;
+        let conn = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let opts = LookupOptions {
+            retries: 1,
+            base_timeout: Duration::from_millis(500),
+            ..LookupOptions::default()
+        };
+        let stray = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        stray
+            .send_to(&vec![0u8; opts.recv_buffer], conn.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (_response, origin) = probe(&conn, &server.addr, None, &opts)
+            .await
+            .unwrap()
+            .expect("the real response should follow the stray");
+        assert_eq!(origin, server.addr);
+    }
+
+    #[tokio::test]
+    async fn deadline_cuts_probe_short() {
+        let conn = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dead = crate::mock::dead_addr();
+        let opts = LookupOptions {
+            retries: 2,
+            base_timeout: Duration::from_millis(50),
+            ..LookupOptions::default()
+        };
+        let result = probe(&conn, &dead, None, &opts).await.unwrap();
+        assert!(result.is_none());
+
+        let opts = LookupOptions {
+            deadline: Some(Duration::from_millis(60)),
+            ..opts
+        };
+        let err = probe(&conn, &dead, None, &opts).await.unwrap_err();
+        assert!(err.to_string().starts_with("Deadline of 60ms exceeded"));
+        assert!(matches!(err, LookupError::Timeout(_)));
+    }
+
+    #[test]
+    fn lists_filtering_tests() {
+        let behavior = FilteringBehavior {
+            public: "203.0.113.5:4000".parse().unwrap(),
+            other_address: "198.51.100.2:3479".parse().unwrap(),
+            filtering: Filtering::AddressDependent,
+            tests: vec![
+                FilteringTest {
+                    change_ip: true,
+                    change_port: true,
+                    response_origin: None,
+                },
+                FilteringTest {
+                    change_ip: false,
+                    change_port: true,
+                    response_origin: Some("198.51.100.1:3479".parse().unwrap()),
+                },
+            ],
+        };
+        assert_eq!(
+            behavior.to_string(),
+            "address-dependent filtering\n\
+             changed IP and port: no response (filtered)\n\
+             changed port: response from 198.51.100.1:3479"
+        );
+    }
+}
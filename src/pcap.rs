@@ -0,0 +1,284 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::error::{Context, Result};
+use log::warn;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket as StdUdpSocket};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+
+/// Link type of the capture, each packet has an Ethernet header
+const LINKTYPE_ETHERNET: u32 = 1;
+/// Synthetic MAC addresses for the local host and the remote server
+const LOCAL_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+const REMOTE_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x02];
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const PROTOCOL_UDP: u8 = 17;
+const TTL: u8 = 64;
+
+/// Writes the UDP datagrams which are sent and received to a pcap file, wrapped in synthetic
+/// Ethernet, IP and UDP headers so that it can be opened in e.g. Wireshark
+pub struct PcapWriter {
+    state: Mutex<State>,
+}
+
+struct State {
+    file: File,
+    /// Source IPs of the routes to destinations, for sockets bound to an unspecified address
+    routes: HashMap<IpAddr, IpAddr>,
+}
+
+impl fmt::Debug for PcapWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PcapWriter").finish_non_exhaustive()
+    }
+}
+
+impl PcapWriter {
+    /// Creates or truncates the capture file at `path`
+    pub fn create(path: &Path) -> Result<PcapWriter> {
+        let mut file =
+            File::create(path).with_context(|| format!("Failed to create pcap file {:?}", path))?;
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&0xa1b2_c3d4_u32.to_le_bytes());
+        header.extend_from_slice(&2_u16.to_le_bytes());
+        header.extend_from_slice(&4_u16.to_le_bytes());
+        // Timezone offset and timestamp accuracy, both always 0
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&65535_u32.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        file.write_all(&header)
+            .with_context(|| format!("Failed to write pcap file {:?}", path))?;
+        Ok(PcapWriter {
+            state: Mutex::new(State {
+                file,
+                routes: HashMap::new(),
+            }),
+        })
+    }
+
+    /// Records `payload` being sent from `local` to `remote`
+    fn record(&self, local: SocketAddr, remote: SocketAddr, payload: &[u8], sent: bool) {
+        let mut state = self.state.lock().expect("pcap lock isn't poisoned");
+        let local = state.local_for(local, &remote);
+        let frame = if sent {
+            frame(LOCAL_MAC, REMOTE_MAC, local, remote, payload)
+        } else {
+            frame(REMOTE_MAC, LOCAL_MAC, remote, local, payload)
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut record = Vec::with_capacity(16 + frame.len());
+        record.extend_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&timestamp.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&frame);
+        if let Err(e) = state.file.write_all(&record) {
+            warn!("Failed to write to pcap file: {}", e);
+        }
+    }
+}
+
+impl State {
+    /// Returns `local` with the source IP that the OS would use to reach `remote`, if it's
+    /// unspecified or in a different family as with dual-stack sockets
+    fn local_for(&mut self, local: SocketAddr, remote: &SocketAddr) -> SocketAddr {
+        if !local.ip().is_unspecified() && local.is_ipv4() == remote.is_ipv4() {
+            return local;
+        }
+        let ip = *self
+            .routes
+            .entry(remote.ip())
+            .or_insert_with(|| route_source(remote));
+        SocketAddr::new(ip, local.port())
+    }
+}
+
+/// Returns the source IP of the route to `remote`, or the unspecified address if there's no
+/// route. Connecting a UDP socket doesn't send anything.
+fn route_source(remote: &SocketAddr) -> IpAddr {
+    let unspecified = match remote {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    StdUdpSocket::bind(SocketAddr::new(unspecified, 0))
+        .and_then(|socket| {
+            socket.connect(remote)?;
+            socket.local_addr()
+        })
+        .map_or(unspecified, |addr| addr.ip())
+}
+
+/// Records `payload` being sent on `conn` to `dest`, if `pcap` is set
+pub(crate) fn sent(pcap: Option<&PcapWriter>, conn: &UdpSocket, dest: &SocketAddr, payload: &[u8]) {
+    if let (Some(pcap), Ok(local)) = (pcap, conn.local_addr()) {
+        pcap.record(local, canonical(dest), payload, true);
+    }
+}
+
+/// Records `payload` being received on `conn` from `origin`, if `pcap` is set
+pub(crate) fn received(
+    pcap: Option<&PcapWriter>,
+    conn: &UdpSocket,
+    origin: &SocketAddr,
+    payload: &[u8],
+) {
+    if let (Some(pcap), Ok(local)) = (pcap, conn.local_addr()) {
+        pcap.record(local, canonical(origin), payload, false);
+    }
+}
+
+/// Returns `addr` with any IPv4-mapped IPv6 address converted back to IPv4
+fn canonical(addr: &SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Returns an Ethernet frame containing a UDP datagram from `src` to `dst`
+fn frame(
+    src_mac: [u8; 6],
+    dst_mac: [u8; 6],
+    src: SocketAddr,
+    dst: SocketAddr,
+    payload: &[u8],
+) -> Vec<u8> {
+    let udp_len = (8 + payload.len()) as u16;
+    let mut udp = Vec::with_capacity(udp_len as usize);
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&udp_len.to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+
+    let mut frame = Vec::with_capacity(14 + 40 + udp.len());
+    frame.extend_from_slice(&dst_mac);
+    frame.extend_from_slice(&src_mac);
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let mut pseudo = Vec::with_capacity(12);
+            pseudo.extend_from_slice(&src_ip.octets());
+            pseudo.extend_from_slice(&dst_ip.octets());
+            pseudo.extend_from_slice(&[0, PROTOCOL_UDP]);
+            pseudo.extend_from_slice(&udp_len.to_be_bytes());
+            set_udp_checksum(&mut udp, &pseudo);
+
+            let mut ip = Vec::with_capacity(20);
+            ip.extend_from_slice(&[0x45, 0]);
+            ip.extend_from_slice(&(20 + udp_len).to_be_bytes());
+            // Identification, then the Don't Fragment flag
+            ip.extend_from_slice(&[0, 0, 0x40, 0]);
+            ip.extend_from_slice(&[TTL, PROTOCOL_UDP, 0, 0]);
+            ip.extend_from_slice(&src_ip.octets());
+            ip.extend_from_slice(&dst_ip.octets());
+            let checksum = checksum(&[&ip]);
+            ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+            frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+            frame.extend_from_slice(&ip);
+        }
+        (src_ip, dst_ip) => {
+            let (src_ip, dst_ip) = (to_ipv6(src_ip), to_ipv6(dst_ip));
+            let mut pseudo = Vec::with_capacity(40);
+            pseudo.extend_from_slice(&src_ip.octets());
+            pseudo.extend_from_slice(&dst_ip.octets());
+            pseudo.extend_from_slice(&u32::from(udp_len).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, PROTOCOL_UDP]);
+            set_udp_checksum(&mut udp, &pseudo);
+
+            frame.extend_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+            frame.extend_from_slice(&[0x60, 0, 0, 0]);
+            frame.extend_from_slice(&udp_len.to_be_bytes());
+            frame.extend_from_slice(&[PROTOCOL_UDP, TTL]);
+            frame.extend_from_slice(&src_ip.octets());
+            frame.extend_from_slice(&dst_ip.octets());
+        }
+    }
+    frame.extend_from_slice(&udp);
+    frame
+}
+
+/// Returns `ip` as IPv6, for the unlikely mix of families which can't be put in an IPv4 header
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Fills in the checksum of the UDP datagram `udp`, covering the IP `pseudo` header
+fn set_udp_checksum(udp: &mut [u8], pseudo: &[u8]) {
+    let checksum = match checksum(&[pseudo, udp]) {
+        // All zeroes means "no checksum", so it's sent as all ones instead
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// Returns the internet checksum (RFC 1071) of the concatenation of `parts`, each of which
+/// has an even length apart from the last
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    for part in parts {
+        for pair in part.chunks(2) {
+            let word = match pair {
+                [high, low] => u16::from_be_bytes([*high, *low]),
+                [high] => u16::from_be_bytes([*high, 0]),
+                _ => unreachable!("chunks are one or two bytes"),
+            };
+            sum += u32::from(word);
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums_are_valid() {
+        // A commonly used example IPv4 header, whose checksum is 0xb861
+        let header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        assert_eq!(checksum(&[&header]), 0xb861);
+
+        // A frame's headers sum to zero once their checksums are filled in
+        let src: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        let dst: SocketAddr = "198.51.100.2:3478".parse().unwrap();
+        let frame = frame(LOCAL_MAC, REMOTE_MAC, src, dst, b"odd");
+        assert_eq!(frame.len(), 14 + 20 + 8 + 3);
+        assert_eq!(checksum(&[&frame[14..34]]), 0);
+        let mut pseudo = frame[26..34].to_vec();
+        pseudo.extend_from_slice(&[0, PROTOCOL_UDP, 0, 11]);
+        assert_eq!(checksum(&[&pseudo, &frame[34..]]), 0);
+    }
+}
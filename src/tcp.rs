@@ -0,0 +1,119 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::error::{Context, Result};
+use crate::{
+    auth, build_request, check_mapped, follow_redirect, parse_response, Binding, LookupError,
+    LookupOptions, Outcome, Reply,
+};
+use std::net::SocketAddr;
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time;
+
+/// Size of the fixed STUN message header, which ends with the message length
+const HEADER_LEN: usize = 20;
+
+/// Connects to `dest` over TCP, sends a binding request, and returns the public address
+/// reported in the response. TCP handles retransmission, so rather than retrying, the whole
+/// exchange is given the total wait time of the UDP retry schedule.
+pub async fn lookup_tcp(dest: &SocketAddr, opts: &LookupOptions) -> Result<Binding> {
+    let timeout = opts.total_timeout();
+    time::timeout(timeout, async {
+        let mut dest = *dest;
+        let mut redirects = 0;
+        loop {
+            let mut stream = TcpStream::connect(dest)
+                .await
+                .with_context(|| format!("Failed to connect to {}", dest))?;
+            match exchange(&mut stream, opts)
+                .await
+                .with_context(|| format!("Failed to query {} over TCP", dest))?
+            {
+                Outcome::Mapped(binding) => {
+                    check_mapped(&binding, &dest, opts)?;
+                    return Ok(binding);
+                }
+                Outcome::Redirect(next) => {
+                    follow_redirect(&mut redirects, &dest, &next)?;
+                    dest = next;
+                }
+            }
+        }
+    })
+    .await
+    .map_err(|_| {
+        LookupError::Timeout(format!(
+            "Timed out after {:?} waiting for response from {}",
+            timeout, dest
+        ))
+    })?
+}
+
+/// Sends a binding request over a connected stream and returns the public address reported
+/// in the response, or where the server redirected the request
+pub(crate) async fn exchange<S>(stream: &mut S, opts: &LookupOptions) -> Result<Outcome>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut challenge = None;
+    for _ in 0..auth::MAX_REQUESTS {
+        let (transaction_id, message_bytes) = build_request(opts, challenge.as_ref())?;
+        let sent = Instant::now();
+        stream
+            .write_all(&message_bytes)
+            .await
+            .context("Failed to send STUN request")?;
+        stream
+            .flush()
+            .await
+            .context("Failed to send STUN request")?;
+        let response = read_message(stream).await?;
+        match parse_response(
+            transaction_id,
+            &response,
+            sent.elapsed(),
+            opts,
+            challenge.as_ref(),
+        )? {
+            Reply::Mapped(binding) => return Ok(Outcome::Mapped(binding)),
+            Reply::Redirect(next) => return Ok(Outcome::Redirect(next)),
+            Reply::Challenge(next) => challenge = Some(next),
+        }
+    }
+    Err(LookupError::Auth(
+        "Too many authentication challenges".to_string(),
+    ))
+}
+
+/// Reads a single STUN message from a stream, using the length in the header to find the end
+async fn read_message<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Vec<u8>> {
+    let mut message = vec![0u8; HEADER_LEN];
+    stream
+        .read_exact(&mut message)
+        .await
+        .context("Failed to read STUN response header")?;
+    let body_len = u16::from_be_bytes([message[2], message[3]]) as usize;
+    message.resize(HEADER_LEN + body_len, 0);
+    stream
+        .read_exact(&mut message[HEADER_LEN..])
+        .await
+        .context("Failed to read STUN response body")?;
+    Ok(message)
+}
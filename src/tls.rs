@@ -0,0 +1,159 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::error::{Context, Result};
+use crate::tcp::exchange;
+use crate::{check_mapped, follow_redirect, Binding, LookupError, LookupOptions, Outcome};
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{
+    ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider,
+};
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{self, ClientConfig, DigitallySignedStruct, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+/// Connects to `dest` over TLS, sends a binding request, and returns the public address
+/// reported in the response. The STUN exchange is the same as for plain TCP. `server_name`
+/// is the hostname (or IP) used for SNI and for verifying the server's certificate.
+pub async fn lookup_tls(
+    dest: &SocketAddr,
+    server_name: &str,
+    opts: &LookupOptions,
+) -> Result<Binding> {
+    let connector = TlsConnector::from(Arc::new(client_config(opts.insecure)?));
+    let server_name = ServerName::try_from(server_name.to_string())
+        .with_context(|| format!("Invalid TLS server name: {}", server_name))?;
+    let timeout = opts.total_timeout();
+    time::timeout(timeout, async {
+        // Per RFC 5389, redirected requests verify the certificate against the original name
+        let mut dest = *dest;
+        let mut redirects = 0;
+        loop {
+            let tcp = TcpStream::connect(dest)
+                .await
+                .with_context(|| format!("Failed to connect to {}", dest))?;
+            let mut stream = connector
+                .connect(server_name.clone(), tcp)
+                .await
+                .with_context(|| format!("TLS handshake with {} failed", dest))?;
+            match exchange(&mut stream, opts)
+                .await
+                .with_context(|| format!("Failed to query {} over TLS", dest))?
+            {
+                Outcome::Mapped(binding) => {
+                    check_mapped(&binding, &dest, opts)?;
+                    return Ok(binding);
+                }
+                Outcome::Redirect(next) => {
+                    follow_redirect(&mut redirects, &dest, &next)?;
+                    dest = next;
+                }
+            }
+        }
+    })
+    .await
+    .map_err(|_| {
+        LookupError::Timeout(format!(
+            "Timed out after {:?} waiting for response from {}",
+            timeout, dest
+        ))
+    })?
+}
+
+/// Builds a TLS client configuration which verifies servers against the system root store,
+/// or which accepts any certificate if `insecure` is set
+pub(crate) fn client_config(insecure: bool) -> Result<ClientConfig> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .context("Failed to configure TLS")?;
+    if insecure {
+        return Ok(builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+            .with_no_client_auth());
+    }
+
+    let mut roots = RootCertStore::empty();
+    let native = rustls_native_certs::load_native_certs();
+    let (added, _ignored) = roots.add_parsable_certificates(native.certs);
+    if added == 0 {
+        return Err(LookupError::Other(format!(
+            "No system root certificates could be loaded: {:?}",
+            native.errors
+        )));
+    }
+    Ok(builder.with_root_certificates(roots).with_no_client_auth())
+}
+
+/// Certificate verifier for `--insecure`: Accepts any certificate, while still checking that
+/// the handshake was signed by it
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
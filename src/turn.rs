@@ -0,0 +1,180 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! A minimal TURN (RFC 5766) client, which allocates a relayed address to check that relaying
+//! works and then releases it again
+
+use crate::attributes::Attribute;
+use crate::auth::{self, Challenge};
+use crate::error::Result;
+use crate::{
+    bind_connected, build_message, decode_response, error_response, mapped_address,
+    recv_with_retries, resolve_endpoint, Family, LookupError, LookupOptions,
+};
+use log::{debug, warn};
+use std::net::SocketAddr;
+use std::time::Duration;
+use stun_codec::rfc5766::attributes::{Lifetime, RequestedTransport, XorRelayAddress};
+use stun_codec::rfc5766::methods;
+use stun_codec::{Message, MessageClass};
+use tokio::net::UdpSocket;
+
+/// IANA protocol number for UDP, the only transport which TURN servers must relay
+const PROTOCOL_UDP: u8 = 17;
+
+/// A relayed address allocated by a TURN server
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Allocation {
+    /// Address on the TURN server which relays traffic to the client, from XOR-RELAYED-ADDRESS
+    pub relayed: SocketAddr,
+    /// The client's public address as seen by the server, if it reported one
+    pub mapped: Option<SocketAddr>,
+    /// How long the allocation would have lasted if it wasn't released, from LIFETIME
+    pub lifetime: Option<Duration>,
+}
+
+/// Sends an Allocate request over UDP to the TURN server at `endpoint`, answering its
+/// authentication challenge with the configured credentials, and returns the relayed address
+/// which it allocated. The allocation is released again before returning.
+pub async fn allocate_turn(
+    endpoint: &str,
+    family: Option<Family>,
+    opts: &LookupOptions,
+) -> Result<Allocation> {
+    let credentials = match &opts.credentials {
+        Some(credentials) => credentials,
+        None => {
+            return Err(LookupError::InvalidOptions(
+                "TURN allocations require a username and password".to_string(),
+            ))
+        }
+    };
+    let dest = resolve_endpoint(endpoint, family, opts).await?;
+    let conn = bind_connected(&dest, opts).await?;
+
+    let mut recvbuf = vec![0u8; opts.recv_buffer];
+    let mut challenge = None;
+    for _ in 0..auth::MAX_REQUESTS {
+        let extra = vec![Attribute::from(RequestedTransport::new(PROTOCOL_UDP))];
+        let (transaction_id, request) =
+            build_message(methods::ALLOCATE, opts, challenge.as_ref(), extra)?;
+        let (recvsize, _rtt) =
+            recv_with_retries(&conn, &dest, &request, &mut recvbuf, opts).await?;
+        let response = decode_response(transaction_id, methods::ALLOCATE, &recvbuf[..recvsize])?;
+        if response.class() == MessageClass::ErrorResponse {
+            if let Some(next) = auth::challenge(&response, Some(credentials), challenge.as_ref())? {
+                challenge = Some(next);
+                continue;
+            }
+            return Err(error_response(&response));
+        }
+        if let Some(challenge) = &challenge {
+            auth::verify(&response, credentials, challenge)?;
+        }
+        let allocation = parse_allocation(&response)?;
+        debug!(
+            "Allocated relayed address {} on {}",
+            allocation.relayed, dest
+        );
+        match release(&conn, &dest, challenge.as_ref(), opts).await {
+            Ok(()) => debug!("Released allocation on {}", dest),
+            Err(e) => warn!("Failed to release allocation on {}: {:#}", dest, e),
+        }
+        return Ok(allocation);
+    }
+    Err(LookupError::Auth(format!(
+        "Too many authentication challenges from {}",
+        dest
+    )))
+}
+
+/// Returns the allocation described by a successful Allocate response
+fn parse_allocation(response: &Message<Attribute>) -> Result<Allocation> {
+    let relayed = response
+        .get_attribute::<XorRelayAddress>()
+        .ok_or_else(|| {
+            LookupError::NoAddress(format!(
+                "No XOR-RELAYED-ADDRESS found in response: {:?}",
+                response
+            ))
+        })?
+        .address();
+    Ok(Allocation {
+        relayed,
+        mapped: mapped_address(response),
+        lifetime: response
+            .get_attribute::<Lifetime>()
+            .map(|lifetime| lifetime.lifetime()),
+    })
+}
+
+/// Deletes the allocation on `dest` with a zero-lifetime Refresh, so that it doesn't tie up the
+/// server's resources until it expires
+async fn release(
+    conn: &UdpSocket,
+    dest: &SocketAddr,
+    challenge: Option<&Challenge>,
+    opts: &LookupOptions,
+) -> Result<()> {
+    let extra = vec![Attribute::from(Lifetime::from_u32(0))];
+    let (transaction_id, request) = build_message(methods::REFRESH, opts, challenge, extra)?;
+    let mut recvbuf = vec![0u8; opts.recv_buffer];
+    let (recvsize, _rtt) = recv_with_retries(conn, dest, &request, &mut recvbuf, opts).await?;
+    let response = decode_response(transaction_id, methods::REFRESH, &recvbuf[..recvsize])?;
+    if response.class() == MessageClass::ErrorResponse {
+        return Err(error_response(&response));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stun_codec::rfc5389::attributes::XorMappedAddress;
+    use stun_codec::TransactionId;
+
+    #[test]
+    fn parses_allocation() {
+        let relayed: SocketAddr = "198.51.100.7:49152".parse().unwrap();
+        let mapped: SocketAddr = "203.0.113.5:4000".parse().unwrap();
+        let mut response = Message::<Attribute>::new(
+            MessageClass::SuccessResponse,
+            methods::ALLOCATE,
+            TransactionId::new([7u8; 12]),
+        );
+        response.add_attribute(Attribute::from(XorRelayAddress::new(relayed)));
+        response.add_attribute(Attribute::from(Lifetime::from_u32(600)));
+        response.add_attribute(Attribute::from(XorMappedAddress::new(mapped)));
+        assert_eq!(
+            parse_allocation(&response).unwrap(),
+            Allocation {
+                relayed,
+                mapped: Some(mapped),
+                lifetime: Some(Duration::from_secs(600)),
+            }
+        );
+
+        let mut response = Message::<Attribute>::new(
+            MessageClass::SuccessResponse,
+            methods::ALLOCATE,
+            TransactionId::new([7u8; 12]),
+        );
+        response.add_attribute(Attribute::from(XorMappedAddress::new(mapped)));
+        assert!(parse_allocation(&response).is_err());
+    }
+}
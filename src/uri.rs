@@ -0,0 +1,78 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::Transport;
+
+/// Splits a `stun:` or `stuns:` URI (RFC 7064) into the transport that it implies and the
+/// remaining `host[:port]`. `stun:` allows plain UDP or TCP, so `default` is kept if it's TCP.
+/// Likewise `stuns:` keeps DTLS if that's the default, and otherwise uses TLS. Endpoints
+/// without a scheme are returned as-is with the `default` transport.
+pub fn parse_endpoint(endpoint: &str, default: Transport) -> (Transport, &str) {
+    match endpoint.split_once(':') {
+        // A host which happens to be named "stun", rather than a URI
+        Some((_host, port)) if port.parse::<u16>().is_ok() => (default, endpoint),
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("stun") => {
+            let transport = match default {
+                Transport::Tcp => Transport::Tcp,
+                _ => Transport::Udp,
+            };
+            (transport, rest)
+        }
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("stuns") => {
+            let transport = match default {
+                Transport::Dtls => Transport::Dtls,
+                _ => Transport::Tls,
+            };
+            (transport, rest)
+        }
+        _ => (default, endpoint),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_schemes() {
+        assert_eq!(
+            parse_endpoint("stun:stun.example.org", Transport::Udp),
+            (Transport::Udp, "stun.example.org")
+        );
+        assert_eq!(
+            parse_endpoint("STUNS:stun.example.org:5349", Transport::Udp),
+            (Transport::Tls, "stun.example.org:5349")
+        );
+        assert_eq!(
+            parse_endpoint("stun:[::1]:3478", Transport::Tcp),
+            (Transport::Tcp, "[::1]:3478")
+        );
+        assert_eq!(
+            parse_endpoint("stun.example.org:3478", Transport::Tls),
+            (Transport::Tls, "stun.example.org:3478")
+        );
+        assert_eq!(
+            parse_endpoint("[::1]:3478", Transport::Udp),
+            (Transport::Udp, "[::1]:3478")
+        );
+        assert_eq!(
+            parse_endpoint("stun:3478", Transport::Udp),
+            (Transport::Udp, "stun:3478")
+        );
+    }
+}
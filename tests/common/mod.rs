@@ -0,0 +1,190 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! A mock STUN server for the integration tests, also included in the unit tests as `mock`
+
+#![allow(dead_code)]
+
+use bytecodec::{DecodeExt, EncodeExt};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use stun_codec::rfc5389::attributes::{
+    ErrorCode, MessageIntegrity, Nonce, Realm, Username, XorMappedAddress,
+};
+use stun_codec::rfc5389::{errors, methods, Attribute};
+use stun_codec::{Message, MessageClass, MessageDecoder, MessageEncoder, TransactionId};
+use tokio::net::UdpSocket;
+
+/// A UDP server on localhost which answers binding requests until it's dropped
+pub struct MockServer {
+    pub addr: SocketAddr,
+    /// Transaction IDs of the requests received so far, in order
+    pub requests: Arc<Mutex<Vec<TransactionId>>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl MockServer {
+    /// Starts a server which answers each binding request with an XOR-MAPPED-ADDRESS of the
+    /// sender's address
+    pub async fn spawn() -> MockServer {
+        MockServer::spawn_with(|request, peer| Some(binding_response(request, peer))).await
+    }
+
+    /// Starts a server which answers each request with whatever `respond` returns, or not at
+    /// all if it returns `None`
+    pub async fn spawn_with<F>(respond: F) -> MockServer
+    where
+        F: Fn(&Message<Attribute>, SocketAddr) -> Option<Message<Attribute>> + Send + 'static,
+    {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+        let task = tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            loop {
+                let (size, peer) = server.recv_from(&mut buf).await.unwrap();
+                let request =
+                    match MessageDecoder::<Attribute>::new().decode_from_bytes(&buf[..size]) {
+                        Ok(Ok(request)) => request,
+                        // Not a valid STUN message, ignore it like a real server would
+                        _ => continue,
+                    };
+                received.lock().unwrap().push(request.transaction_id());
+                if let Some(response) = respond(&request, peer) {
+                    server.send_to(&encode(response), peer).await.unwrap();
+                }
+            }
+        });
+        MockServer {
+            addr,
+            requests,
+            task,
+        }
+    }
+
+    /// Starts a server which requires the long-term credentials `username` and `password` in
+    /// `realm`: requests without a valid MESSAGE-INTEGRITY get a 401 challenge with REALM and
+    /// NONCE, and the rest get a binding response signed with the same credentials
+    pub async fn spawn_authenticated(username: &str, realm: &str, password: &str) -> MockServer {
+        let (username, realm, password) = (
+            username.to_string(),
+            realm.to_string(),
+            password.to_string(),
+        );
+        MockServer::spawn_with(move |request, peer| {
+            if !has_integrity(request, &username, &realm, &password) {
+                return Some(challenge(
+                    request,
+                    errors::Unauthorized.into(),
+                    &realm,
+                    NONCE,
+                ));
+            }
+            let mut response = binding_response(request, peer);
+            sign(&mut response, &username, &realm, &password);
+            Some(response)
+        })
+        .await
+    }
+
+    /// Returns the number of requests received so far
+    pub fn request_count(&self) -> usize {
+        self.requests.lock().unwrap().len()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Returns a success response to `request` with an XOR-MAPPED-ADDRESS of `mapped`
+pub fn binding_response(request: &Message<Attribute>, mapped: SocketAddr) -> Message<Attribute> {
+    let mut response = Message::new(
+        MessageClass::SuccessResponse,
+        methods::BINDING,
+        request.transaction_id(),
+    );
+    response.add_attribute(Attribute::XorMappedAddress(XorMappedAddress::new(mapped)));
+    response
+}
+
+/// Nonce included in the challenges from [`MockServer::spawn_authenticated`]
+pub const NONCE: &str = "mock-nonce";
+
+/// Returns an error response to `request` with `error`, challenging the client to authenticate
+/// with `realm` and `nonce`
+pub fn challenge(
+    request: &Message<Attribute>,
+    error: ErrorCode,
+    realm: &str,
+    nonce: &str,
+) -> Message<Attribute> {
+    let mut response = Message::new(
+        MessageClass::ErrorResponse,
+        methods::BINDING,
+        request.transaction_id(),
+    );
+    response.add_attribute(Attribute::ErrorCode(error));
+    response.add_attribute(Attribute::Realm(Realm::new(realm.to_string()).unwrap()));
+    response.add_attribute(Attribute::Nonce(Nonce::new(nonce.to_string()).unwrap()));
+    response
+}
+
+/// Returns whether `message` has a MESSAGE-INTEGRITY matching `username`, `realm` and
+/// `password`
+pub fn has_integrity(
+    message: &Message<Attribute>,
+    username: &str,
+    realm: &str,
+    password: &str,
+) -> bool {
+    let username = Username::new(username.to_string()).unwrap();
+    let realm = Realm::new(realm.to_string()).unwrap();
+    message
+        .get_attribute::<MessageIntegrity>()
+        .is_some_and(|integrity| {
+            integrity
+                .check_long_term_credential(&username, &realm, password)
+                .is_ok()
+        })
+}
+
+/// Adds a MESSAGE-INTEGRITY to `message` for `username`, `realm` and `password`
+pub fn sign(message: &mut Message<Attribute>, username: &str, realm: &str, password: &str) {
+    let username = Username::new(username.to_string()).unwrap();
+    let realm = Realm::new(realm.to_string()).unwrap();
+    let integrity =
+        MessageIntegrity::new_long_term_credential(message, &username, &realm, password).unwrap();
+    message.add_attribute(Attribute::MessageIntegrity(integrity));
+}
+
+/// Encodes `message` into bytes
+pub fn encode(message: Message<Attribute>) -> Vec<u8> {
+    MessageEncoder::new().encode_into_bytes(message).unwrap()
+}
+
+/// Returns an address on localhost which nothing is listening on
+pub fn dead_addr() -> SocketAddr {
+    std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
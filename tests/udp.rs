@@ -0,0 +1,463 @@
+/*
+    iplookup - Query STUN service for current public IP address
+    Copyright (C) 2020  Nicholas Parker
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+mod common;
+
+use bytecodec::DecodeExt;
+use common::MockServer;
+use iplookup::{
+    check_symmetric, lookup, lookup_blocking, lookup_endpoint, lookup_first, lookup_in_turn,
+    test_hairpin, Credentials, Family, LookupClient, LookupError, LookupOptions, ResponseAttribute,
+};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use stun_codec::rfc5389::attributes::{AlternateServer, ErrorCode, Software};
+use stun_codec::rfc5389::{errors, methods, Attribute};
+use stun_codec::{Message, MessageClass, MessageDecoder, TransactionId};
+use tokio::net::UdpSocket;
+
+/// Options which give up quickly, for servers which aren't expected to answer
+fn fast_opts() -> LookupOptions {
+    LookupOptions {
+        retries: 2,
+        base_timeout: Duration::from_millis(100),
+        final_timeout_factor: 2,
+        ..LookupOptions::default()
+    }
+}
+
+#[tokio::test]
+async fn lookup_returns_sender_address() {
+    let server = MockServer::spawn().await;
+    let conn = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let binding = lookup(&conn, &server.addr, &LookupOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(binding.addr, conn.local_addr().unwrap());
+    assert_eq!(binding.other_address, None);
+    assert_eq!(server.request_count(), 1);
+}
+
+#[tokio::test]
+async fn lookup_endpoint_resolves_and_queries() {
+    let server = MockServer::spawn().await;
+    let binding = lookup_endpoint(&server.addr.to_string(), None, &LookupOptions::default())
+        .await
+        .unwrap();
+    assert!(binding.addr.ip().is_loopback());
+    assert_ne!(binding.addr.port(), server.addr.port());
+}
+
+#[tokio::test]
+async fn each_request_has_a_new_transaction_id() {
+    let server = MockServer::spawn().await;
+    let conn = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let opts = LookupOptions::default();
+    lookup(&conn, &server.addr, &opts).await.unwrap();
+    lookup(&conn, &server.addr, &opts).await.unwrap();
+    let requests = server.requests.lock().unwrap();
+    assert_eq!(requests.len(), 2);
+    assert_ne!(requests[0], requests[1]);
+}
+
+#[tokio::test]
+async fn retransmissions_reuse_the_transaction_id() {
+    // Ignore the first request so that the client has to resend it
+    let dropped = AtomicBool::new(false);
+    let server = MockServer::spawn_with(move |request, peer| {
+        if !dropped.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+        Some(common::binding_response(request, peer))
+    })
+    .await;
+    let conn = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    lookup(&conn, &server.addr, &fast_opts()).await.unwrap();
+    let requests = server.requests.lock().unwrap();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0], requests[1]);
+}
+
+#[tokio::test]
+async fn unanswered_lookup_times_out() {
+    let server = MockServer::spawn_with(|_request, _peer| None).await;
+    let conn = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let err = lookup(&conn, &server.addr, &fast_opts()).await.unwrap_err();
+    assert!(matches!(err, LookupError::Timeout(_)));
+    assert_eq!(server.request_count(), 2);
+}
+
+#[tokio::test]
+async fn lookup_first_skips_dead_server() {
+    let server = MockServer::spawn().await;
+    let endpoints = vec![common::dead_addr().to_string(), server.addr.to_string()];
+    let (endpoint, binding) = lookup_first(&endpoints, None, &fast_opts()).await.unwrap();
+    assert_eq!(endpoint, server.addr.to_string());
+    assert!(binding.addr.ip().is_loopback());
+}
+
+#[tokio::test]
+async fn lookup_in_turn_stops_at_first_answer() {
+    let first = MockServer::spawn().await;
+    let second = MockServer::spawn().await;
+    let endpoints = vec![
+        common::dead_addr().to_string(),
+        first.addr.to_string(),
+        second.addr.to_string(),
+    ];
+    let (endpoint, _) = lookup_in_turn(&endpoints, None, &fast_opts())
+        .await
+        .unwrap();
+    assert_eq!(endpoint, first.addr.to_string());
+    assert_eq!(second.request_count(), 0);
+}
+
+#[tokio::test]
+async fn different_failures_are_listed_per_server() {
+    let server = MockServer::spawn_with(|request, _peer| {
+        let mut response = Message::new(
+            MessageClass::ErrorResponse,
+            methods::BINDING,
+            request.transaction_id(),
+        );
+        response.add_attribute(Attribute::ErrorCode(
+            ErrorCode::new(400, "Bad Request".to_string()).unwrap(),
+        ));
+        Some(response)
+    })
+    .await;
+    let dead = common::dead_addr();
+    let endpoints = vec![server.addr.to_string(), dead.to_string()];
+    let err = lookup_in_turn(&endpoints, None, &fast_opts())
+        .await
+        .unwrap_err();
+    let errors = match err {
+        LookupError::AllFailed(errors) => errors,
+        e => panic!("unexpected error: {:?}", e),
+    };
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].0, server.addr.to_string());
+    assert!(matches!(
+        errors[0].1,
+        LookupError::ServerError { code: 400, .. }
+    ));
+    assert_eq!(errors[1].0, dead.to_string());
+    assert!(matches!(errors[1].1, LookupError::Timeout(_)));
+}
+
+#[tokio::test]
+async fn full_receive_buffer_is_reported_as_truncated() {
+    // Pad the response out with a long SOFTWARE attribute
+    let respond = |request: &Message<Attribute>, peer| {
+        let mut response = common::binding_response(request, peer);
+        let software = Software::new("x".repeat(120)).unwrap();
+        response.add_attribute(Attribute::Software(software));
+        Some(response)
+    };
+    let server = MockServer::spawn_with(respond).await;
+    let probe = Message::new(
+        MessageClass::Request,
+        methods::BINDING,
+        TransactionId::new([0; 12]),
+    );
+    let size = common::encode(respond(&probe, server.addr).unwrap()).len();
+
+    let conn = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let opts = LookupOptions {
+        recv_buffer: size + 1,
+        ..fast_opts()
+    };
+    lookup(&conn, &server.addr, &opts).await.unwrap();
+
+    let opts = LookupOptions {
+        recv_buffer: size,
+        ..fast_opts()
+    };
+    let err = lookup(&conn, &server.addr, &opts).await.unwrap_err();
+    assert!(matches!(err, LookupError::Decode(_)));
+    assert!(err.to_string().contains("possibly truncated"), "{}", err);
+}
+
+#[tokio::test]
+async fn mismatched_transaction_id_is_rejected() {
+    let server = MockServer::spawn_with(|_request, peer| {
+        let other = Message::new(
+            MessageClass::SuccessResponse,
+            methods::BINDING,
+            TransactionId::new([0x55; 12]),
+        );
+        Some(common::binding_response(&other, peer))
+    })
+    .await;
+    let conn = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let err = lookup(&conn, &server.addr, &fast_opts()).await.unwrap_err();
+    let message = err.to_string();
+    assert!(
+        message.starts_with("Returned transaction id") && message.contains("doesn't match sent"),
+        "unexpected error: {}",
+        message
+    );
+    assert!(matches!(err, LookupError::TransactionMismatch(_)));
+}
+
+/// Options with credentials matching [`MockServer::spawn_authenticated`] in the tests below
+fn auth_opts() -> LookupOptions {
+    LookupOptions {
+        credentials: Some(Credentials {
+            username: "user".to_string(),
+            password: "secret".to_string(),
+            realm: Some("example.org".to_string()),
+        }),
+        ..fast_opts()
+    }
+}
+
+#[tokio::test]
+async fn challenge_is_answered_with_signed_request() {
+    let server = MockServer::spawn_authenticated("user", "example.org", "secret").await;
+    let conn = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let binding = lookup(&conn, &server.addr, &auth_opts()).await.unwrap();
+    assert_eq!(binding.addr, conn.local_addr().unwrap());
+    // The first request is unsigned, and the retry answers the challenge
+    assert_eq!(server.request_count(), 2);
+}
+
+#[tokio::test]
+async fn response_with_wrong_integrity_is_rejected() {
+    let server = MockServer::spawn_with(|request, peer| {
+        if !common::has_integrity(request, "user", "example.org", "secret") {
+            return Some(common::challenge(
+                request,
+                errors::Unauthorized.into(),
+                "example.org",
+                common::NONCE,
+            ));
+        }
+        let mut response = common::binding_response(request, peer);
+        common::sign(&mut response, "user", "example.org", "wrong");
+        Some(response)
+    })
+    .await;
+    let conn = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let err = lookup(&conn, &server.addr, &auth_opts()).await.unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "MESSAGE-INTEGRITY in response doesn't match the credentials"
+    );
+    assert!(matches!(err, LookupError::Auth(_)));
+}
+
+#[tokio::test]
+async fn response_from_other_origin_is_ignored() {
+    let server = MockServer::spawn().await;
+    let conn = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    // Queue a datagram from a third party, which the client will receive before the response
+    let spoofer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    spoofer
+        .send_to(b"not from the server", conn.local_addr().unwrap())
+        .await
+        .unwrap();
+
+    let binding = lookup(&conn, &server.addr, &fast_opts()).await.unwrap();
+    assert_eq!(binding.addr, conn.local_addr().unwrap());
+    // The client keeps listening past the spoofed datagram instead of resending
+    assert_eq!(server.request_count(), 1);
+}
+
+#[tokio::test]
+async fn redirect_across_families_is_classified() {
+    let server = MockServer::spawn_with(|request, _peer| {
+        let mut response = Message::new(
+            MessageClass::ErrorResponse,
+            methods::BINDING,
+            request.transaction_id(),
+        );
+        response.add_attribute(Attribute::ErrorCode(
+            ErrorCode::new(300, "Try Alternate".to_string()).unwrap(),
+        ));
+        response.add_attribute(Attribute::AlternateServer(AlternateServer::new(
+            "[::1]:3478".parse().unwrap(),
+        )));
+        Some(response)
+    })
+    .await;
+    let conn = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let err = lookup(&conn, &server.addr, &fast_opts()).await.unwrap_err();
+    assert!(matches!(err, LookupError::Redirect(_)));
+}
+
+#[tokio::test]
+async fn socket_connected_elsewhere_is_rejected() {
+    let server = MockServer::spawn().await;
+    let conn = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let other = common::dead_addr();
+    conn.connect(other).await.unwrap();
+    let err = lookup(&conn, &server.addr, &fast_opts()).await.unwrap_err();
+    assert!(matches!(err, LookupError::InvalidOptions(_)));
+    // The caller's socket is left connected where it was
+    assert_eq!(conn.peer_addr().unwrap(), other);
+    assert_eq!(server.request_count(), 0);
+}
+
+#[tokio::test]
+async fn only_other_origins_times_out() {
+    let server = MockServer::spawn_with(|_request, _peer| None).await;
+    let conn = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let spoofer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    spoofer
+        .send_to(b"not from the server", conn.local_addr().unwrap())
+        .await
+        .unwrap();
+
+    let opts = LookupOptions {
+        retries: 1,
+        ..fast_opts()
+    };
+    let err = lookup(&conn, &server.addr, &opts).await.unwrap_err();
+    assert!(matches!(err, LookupError::Timeout(_)));
+}
+
+#[tokio::test]
+async fn symmetric_check_compares_ports() {
+    let first = MockServer::spawn().await;
+    let second = MockServer::spawn().await;
+    // Pretends to be behind a NAT which picks a new port for each destination
+    let remapping = MockServer::spawn_with(|request, peer| {
+        let remapped = SocketAddr::new(peer.ip(), peer.port().wrapping_add(1));
+        Some(common::binding_response(request, remapped))
+    })
+    .await;
+    let opts = LookupOptions::default();
+
+    let check = check_symmetric(
+        &first.addr.to_string(),
+        &second.addr.to_string(),
+        None,
+        &opts,
+    )
+    .await
+    .unwrap();
+    assert!(!check.symmetric);
+    assert_eq!(check.tests[0].public, check.local);
+    assert_eq!(check.tests[1].public, check.local);
+
+    let check = check_symmetric(
+        &first.addr.to_string(),
+        &remapping.addr.to_string(),
+        None,
+        &opts,
+    )
+    .await
+    .unwrap();
+    assert!(check.symmetric);
+    assert_eq!(check.tests[1].server, remapping.addr);
+}
+
+#[tokio::test]
+async fn hairpin_test_sends_to_public_address() {
+    let server = MockServer::spawn().await;
+    let hairpin = test_hairpin(&server.addr.to_string(), None, &fast_opts())
+        .await
+        .unwrap();
+    assert!(hairpin.supported);
+    assert_eq!(hairpin.public, hairpin.local);
+    assert_ne!(hairpin.origin, Some(hairpin.local));
+
+    // Reports a public address which nothing is listening on
+    let dead = common::dead_addr();
+    let server =
+        MockServer::spawn_with(move |request, _peer| Some(common::binding_response(request, dead)))
+            .await;
+    let hairpin = test_hairpin(&server.addr.to_string(), None, &fast_opts())
+        .await
+        .unwrap();
+    assert!(!hairpin.supported);
+    assert_eq!(hairpin.public, dead);
+    assert_eq!(hairpin.origin, None);
+}
+
+#[tokio::test]
+async fn client_uses_built_options() {
+    let server = MockServer::spawn().await;
+    let client = LookupClient::builder()
+        .retries(3)
+        .base_timeout(Duration::from_millis(100))
+        .family(Family::V4)
+        .build();
+    assert_eq!(client.options().retries, 3);
+    let binding = client.lookup(&server.addr.to_string()).await.unwrap();
+    assert!(binding.addr.is_ipv4());
+
+    // Options which aren't set keep their defaults
+    assert_eq!(
+        client.options().final_timeout_factor,
+        LookupOptions::default().final_timeout_factor
+    );
+}
+
+#[test]
+fn lookup_blocking_works_without_a_runtime() {
+    // A blocking server, since there's no runtime to run MockServer on
+    let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 2048];
+        let (size, peer) = server.recv_from(&mut buf).unwrap();
+        let request = MessageDecoder::<Attribute>::new()
+            .decode_from_bytes(&buf[..size])
+            .unwrap()
+            .unwrap();
+        let response = common::encode(common::binding_response(&request, peer));
+        server.send_to(&response, peer).unwrap();
+    });
+
+    let addr = lookup_blocking(&server_addr.to_string(), &LookupOptions::default()).unwrap();
+    assert!(addr.ip().is_loopback());
+}
+
+#[tokio::test]
+async fn binding_lists_response_attributes() {
+    let server = MockServer::spawn_with(|request, peer| {
+        let mut response = common::binding_response(request, peer);
+        response.add_attribute(Attribute::Software(
+            Software::new("mock server".to_string()).unwrap(),
+        ));
+        Some(response)
+    })
+    .await;
+    let conn = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let binding = lookup(&conn, &server.addr, &LookupOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(
+        binding.attributes,
+        vec![
+            ResponseAttribute {
+                name: Some("XOR-MAPPED-ADDRESS"),
+                code: 0x0020,
+                value: conn.local_addr().unwrap().to_string(),
+            },
+            ResponseAttribute {
+                name: Some("SOFTWARE"),
+                code: 0x8022,
+                value: "mock server".to_string(),
+            },
+        ]
+    );
+}